@@ -40,6 +40,11 @@ const RC: &str = r#"
 "#;
 
 fn main() {
+    // Resources only exist for windows executables.
+    if env::var("CARGO_CFG_TARGET_OS").unwrap() != "windows" {
+        return;
+    }
+
     // Update manifest with package name and version from Cargo.toml.
     let name = env::var("CARGO_PKG_NAME").unwrap();
     let major = env::var("CARGO_PKG_VERSION_MAJOR").unwrap();
@@ -0,0 +1,286 @@
+//! Interfaces between the platform independent key processing and the system:
+//! key events come from an [`InputBackend`] and are sent to an [`OutputSink`].
+
+use std::fmt::Display;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::host_layout::{HostKey, Modifiers};
+use crate::layout::LayoutIdx;
+use crate::rules::ForegroundContext;
+use crate::toggle_keys::{ToggleKey, ToggleKeyHost};
+
+/// Key event type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    /// Virtual key as defined by the layout set by Windows.
+    ///
+    /// <https://docs.microsoft.com/en-us/windows/win32/inputdev/virtual-key-codes>
+    VirtualKey(u8),
+
+    /// Unicode character.
+    Unicode(char),
+}
+
+/// Key event received from or sent to the system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// Virtual key or Unicode character of this event.
+    pub key: KeyType,
+
+    /// Scan code as defined by the keyboard.
+    /// Extended keycodes have the three most significant bits set (0xExxx).
+    pub scan_code: u16,
+
+    /// Key was released.
+    pub up: bool,
+
+    /// Time in milliseconds since boot.
+    pub time: u32,
+}
+
+impl Display for KeyEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{{ sc: {:#06X}, ", self.scan_code))?;
+
+        match self.key {
+            KeyType::VirtualKey(vk) => f.write_fmt(format_args!("vk: {vk:#04X}"))?,
+            KeyType::Unicode(c) => f.write_fmt(format_args!("char: {c}"))?,
+        }
+
+        f.write_fmt(format_args!(
+            ", {} }}",
+            if self.up { "up  " } else { "down" }
+        ))?;
+
+        Ok(())
+    }
+}
+
+/// Event for the system or the main loop, emitted in order by the
+/// [`Engine`](crate::engine::Engine) for each key event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputEvent {
+    /// Virtual key press. The scan code is 0 for synthetic keys, e.g.
+    /// modifier keys pressed around a character.
+    Press { virtual_key: u8, scan_code: u16 },
+
+    /// Virtual key release.
+    Release { virtual_key: u8, scan_code: u16 },
+
+    /// Unicode character pressed or released.
+    Text { character: char, up: bool },
+
+    /// Not sent to the system.
+    Internal(InternalEvent),
+}
+
+impl OutputEvent {
+    /// Event pressing or releasing `key`.
+    pub fn key(key: KeyType, scan_code: u16, up: bool) -> Self {
+        match (key, up) {
+            (KeyType::VirtualKey(virtual_key), false) => Self::Press {
+                virtual_key,
+                scan_code,
+            },
+            (KeyType::VirtualKey(virtual_key), true) => Self::Release {
+                virtual_key,
+                scan_code,
+            },
+            (KeyType::Unicode(character), up) => Self::Text { character, up },
+        }
+    }
+
+    /// Synthetic virtual key event without scan code.
+    pub fn virtual_key(virtual_key: u8, up: bool) -> Self {
+        Self::key(KeyType::VirtualKey(virtual_key), 0, up)
+    }
+
+    /// The key event to send to the system, `None` for internal events.
+    #[cfg_attr(
+        not(any(windows, all(target_os = "linux", feature = "evdev"))),
+        allow(dead_code)
+    )]
+    pub fn key_event(self, time: u32) -> Option<KeyEvent> {
+        let (key, scan_code, up) = match self {
+            Self::Press {
+                virtual_key,
+                scan_code,
+            } => (KeyType::VirtualKey(virtual_key), scan_code, false),
+            Self::Release {
+                virtual_key,
+                scan_code,
+            } => (KeyType::VirtualKey(virtual_key), scan_code, true),
+            Self::Text { character, up } => (KeyType::Unicode(character), 0, up),
+            Self::Internal(_) => return None,
+        };
+        Some(KeyEvent {
+            key,
+            scan_code,
+            up,
+            time,
+        })
+    }
+}
+
+/// Change of the remapping state caused by a key event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InternalEvent {
+    /// Another layout is active, selected by a layout switching key or a rule.
+    LayoutChanged(LayoutIdx),
+
+    /// The base layer was changed by a `set_base` key.
+    BaseLayerChanged,
+}
+
+/// Source of the key events to remap.
+pub trait InputBackend {
+    /// Waits for the next key event. Returns `None` when no key event arrived
+    /// within `timeout_ms`, an error when the device cannot be read anymore.
+    #[cfg_attr(
+        not(any(windows, all(target_os = "linux", feature = "evdev"))),
+        allow(dead_code)
+    )]
+    async fn next_key_event(&mut self, timeout_ms: u32) -> Result<Option<KeyEvent>>;
+
+    /// Milliseconds since boot, same clock as the key event time stamps.
+    #[cfg_attr(
+        not(any(windows, all(target_os = "linux", feature = "evdev"))),
+        allow(dead_code)
+    )]
+    fn time(&self) -> u32;
+
+    /// Returns the foreground application when it changed since the last call.
+    fn foreground_changed(&mut self) -> Option<ForegroundContext>;
+}
+
+/// Destination of the remapped key events.
+pub trait OutputSink {
+    /// Sends the events in order with the time stamp `time`, skipping
+    /// internal events.
+    fn send(&mut self, events: &[OutputEvent], time: u32);
+
+    /// Returns the key which types the character on the keyboard layout of
+    /// the system, used when there is no
+    /// [`HostLayout`](crate::host_layout::HostLayout) model of it.
+    fn host_key(&self, c: char) -> Option<HostKey>;
+
+    /// Modifier keys held on the system.
+    fn modifiers(&self) -> Modifiers;
+
+    fn toggle_key_enabled(&self, key: ToggleKey) -> bool;
+}
+
+/// Toggle keys of an [`OutputSink`], toggled by adding virtual key events to
+/// the output events.
+pub struct OutputToggleKeys<'a, O> {
+    pub output: &'a O,
+    pub events: &'a mut Vec<OutputEvent>,
+}
+
+impl<O: OutputSink> ToggleKeyHost for OutputToggleKeys<'_, O> {
+    fn is_enabled(&self, key: ToggleKey) -> bool {
+        self.output.toggle_key_enabled(key)
+    }
+
+    fn toggle(&mut self, key: ToggleKey) {
+        for up in [false, true] {
+            (self.events).push(OutputEvent::virtual_key(key.virtual_key(), up));
+        }
+    }
+}
+
+/// In-memory input and output for tests.
+#[cfg(test)]
+pub mod fake {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    pub struct FakeInput {
+        pub time: u32,
+        pub foreground: Option<ForegroundContext>,
+    }
+
+    impl InputBackend for FakeInput {
+        /// Always times out, tests pass key events to the engine directly.
+        async fn next_key_event(&mut self, timeout_ms: u32) -> Result<Option<KeyEvent>> {
+            self.time = self.time.wrapping_add(timeout_ms);
+            Ok(None)
+        }
+
+        fn time(&self) -> u32 {
+            self.time
+        }
+
+        fn foreground_changed(&mut self) -> Option<ForegroundContext> {
+            self.foreground.take()
+        }
+    }
+
+    /// Records sent events. Toggle keys change their state when pressed, like
+    /// on a real system.
+    #[derive(Debug, Default)]
+    pub struct FakeOutput {
+        pub sent: Vec<OutputEvent>,
+        /// Characters typed without modifiers on the keyboard layout of the
+        /// system.
+        pub virtual_keys: HashMap<char, u8>,
+        pub modifiers: Modifiers,
+        pub enabled_toggle_keys: Vec<ToggleKey>,
+    }
+
+    impl OutputSink for FakeOutput {
+        fn send(&mut self, events: &[OutputEvent], _time: u32) {
+            for event in events {
+                if matches!(event, OutputEvent::Internal(_)) {
+                    continue;
+                }
+                self.sent.push(*event);
+
+                let OutputEvent::Press { virtual_key, .. } = *event else {
+                    continue;
+                };
+                let toggle_key = ToggleKey::ALL
+                    .into_iter()
+                    .find(|toggle_key| toggle_key.virtual_key() == virtual_key);
+                if let Some(toggle_key) = toggle_key {
+                    match self
+                        .enabled_toggle_keys
+                        .iter()
+                        .position(|k| *k == toggle_key)
+                    {
+                        Some(idx) => _ = self.enabled_toggle_keys.remove(idx),
+                        None => self.enabled_toggle_keys.push(toggle_key),
+                    }
+                }
+            }
+        }
+
+        fn host_key(&self, c: char) -> Option<HostKey> {
+            let virtual_key = *self.virtual_keys.get(&c)?;
+            Some(HostKey {
+                virtual_key,
+                shift: false,
+                ctrl_alt: false,
+                caps_lock: false,
+                dead: false,
+            })
+        }
+
+        fn modifiers(&self) -> Modifiers {
+            Modifiers {
+                caps_lock: self.toggle_key_enabled(ToggleKey::Caps),
+                ..self.modifiers
+            }
+        }
+
+        fn toggle_key_enabled(&self, key: ToggleKey) -> bool {
+            self.enabled_toggle_keys.contains(&key)
+        }
+    }
+}
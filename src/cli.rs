@@ -0,0 +1,319 @@
+//! Command line parsing.
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, bail};
+
+use crate::config::ConfigSource;
+use crate::log::Level;
+
+pub const USAGE: &str = "\
+Usage: kbremap [<command>] [<config>] [<options>]
+
+Commands:
+  run                 Remap the keyboard (default)
+  check               Validate the configuration
+  print               Print the layouts of the configuration
+  test                Run the `[[tests]]` of the configuration
+  simulate            Feed key events from a script through the layout
+  convert             Write the configuration as one file with includes and definitions resolved
+  replay              Feed a recording through the layout and report changed output
+
+Options:
+  --profile <name>    Load the profile `<name>` instead of a configuration file
+  --list-profiles     Print the available profiles
+  --no-tray           Run without tray icon
+  --device <path>     Keyboard to remap on linux, e.g. `/dev/input/by-id/<name>-event-kbd`
+  --log-level <level> Console output: off, error, info or debug (default)
+  --script <file>     Key events to simulate, read from stdin by default
+  --output <file>     File to convert to, printed by default
+  --recording <file>  Record all key events while running, or the recording to replay
+  --help              Print this help
+";
+
+/// What to do after startup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Run {
+        recording: Option<PathBuf>,
+        device: Option<PathBuf>,
+    },
+    Check,
+    Print,
+    Test,
+    Simulate {
+        script: Option<PathBuf>,
+    },
+    Convert {
+        output: Option<PathBuf>,
+    },
+    Replay {
+        recording: PathBuf,
+    },
+    ListProfiles,
+    Help,
+}
+
+impl Command {
+    /// Only `run` installs the keyboard hook, all other commands work without
+    /// touching the system.
+    pub fn is_headless(&self) -> bool {
+        !matches!(self, Command::Run { .. })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cli {
+    pub command: Command,
+    pub config: ConfigSource,
+    // Only windows has a tray icon.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub tray: bool,
+    pub log_level: Level,
+}
+
+impl Cli {
+    /// Parses the arguments without the program name.
+    pub fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Self> {
+        let mut args = args.into_iter();
+        let mut command = None;
+        let mut config = None;
+        let mut profile = None;
+        let mut tray = true;
+        let mut log_level = Level::Debug;
+        let mut script = None;
+        let mut output = None;
+        let mut recording = None;
+        let mut device = None;
+        let mut list_profiles = false;
+        let mut help = false;
+
+        while let Some(arg) = args.next() {
+            let mut value = |option: &str| {
+                args.next()
+                    .with_context(|| format!("missing value for `{option}`"))
+            };
+            match arg.to_str() {
+                Some("--profile") => {
+                    let name = value("--profile")?;
+                    profile = Some(name.into_string().ok().context("invalid profile name")?);
+                }
+                Some("--log-level") => {
+                    let level = value("--log-level")?;
+                    log_level = level.to_str().unwrap_or_default().parse()?;
+                }
+                Some("--script") => script = Some(PathBuf::from(value("--script")?)),
+                Some("--output") => output = Some(PathBuf::from(value("--output")?)),
+                Some("--recording") => recording = Some(PathBuf::from(value("--recording")?)),
+                Some("--device") => device = Some(PathBuf::from(value("--device")?)),
+                Some("--no-tray") => tray = false,
+                Some("--list-profiles") => list_profiles = true,
+                Some("--help" | "-h") => help = true,
+                Some(option) if option.starts_with("--") => bail!("unknown option `{option}`"),
+                Some(name) if command.is_none() && config.is_none() && command_name(name) => {
+                    command = Some(name.to_string());
+                }
+                _ if config.is_none() => config = Some(PathBuf::from(arg)),
+                _ => bail!("unexpected argument `{}`", arg.to_string_lossy()),
+            }
+        }
+
+        let name = command.as_deref().unwrap_or("run");
+        let command = match command.as_deref() {
+            _ if help => Command::Help,
+            None if list_profiles => Command::ListProfiles,
+            Some(_) if list_profiles => bail!("`--list-profiles` cannot be used with `{name}`"),
+            None | Some("run") => Command::Run {
+                recording: recording.take(),
+                device: device.take(),
+            },
+            Some("check") => Command::Check,
+            Some("print") => Command::Print,
+            Some("test") => Command::Test,
+            Some("simulate") => Command::Simulate {
+                script: script.take(),
+            },
+            Some("convert") => Command::Convert {
+                output: output.take(),
+            },
+            Some("replay") => Command::Replay {
+                recording: recording
+                    .take()
+                    .context("`replay` requires `--recording <file>`")?,
+            },
+            Some(_) => unreachable!(),
+        };
+
+        // Options of other commands would be silently ignored.
+        let unused = [
+            ("--script", script),
+            ("--output", output),
+            ("--recording", recording),
+            ("--device", device),
+        ];
+        if command != Command::Help
+            && let Some((option, _)) = unused.iter().find(|(_, value)| value.is_some())
+        {
+            let name = match command {
+                Command::ListProfiles => "--list-profiles",
+                _ => name,
+            };
+            bail!("`{option}` cannot be used with `{name}`");
+        }
+
+        let config = match (config, profile) {
+            (Some(_), Some(_)) => {
+                bail!("cannot use a configuration file together with `--profile`")
+            }
+            (Some(path), None) => ConfigSource::Path(path),
+            (None, Some(name)) => ConfigSource::Profile(name),
+            (None, None) => ConfigSource::Default,
+        };
+
+        Ok(Self {
+            command,
+            config,
+            tray,
+            log_level,
+        })
+    }
+}
+
+fn command_name(name: &str) -> bool {
+    [
+        "run", "check", "print", "test", "simulate", "convert", "replay",
+    ]
+    .contains(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Cli> {
+        Cli::parse(args.split_whitespace().map(OsString::from))
+    }
+
+    #[test]
+    fn commands() {
+        let cli = parse("").unwrap();
+        assert_eq!(
+            cli,
+            Cli {
+                command: Command::Run {
+                    recording: None,
+                    device: None,
+                },
+                config: ConfigSource::Default,
+                tray: true,
+                log_level: Level::Debug,
+            }
+        );
+
+        // A single argument is the configuration file, as in earlier versions.
+        let cli = parse("neo.toml").unwrap();
+        assert_eq!(
+            cli.command,
+            Command::Run {
+                recording: None,
+                device: None,
+            }
+        );
+        assert_eq!(cli.config, ConfigSource::Path("neo.toml".into()));
+
+        let cli = parse("run --no-tray --log-level info neo.toml --recording a.jsonl").unwrap();
+        assert_eq!(
+            cli.command,
+            Command::Run {
+                recording: Some("a.jsonl".into()),
+                device: None,
+            }
+        );
+        assert_eq!(cli.config, ConfigSource::Path("neo.toml".into()));
+        assert!(!cli.tray);
+        assert_eq!(cli.log_level, Level::Info);
+
+        let cli = parse("--device /dev/input/event3").unwrap();
+        assert_eq!(
+            cli.command,
+            Command::Run {
+                recording: None,
+                device: Some("/dev/input/event3".into()),
+            }
+        );
+
+        let cli = parse("check --profile neo").unwrap();
+        assert_eq!(cli.command, Command::Check);
+        assert_eq!(cli.config, ConfigSource::Profile("neo".into()));
+        assert!(cli.command.is_headless());
+
+        let cli = parse("simulate config.toml --script keys.txt").unwrap();
+        assert_eq!(
+            cli.command,
+            Command::Simulate {
+                script: Some("keys.txt".into())
+            }
+        );
+
+        assert_eq!(
+            parse("convert").unwrap().command,
+            Command::Convert { output: None }
+        );
+        assert_eq!(parse("print").unwrap().command, Command::Print);
+        assert_eq!(parse("test neo.toml").unwrap().command, Command::Test);
+        assert_eq!(
+            parse("replay --recording a.jsonl").unwrap().command,
+            Command::Replay {
+                recording: "a.jsonl".into()
+            }
+        );
+        assert_eq!(
+            parse("--list-profiles").unwrap().command,
+            Command::ListProfiles
+        );
+        assert_eq!(parse("check --help").unwrap().command, Command::Help);
+
+        // Commands are only recognized before the configuration file.
+        let cli = parse("check print").unwrap();
+        assert_eq!(cli.command, Command::Check);
+        assert_eq!(cli.config, ConfigSource::Path("print".into()));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |args| parse(args).unwrap_err().to_string();
+        assert_eq!(error("--profile"), "missing value for `--profile`");
+        assert_eq!(error("--verbose"), "unknown option `--verbose`");
+        assert_eq!(error("replay"), "`replay` requires `--recording <file>`");
+        assert_eq!(
+            error("check --script keys.txt"),
+            "`--script` cannot be used with `check`"
+        );
+        assert_eq!(
+            error("--output a.toml"),
+            "`--output` cannot be used with `run`"
+        );
+        assert_eq!(
+            error("replay --recording a.jsonl --device /dev/input/event3"),
+            "`--device` cannot be used with `replay`"
+        );
+        assert_eq!(
+            error("--list-profiles --recording a.jsonl"),
+            "`--recording` cannot be used with `--list-profiles`"
+        );
+        assert_eq!(
+            error("print --list-profiles"),
+            "`--list-profiles` cannot be used with `print`"
+        );
+        assert_eq!(error("check a.toml b.toml"), "unexpected argument `b.toml`");
+        assert_eq!(
+            error("neo.toml --profile neo"),
+            "cannot use a configuration file together with `--profile`"
+        );
+        assert_eq!(
+            error("--log-level trace"),
+            "invalid log level `trace`, expected `off`, `error`, `info` or `debug`"
+        );
+    }
+}
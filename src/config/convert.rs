@@ -0,0 +1,336 @@
+//! Writing a merged configuration back as a single self-contained file.
+
+use std::fmt::{self, Write};
+use std::path::Path;
+
+use anyhow::Result;
+use toml::Value;
+
+use super::{Config, LockTarget, Mapping, MappingTarget};
+use crate::layout::{Fallthrough, SendAs, UnicodeInput};
+
+/// Loads a configuration file and returns it as one file with all includes
+/// merged and definitions expanded.
+pub fn convert_toml(path: &Path) -> Result<String> {
+    Ok(Config::load(path)?.to_toml())
+}
+
+impl Config {
+    fn to_toml(&self) -> String {
+        let mut out = String::new();
+        self.write_toml(&mut out, "").unwrap();
+        out
+    }
+
+    /// Writes the configuration with its tables nested under `prefix`.
+    fn write_toml(&self, out: &mut String, prefix: &str) -> fmt::Result {
+        let settings = [
+            ("base_layer", &self.base_layer),
+            ("caps_lock_layer", &self.caps_lock_layer),
+            ("num_lock_layer", &self.num_lock_layer),
+            ("scroll_lock_layer", &self.scroll_lock_layer),
+            ("default_layout", &self.default_layout),
+        ];
+        for (key, value) in settings {
+            if let Some(value) = value {
+                writeln!(out, "{key} = {}", string(value))?;
+            }
+        }
+        if let Some(persist_base_layer) = self.persist_base_layer {
+            writeln!(out, "persist_base_layer = {persist_base_layer}")?;
+        }
+        if let Some(wrap_modifiers) = self.wrap_modifiers {
+            writeln!(out, "wrap_modifiers = {wrap_modifiers}")?;
+        }
+        if let Some(unicode_input) = &self.unicode_input {
+            writeln!(out, "unicode_input = {}", unicode_input_toml(unicode_input))?;
+        }
+
+        let mut layers: Vec<_> = self.layers.iter().collect();
+        layers.sort_by_key(|(name, _)| *name);
+        if !layers.is_empty() {
+            writeln!(out, "\n[{prefix}layers]")?;
+        }
+        for (name, layer) in layers {
+            let mut settings = String::new();
+            if let Some(fallthrough) = layer.fallthrough {
+                let fallthrough = match fallthrough {
+                    Fallthrough::Transparent => "transparent",
+                    Fallthrough::Opaque => "opaque",
+                    Fallthrough::Passthrough => "passthrough",
+                };
+                write!(settings, "fallthrough = {}, ", string(fallthrough))?;
+            }
+            if let Some(timeout_ms) = layer.lock_timeout_ms {
+                write!(settings, "lock_timeout_ms = {timeout_ms}, ")?;
+            }
+            if let Some(send_as) = layer.send_as {
+                write!(settings, "send_as = {}, ", string(send_as_name(send_as)))?;
+            }
+
+            if settings.is_empty() {
+                writeln!(out, "{} = [", key(name))?;
+            } else {
+                writeln!(out, "{} = {{ {settings}mappings = [", key(name))?;
+            }
+            for mapping in &layer.mappings {
+                writeln!(out, "    {},", mapping_toml(mapping))?;
+            }
+            writeln!(out, "]{}", if settings.is_empty() { "" } else { " }" })?;
+        }
+
+        for conditional_layer in &self.conditional_layers {
+            writeln!(out, "\n[[{prefix}conditional_layers]]")?;
+            writeln!(out, "layers = {}", strings(&conditional_layer.layers))?;
+            writeln!(out, "activate = {}", string(&conditional_layer.activate))?;
+        }
+
+        for rule in &self.rules {
+            writeln!(out, "\n[[{prefix}rules]]")?;
+            let conditions = [
+                ("process", &rule.process),
+                ("window_class", &rule.window_class),
+                ("input_language", &rule.input_language),
+                ("layout", &rule.layout),
+            ];
+            for (key, value) in conditions {
+                if let Some(value) = value {
+                    writeln!(out, "{key} = {}", string(value))?;
+                }
+            }
+            if rule.passthrough {
+                writeln!(out, "passthrough = true")?;
+            }
+        }
+
+        for test in &self.tests {
+            writeln!(out, "\n[[{prefix}tests]]")?;
+            writeln!(out, "name = {}", string(&test.name))?;
+            if let Some(layout) = &test.layout {
+                writeln!(out, "layout = {}", string(layout))?;
+            }
+            writeln!(out, "keys = {}", string(&test.keys))?;
+            if let Some(types) = &test.types {
+                writeln!(out, "types = {}", string(types))?;
+            }
+            if let Some(actions) = &test.actions {
+                writeln!(out, "actions = {}", strings(actions))?;
+            }
+            if let Some(layer) = &test.layer {
+                writeln!(out, "layer = {}", string(layer))?;
+            }
+        }
+
+        let mut layouts: Vec<_> = self.layouts.iter().collect();
+        layouts.sort_by_key(|(name, _)| *name);
+        for (name, layout) in layouts {
+            let prefix = format!("{prefix}layouts.{}", key(name));
+            writeln!(out, "\n[{prefix}]")?;
+            layout.write_toml(out, &format!("{prefix}."))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn mapping_toml(mapping: &Mapping) -> String {
+    let mut fields = vec![format!("scan_code = {:#04X}", mapping.scan_code)];
+    match &mapping.target {
+        MappingTarget::Characters {
+            characters,
+            send_as,
+            wrap_modifiers,
+        } => {
+            fields.push(format!("characters = {}", string(characters)));
+            if let Some(send_as) = send_as {
+                fields.push(format!("send_as = {}", string(send_as_name(*send_as))));
+            }
+            if let Some(wrap_modifiers) = wrap_modifiers {
+                fields.push(format!("wrap_modifiers = {wrap_modifiers}"));
+            }
+        }
+        MappingTarget::VirtualKeys { virtual_keys } => {
+            let virtual_keys: Vec<_> = virtual_keys.iter().map(|vk| format!("{vk:#04X}")).collect();
+            fields.push(format!("virtual_keys = [{}]", virtual_keys.join(", ")));
+        }
+        MappingTarget::SwitchLayout { layout } => {
+            fields.push(format!("switch_layout = {}", string(layout)));
+        }
+        MappingTarget::CycleLayouts { layouts } => {
+            fields.push(format!("cycle_layouts = {}", strings(layouts)));
+        }
+        MappingTarget::Layer {
+            layer,
+            lock,
+            virtual_key,
+        } => {
+            if let Some(layer) = layer {
+                fields.push(format!("layer = {}", string(layer)));
+            }
+            match lock {
+                Some(LockTarget::Toggle(layer)) => fields.push(format!("lock = {}", string(layer))),
+                Some(LockTarget::Replace(layer)) => fields.push(format!("to = {}", string(layer))),
+                Some(LockTarget::Clear) => fields.push(String::from("clear_locks = true")),
+                Some(LockTarget::SetBase(layer)) => {
+                    fields.push(format!("set_base = {}", string(layer)));
+                }
+                None => {}
+            }
+            if let Some(virtual_key) = virtual_key {
+                fields.push(format!("virtual_key = {virtual_key:#04X}"));
+            }
+        }
+    }
+    format!("{{ {} }}", fields.join(", "))
+}
+
+fn send_as_name(send_as: SendAs) -> &'static str {
+    match send_as {
+        SendAs::Auto => "auto",
+        SendAs::Unicode => "unicode",
+        SendAs::VirtualKey => "virtual_key",
+        SendAs::AltCode => "alt_code",
+    }
+}
+
+fn unicode_input_toml(unicode_input: &UnicodeInput) -> String {
+    match unicode_input {
+        UnicodeInput::CtrlShiftU => string("ctrl_shift_u"),
+        UnicodeInput::Compose {
+            virtual_key,
+            sequences,
+        } => {
+            let sequences: Vec<_> = sequences
+                .iter()
+                .map(|(c, sequence)| format!("{} = {}", key(&c.to_string()), string(sequence)))
+                .collect();
+            format!(
+                "{{ compose = {{ virtual_key = {virtual_key:#04X}, sequences = {{ {} }} }} }}",
+                sequences.join(", ")
+            )
+        }
+        UnicodeInput::Command(args) => format!("{{ command = {} }}", strings(args)),
+    }
+}
+
+/// Quoted and escaped TOML string.
+fn string(s: &str) -> String {
+    Value::from(s).to_string()
+}
+
+fn strings(strings: &[String]) -> String {
+    let strings: Vec<_> = strings.iter().map(|s| string(s)).collect();
+    format!("[{}]", strings.join(", "))
+}
+
+/// Bare key if possible, quoted otherwise.
+fn key(name: &str) -> String {
+    let bare = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if bare { name.to_string() } else { string(name) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Layouts;
+    use crate::print;
+
+    const CONFIG: &str = r#"
+        base_layer = "base"
+        caps_lock_layer = "shift"
+        unicode_input = { compose = { virtual_key = 0x5D, sequences = { "é" = "'e", "→" = "->" } } }
+
+        [define.keys]
+        a = 0x1E
+
+        [define.mappings]
+        letters = [{ scan_code = "a", characters = "ab" }]
+
+        [layers]
+        base = [
+            { use = "letters" },
+            { scan_code = 0x2A, layer = "shift", virtual_key = 0xA0 },
+            { scan_code = 0x01, switch_layout = "gaming" },
+        ]
+        shift = { fallthrough = "opaque", lock_timeout_ms = 5000, send_as = "unicode", mappings = [
+            { scan_code = "a", characters = "A\"", send_as = "alt_code", wrap_modifiers = true },
+            { scan_code = 0x01, clear_locks = true },
+        ] }
+
+        [[conditional_layers]]
+        layers = ["base", "shift"]
+        activate = "shift"
+
+        [[tests]]
+        name = "shift"
+        keys = "shift+a"
+        types = "A\""
+
+        [layouts."my gaming"]
+        base_layer = "game"
+        [layouts."my gaming".layers]
+        game = [{ scan_code = 0x02, virtual_keys = [0x41, 0x42] }]
+
+        [[rules]]
+        process = "game.exe"
+        layout = "my gaming"
+        "#;
+
+    #[test]
+    fn convert() {
+        let config = Config::parse(None, CONFIG, &mut Vec::new()).unwrap();
+        let converted = config.to_toml();
+        assert_eq!(
+            converted,
+            r#"base_layer = "base"
+caps_lock_layer = "shift"
+unicode_input = { compose = { virtual_key = 0x5D, sequences = { "é" = "'e", "→" = "->" } } }
+
+[layers]
+base = [
+    { scan_code = 0x1E, characters = "ab" },
+    { scan_code = 0x2A, layer = "shift", virtual_key = 0xA0 },
+    { scan_code = 0x01, switch_layout = "gaming" },
+]
+shift = { fallthrough = "opaque", lock_timeout_ms = 5000, send_as = "unicode", mappings = [
+    { scan_code = 0x1E, characters = 'A"', send_as = "alt_code", wrap_modifiers = true },
+    { scan_code = 0x01, clear_locks = true },
+] }
+
+[[conditional_layers]]
+layers = ["base", "shift"]
+activate = "shift"
+
+[[rules]]
+process = "game.exe"
+layout = "my gaming"
+
+[[tests]]
+name = "shift"
+keys = "shift+a"
+types = 'A"'
+
+[layouts."my gaming"]
+base_layer = "game"
+
+[layouts."my gaming".layers]
+game = [
+    { scan_code = 0x02, virtual_keys = [0x41, 0x42] },
+]
+"#
+        );
+
+        // Converted configurations result in the same layouts.
+        let config = CONFIG.replace("\"gaming\"", "\"my gaming\"");
+        let converted = Config::parse(None, &config, &mut Vec::new())
+            .unwrap()
+            .to_toml();
+        assert_eq!(
+            print::layouts(&Layouts::parse_toml(&converted).unwrap()),
+            print::layouts(&Layouts::parse_toml(&config).unwrap())
+        );
+    }
+}
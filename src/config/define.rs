@@ -0,0 +1,305 @@
+//! Reusable definitions from the `[define]` table and their expansion.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
+
+use anyhow::{Result, bail};
+use serde::Deserialize;
+use toml::Spanned;
+
+use super::{LockTarget, Mapping, MappingTarget, ReadableMapping};
+use crate::layout::ScanCode;
+
+/// `[define]` table as written by the user.
+#[derive(Debug, Default, Deserialize)]
+pub(super) struct ReadableDefines {
+    /// Names for scan codes.
+    #[serde(default)]
+    keys: HashMap<String, ScanCode>,
+    /// Reusable strings for the `characters` target.
+    #[serde(default)]
+    characters: HashMap<String, String>,
+    /// Reusable groups of mappings.
+    #[serde(default)]
+    mappings: HashMap<String, Vec<Spanned<ReadableMapping>>>,
+}
+
+/// Scan code given as number or as name from `[define.keys]`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(super) enum KeyName {
+    ScanCode(ScanCode),
+    Name(String),
+}
+
+/// Characters given literally or by name from `[define.characters]`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(super) enum CharactersRef {
+    Literal(String),
+    Use {
+        #[serde(rename = "use")]
+        name: String,
+    },
+}
+
+/// Definitions with all mapping groups expanded.
+#[derive(Debug, Default, Clone)]
+pub(super) struct Defines {
+    keys: HashMap<String, ScanCode>,
+    characters: HashMap<String, String>,
+    mappings: HashMap<String, Vec<Mapping>>,
+}
+
+/// Position in a configuration file.
+#[derive(Debug, Clone)]
+pub(super) struct Location {
+    file: Option<Rc<Path>>,
+    line: usize,
+    column: usize,
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Text of a configuration file to translate spans into locations.
+pub(super) struct Source<'a> {
+    file: Option<Rc<Path>>,
+    text: &'a str,
+}
+
+impl<'a> Source<'a> {
+    pub fn new(file: Option<&Path>, text: &'a str) -> Self {
+        Self {
+            file: file.map(Rc::from),
+            text,
+        }
+    }
+
+    pub fn location(&self, span: Range<usize>) -> Location {
+        let before = &self.text[..span.start];
+        let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+        Location {
+            file: self.file.clone(),
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+impl Defines {
+    /// Adds the definitions of a file. Overrides existing definitions with
+    /// the same name.
+    pub fn extend(&mut self, defines: ReadableDefines, source: &Source) -> Result<()> {
+        self.keys.extend(defines.keys);
+        self.characters.extend(defines.characters);
+
+        // Groups of this file shadow inherited groups, even when referenced
+        // from another group of this file.
+        for name in defines.mappings.keys() {
+            self.mappings.remove(name);
+        }
+
+        let mut names: Vec<_> = defines.mappings.keys().collect();
+        names.sort();
+        for name in names {
+            if !self.mappings.contains_key(name) {
+                let mut expander = Expander {
+                    defines: self,
+                    groups: &defines.mappings,
+                    source,
+                    group_stack: Vec::new(),
+                };
+                expander.expand_group(name, None)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Scan code of a key name from `[define.keys]`.
+    pub fn key(&self, name: &str) -> Option<ScanCode> {
+        self.keys.get(name).copied()
+    }
+
+    /// Overrides definitions with the ones from `overlay`.
+    pub fn merge(&mut self, overlay: Defines) {
+        self.keys.extend(overlay.keys);
+        self.characters.extend(overlay.characters);
+        self.mappings.extend(overlay.mappings);
+    }
+
+    /// Resolves all names and groups used by `mappings`.
+    pub fn expand(
+        &mut self,
+        mappings: &[Spanned<ReadableMapping>],
+        source: &Source,
+    ) -> Result<Vec<Mapping>> {
+        let groups = HashMap::new();
+        let mut expander = Expander {
+            defines: self,
+            groups: &groups,
+            source,
+            group_stack: Vec::new(),
+        };
+        let mut expanded = Vec::new();
+        expander.expand(mappings, &mut expanded)?;
+        Ok(expanded)
+    }
+}
+
+struct Expander<'a> {
+    defines: &'a mut Defines,
+    /// Not yet expanded groups of the current file.
+    groups: &'a HashMap<String, Vec<Spanned<ReadableMapping>>>,
+    source: &'a Source<'a>,
+    /// Groups currently being expanded, to detect cycles.
+    group_stack: Vec<String>,
+}
+
+impl Expander<'_> {
+    fn expand(
+        &mut self,
+        mappings: &[Spanned<ReadableMapping>],
+        expanded: &mut Vec<Mapping>,
+    ) -> Result<()> {
+        for mapping in mappings {
+            let location = self.source.location(mapping.span());
+            let mapping = mapping.get_ref();
+
+            if let Some(group) = &mapping.group {
+                if mapping.scan_code.is_some() {
+                    bail!("{location}: `use` cannot be combined with `scan_code`");
+                }
+                let span = group.span();
+                let group = self.expand_group(group.get_ref(), Some(span))?;
+                expanded.extend_from_slice(group);
+                continue;
+            }
+
+            let Some(scan_code) = &mapping.scan_code else {
+                bail!("{location}: mapping requires either `scan_code` or `use`");
+            };
+            let scan_code = match scan_code.get_ref() {
+                KeyName::ScanCode(scan_code) => *scan_code,
+                KeyName::Name(name) => match self.defines.keys.get(name) {
+                    Some(scan_code) => *scan_code,
+                    None => bail!(
+                        "{}: key `{name}` is not defined",
+                        self.source.location(scan_code.span())
+                    ),
+                },
+            };
+
+            if mapping.characters.is_none() {
+                if mapping.send_as.is_some() {
+                    bail!("{location}: `send_as` requires `characters`");
+                }
+                if mapping.wrap_modifiers.is_some() {
+                    bail!("{location}: `wrap_modifiers` requires `characters`");
+                }
+            }
+
+            let target = if let Some(characters) = &mapping.characters {
+                let characters = match characters.get_ref() {
+                    CharactersRef::Literal(characters) => characters.clone(),
+                    CharactersRef::Use { name } => match self.defines.characters.get(name) {
+                        Some(characters) => characters.clone(),
+                        None => bail!(
+                            "{}: characters `{name}` are not defined",
+                            self.source.location(characters.span())
+                        ),
+                    },
+                };
+                MappingTarget::Characters {
+                    characters,
+                    send_as: mapping.send_as,
+                    wrap_modifiers: mapping.wrap_modifiers,
+                }
+            } else if let Some(virtual_keys) = &mapping.virtual_keys {
+                MappingTarget::VirtualKeys {
+                    virtual_keys: virtual_keys.clone(),
+                }
+            } else if let Some(layout) = &mapping.switch_layout {
+                MappingTarget::SwitchLayout {
+                    layout: layout.clone(),
+                }
+            } else if let Some(layouts) = &mapping.cycle_layouts {
+                MappingTarget::CycleLayouts {
+                    layouts: layouts.clone(),
+                }
+            } else {
+                let mut locks = [
+                    mapping.lock.clone().map(LockTarget::Toggle),
+                    mapping.to.clone().map(LockTarget::Replace),
+                    mapping
+                        .clear_locks
+                        .filter(|clear| *clear)
+                        .map(|_| LockTarget::Clear),
+                    mapping.set_base.clone().map(LockTarget::SetBase),
+                ]
+                .into_iter()
+                .flatten();
+                let lock = locks.next();
+                if locks.next().is_some() {
+                    bail!(
+                        "{location}: only one of `lock`, `to`, `clear_locks` and `set_base` can be used"
+                    );
+                }
+                MappingTarget::Layer {
+                    layer: mapping.layer.clone(),
+                    lock,
+                    virtual_key: mapping.virtual_key,
+                }
+            };
+
+            expanded.push(Mapping {
+                scan_code,
+                target,
+                location,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns the expanded mappings of a group, expanding it first if needed.
+    fn expand_group(&mut self, name: &str, used_at: Option<Range<usize>>) -> Result<&[Mapping]> {
+        if !self.defines.mappings.contains_key(name) {
+            let Some(group) = self.groups.get(name) else {
+                match used_at {
+                    Some(span) => bail!(
+                        "{}: mapping group `{name}` is not defined",
+                        self.source.location(span)
+                    ),
+                    None => bail!("mapping group `{name}` is not defined"),
+                }
+            };
+
+            if self.group_stack.iter().any(|n| n == name) {
+                bail!(
+                    "cyclic mapping group: {} -> {name}",
+                    self.group_stack.join(" -> ")
+                );
+            }
+
+            self.group_stack.push(name.to_string());
+            let mut expanded = Vec::new();
+            self.expand(group, &mut expanded)?;
+            self.group_stack.pop();
+
+            self.defines.mappings.insert(name.to_string(), expanded);
+        }
+
+        Ok(&self.defines.mappings[name])
+    }
+}
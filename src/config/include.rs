@@ -0,0 +1,266 @@
+//! Loading of configuration files and merging of their includes.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{Context, Result, bail};
+
+use super::define::{Defines, Source};
+use super::search::find_existing;
+use super::{ConditionalLayer, Config, Layer, Mapping, ReadableConfig};
+use crate::layout::ScanCode;
+
+impl Config {
+    /// Loads a configuration file and merges all of its includes.
+    pub(super) fn load(path: &Path) -> Result<Self> {
+        let mut include_stack = Vec::new();
+        Self::load_recursive(path, &mut include_stack)
+    }
+
+    fn load_recursive(path: &Path, include_stack: &mut Vec<PathBuf>) -> Result<Self> {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("cannot load configuration file {}", path.display()))?;
+
+        if let Some(idx) = include_stack.iter().position(|p| *p == path) {
+            let cycle: Vec<_> = include_stack[idx..]
+                .iter()
+                .chain([&path])
+                .map(|p| p.display().to_string())
+                .collect();
+            bail!("cyclic include: {}", cycle.join(" -> "));
+        }
+
+        let config = fs::read_to_string(&path)
+            .with_context(|| format!("cannot read configuration file {}", path.display()))?;
+
+        include_stack.push(path.clone());
+        let config = Self::parse(Some(&path), &config, include_stack);
+        include_stack.pop();
+
+        let mut config = config?;
+        config.files.insert(0, path);
+        Ok(config)
+    }
+
+    /// Parses a single configuration file, merges its includes in order and
+    /// applies the file on top of them.
+    ///
+    /// Includes are relative to `path`. Without a path (e.g. parsed from a
+    /// string) they are relative to the working directory.
+    pub(super) fn parse(
+        path: Option<&Path>,
+        config: &str,
+        include_stack: &mut Vec<PathBuf>,
+    ) -> Result<Self> {
+        let source = Source::new(path, config);
+        let config: ReadableConfig = toml::from_str(config).with_context(|| match path {
+            Some(path) => format!("invalid configuration file {}", path.display()),
+            None => String::from("invalid configuration"),
+        })?;
+
+        let base_dir = match path {
+            Some(path) => path.parent().unwrap().to_path_buf(),
+            None => env::current_dir()?,
+        };
+
+        Self::from_readable(
+            config,
+            &source,
+            &base_dir,
+            include_stack,
+            &Defines::default(),
+        )
+    }
+
+    /// Merges the includes of `config` and applies `config` on top of them.
+    ///
+    /// `[layouts]` sections are handled like separate files in the same
+    /// directory which inherit the definitions of the enclosing file.
+    fn from_readable(
+        config: ReadableConfig,
+        source: &Source,
+        base_dir: &Path,
+        include_stack: &mut Vec<PathBuf>,
+        inherited_defines: &Defines,
+    ) -> Result<Self> {
+        let mut merged = Config {
+            defines: inherited_defines.clone(),
+            ..Default::default()
+        };
+        for include in &config.include {
+            let path = resolve_path(include, base_dir).with_context(|| {
+                format!(
+                    "cannot resolve include {} relative to {}",
+                    include.display(),
+                    base_dir.display()
+                )
+            })?;
+            merged.merge(Self::load_recursive(&path, include_stack)?);
+        }
+
+        // Definitions of included files are visible in this file.
+        let mut defines = merged.defines.clone();
+        defines.extend(config.define, source)?;
+
+        let mut layers = HashMap::new();
+        for (name, layer) in &config.layers {
+            let layer = Layer {
+                fallthrough: layer.fallthrough,
+                lock_timeout_ms: layer.lock_timeout_ms,
+                send_as: layer.send_as,
+                mappings: defines.expand(&layer.mappings, source)?,
+            };
+            layers.insert(name.clone(), layer);
+        }
+
+        let mut layouts = HashMap::new();
+        for (name, layout) in config.layouts {
+            if !layout.layouts.is_empty() {
+                bail!("layout `{name}`: layouts cannot be nested");
+            }
+            if !layout.rules.is_empty() {
+                bail!("layout `{name}`: rules are only supported at the top level");
+            }
+            if !layout.tests.is_empty() {
+                bail!("layout `{name}`: tests are only supported at the top level");
+            }
+            if layout.persist_base_layer.is_some() {
+                bail!("layout `{name}`: `persist_base_layer` is only supported at the top level");
+            }
+            if layout.unicode_input.is_some() {
+                bail!("layout `{name}`: `unicode_input` is only supported at the top level");
+            }
+            let layout = Self::from_readable(layout, source, base_dir, include_stack, &defines)
+                .with_context(|| format!("layout `{name}`"))?;
+            layouts.insert(name, layout);
+        }
+
+        merged.merge(Config {
+            base_layer: config.base_layer,
+            caps_lock_layer: config.caps_lock_layer,
+            num_lock_layer: config.num_lock_layer,
+            scroll_lock_layer: config.scroll_lock_layer,
+            persist_base_layer: config.persist_base_layer,
+            wrap_modifiers: config.wrap_modifiers,
+            unicode_input: config.unicode_input,
+            defines,
+            layers,
+            conditional_layers: config
+                .conditional_layers
+                .into_iter()
+                .map(|conditional_layer| ConditionalLayer {
+                    location: source.location(conditional_layer.layers.span()),
+                    layers: conditional_layer.layers.into_inner(),
+                    activate: conditional_layer.activate,
+                })
+                .collect(),
+            default_layout: config.default_layout,
+            layouts,
+            rules: config.rules,
+            tests: config.tests,
+            files: Vec::new(),
+        });
+
+        for name in config.remove_layers {
+            if merged.layers.remove(&name).is_none() {
+                bail!("cannot remove layer `{name}`: layer not found");
+            }
+        }
+
+        Ok(merged)
+    }
+
+    /// Overrides `self` with values from `overlay`.
+    ///
+    /// Mappings are replaced individually per layer and scan code.
+    fn merge(&mut self, overlay: Config) {
+        if overlay.base_layer.is_some() {
+            self.base_layer = overlay.base_layer;
+        }
+        if overlay.caps_lock_layer.is_some() {
+            self.caps_lock_layer = overlay.caps_lock_layer;
+        }
+        if overlay.num_lock_layer.is_some() {
+            self.num_lock_layer = overlay.num_lock_layer;
+        }
+        if overlay.scroll_lock_layer.is_some() {
+            self.scroll_lock_layer = overlay.scroll_lock_layer;
+        }
+        if overlay.persist_base_layer.is_some() {
+            self.persist_base_layer = overlay.persist_base_layer;
+        }
+        if overlay.wrap_modifiers.is_some() {
+            self.wrap_modifiers = overlay.wrap_modifiers;
+        }
+        if overlay.unicode_input.is_some() {
+            self.unicode_input = overlay.unicode_input;
+        }
+        if overlay.default_layout.is_some() {
+            self.default_layout = overlay.default_layout;
+        }
+
+        for (name, overlay_layout) in overlay.layouts {
+            match self.layouts.get_mut(&name) {
+                Some(layout) => layout.merge(overlay_layout),
+                None => {
+                    self.layouts.insert(name, overlay_layout);
+                }
+            }
+        }
+
+        self.defines.merge(overlay.defines);
+        self.conditional_layers.extend(overlay.conditional_layers);
+
+        // Rules of the including file take precedence.
+        self.rules.splice(0..0, overlay.rules);
+
+        self.tests.extend(overlay.tests);
+        self.files.extend(overlay.files);
+
+        for (name, overlay_layer) in overlay.layers {
+            let Some(layer) = self.layers.get_mut(&name) else {
+                self.layers.insert(name, overlay_layer);
+                continue;
+            };
+
+            if overlay_layer.fallthrough.is_some() {
+                layer.fallthrough = overlay_layer.fallthrough;
+            }
+            if overlay_layer.lock_timeout_ms.is_some() {
+                layer.lock_timeout_ms = overlay_layer.lock_timeout_ms;
+            }
+            if overlay_layer.send_as.is_some() {
+                layer.send_as = overlay_layer.send_as;
+            }
+
+            let overridden: HashSet<ScanCode> = overlay_layer
+                .mappings
+                .iter()
+                .flat_map(Mapping::scan_codes)
+                .collect();
+            layer.mappings = layer
+                .mappings
+                .drain(..)
+                .flat_map(Mapping::split)
+                .filter(|mapping| !overridden.contains(&mapping.scan_code))
+                .chain(overlay_layer.mappings)
+                .collect();
+        }
+    }
+}
+
+/// Resolves a relative configuration file path.
+///
+/// Looks in `base_dir` first and falls back to the directory of our executable.
+pub fn resolve_path(path: &Path, base_dir: &Path) -> Result<PathBuf> {
+    let mut candidates = vec![base_dir.join(path)];
+    if path.is_relative() {
+        let mut exe_dir = env::current_exe()?;
+        exe_dir.pop();
+        candidates.push(exe_dir.join(path));
+    }
+
+    find_existing(&candidates, Path::exists)
+}
@@ -0,0 +1,1325 @@
+//! Serde based configuration parser.
+
+mod convert;
+mod define;
+mod include;
+mod search;
+
+use std::collections::HashMap;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use toml::Spanned;
+
+use crate::layout::{
+    CharacterOutput, Fallthrough, GlobalOptions, KeyAction, LayerLock, Layout, LayoutBuilder,
+    LayoutIdx, LayoutSwitch, Layouts, ScanCode, SendAs, UnicodeInput,
+};
+use crate::rules::{Rule, RuleAction};
+use crate::self_test::{self, LayoutTest};
+use crate::toggle_keys::ToggleKey;
+
+pub use self::convert::convert_toml;
+use self::define::{CharactersRef, Defines, KeyName, Location, ReadableDefines};
+pub use self::search::{ConfigSource, SearchDirs};
+
+/// A single configuration file as written by the user.
+#[derive(Debug, Default, Deserialize)]
+struct ReadableConfig {
+    /// Configuration files merged before this file.
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    base_layer: Option<String>,
+    caps_lock_layer: Option<String>,
+    num_lock_layer: Option<String>,
+    scroll_lock_layer: Option<String>,
+    /// Remember the base layer selected with `set_base` across restarts.
+    persist_base_layer: Option<bool>,
+    /// Default of `wrap_modifiers` for all `characters` mappings.
+    wrap_modifiers: Option<bool>,
+    /// How Unicode input is typed on linux.
+    unicode_input: Option<UnicodeInput>,
+    /// Layers of included files to drop.
+    #[serde(default)]
+    remove_layers: Vec<String>,
+    #[serde(default)]
+    define: ReadableDefines,
+    #[serde(default)]
+    layers: HashMap<String, ReadableLayer>,
+    #[serde(default)]
+    conditional_layers: Vec<ReadableConditionalLayer>,
+    /// Layout to start with, `default` for the layers of this file.
+    default_layout: Option<String>,
+    /// Additional layouts, each with the same settings as a configuration file.
+    #[serde(default)]
+    layouts: HashMap<String, ReadableConfig>,
+    /// Layout selection depending on the foreground application.
+    #[serde(default)]
+    rules: Vec<ReadableRule>,
+    /// Key sequences with expected results for the `test` command.
+    #[serde(default)]
+    tests: Vec<ReadableTest>,
+}
+
+/// Layout selection for applications matching `process`, `window_class` and
+/// `input_language`.
+#[derive(Debug, Clone, Deserialize)]
+struct ReadableRule {
+    process: Option<String>,
+    window_class: Option<String>,
+    input_language: Option<String>,
+    layout: Option<String>,
+    #[serde(default)]
+    passthrough: bool,
+}
+
+/// Key sequence like `shift+a` and the expected characters, actions or layer.
+#[derive(Debug, Clone, Deserialize)]
+struct ReadableTest {
+    name: String,
+    layout: Option<String>,
+    keys: String,
+    types: Option<String>,
+    actions: Option<Vec<String>>,
+    layer: Option<String>,
+}
+
+/// Layer activated when all `layers` are active at the same time.
+#[derive(Debug, Clone, Deserialize)]
+struct ReadableConditionalLayer {
+    layers: Spanned<Vec<String>>,
+    activate: String,
+}
+
+/// Layer as written by the user. Either a list of mappings or a table with
+/// layer settings and a `mappings` list.
+#[derive(Debug, Default)]
+struct ReadableLayer {
+    fallthrough: Option<Fallthrough>,
+    lock_timeout_ms: Option<u32>,
+    /// Default of `send_as` for the `characters` mappings of this layer.
+    send_as: Option<SendAs>,
+    mappings: Vec<Spanned<ReadableMapping>>,
+}
+
+impl<'de> Deserialize<'de> for ReadableLayer {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct LayerTable {
+            fallthrough: Option<Fallthrough>,
+            lock_timeout_ms: Option<u32>,
+            send_as: Option<SendAs>,
+            #[serde(default)]
+            mappings: Vec<Spanned<ReadableMapping>>,
+        }
+
+        struct LayerVisitor;
+
+        impl<'de> Visitor<'de> for LayerVisitor {
+            type Value = ReadableLayer;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a list of mappings or a layer table")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+                Ok(ReadableLayer {
+                    fallthrough: None,
+                    lock_timeout_ms: None,
+                    send_as: None,
+                    mappings: Deserialize::deserialize(SeqAccessDeserializer::new(seq))?,
+                })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                let table = LayerTable::deserialize(MapAccessDeserializer::new(map))?;
+                Ok(ReadableLayer {
+                    fallthrough: table.fallthrough,
+                    lock_timeout_ms: table.lock_timeout_ms,
+                    send_as: table.send_as,
+                    mappings: table.mappings,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(LayerVisitor)
+    }
+}
+
+/// Mapping as written by the user. Either references a mapping group with
+/// `use` or maps a `scan_code` to one of the targets.
+#[derive(Debug, Deserialize)]
+struct ReadableMapping {
+    #[serde(rename = "use")]
+    group: Option<Spanned<String>>,
+    scan_code: Option<Spanned<KeyName>>,
+    characters: Option<Spanned<CharactersRef>>,
+    send_as: Option<SendAs>,
+    wrap_modifiers: Option<bool>,
+    virtual_keys: Option<Vec<u8>>,
+    layer: Option<String>,
+    lock: Option<String>,
+    to: Option<String>,
+    clear_locks: Option<bool>,
+    set_base: Option<String>,
+    virtual_key: Option<u8>,
+    switch_layout: Option<String>,
+    cycle_layouts: Option<Vec<String>>,
+}
+
+/// Configuration with all includes merged and definitions expanded.
+#[derive(Debug, Default)]
+struct Config {
+    base_layer: Option<String>,
+    caps_lock_layer: Option<String>,
+    num_lock_layer: Option<String>,
+    scroll_lock_layer: Option<String>,
+    persist_base_layer: Option<bool>,
+    wrap_modifiers: Option<bool>,
+    unicode_input: Option<UnicodeInput>,
+    defines: Defines,
+    layers: HashMap<String, Layer>,
+    conditional_layers: Vec<ConditionalLayer>,
+    default_layout: Option<String>,
+    layouts: HashMap<String, Config>,
+    rules: Vec<ReadableRule>,
+    tests: Vec<ReadableTest>,
+    /// Loaded configuration files, to reload on changes.
+    files: Vec<PathBuf>,
+}
+
+#[derive(Debug)]
+struct ConditionalLayer {
+    layers: Vec<String>,
+    activate: String,
+    location: Location,
+}
+
+#[derive(Debug, Default)]
+struct Layer {
+    fallthrough: Option<Fallthrough>,
+    lock_timeout_ms: Option<u32>,
+    send_as: Option<SendAs>,
+    mappings: Vec<Mapping>,
+}
+
+#[derive(Debug, Clone)]
+struct Mapping {
+    scan_code: ScanCode,
+    target: MappingTarget,
+    /// Where the mapping was defined, for diagnostics.
+    location: Location,
+}
+
+#[derive(Debug, Clone)]
+enum MappingTarget {
+    Characters {
+        characters: String,
+        send_as: Option<SendAs>,
+        wrap_modifiers: Option<bool>,
+    },
+    VirtualKeys {
+        virtual_keys: Vec<u8>,
+    },
+    SwitchLayout {
+        layout: String,
+    },
+    CycleLayouts {
+        layouts: Vec<String>,
+    },
+    Layer {
+        layer: Option<String>,
+        lock: Option<LockTarget>,
+        virtual_key: Option<u8>,
+    },
+}
+
+/// Change of the locked layers, see [`LayerLock`].
+#[derive(Debug, Clone)]
+enum LockTarget {
+    Toggle(String),
+    Replace(String),
+    Clear,
+    SetBase(String),
+}
+
+impl Mapping {
+    /// Number of consecutive scan codes mapped by this entry.
+    fn len(&self) -> u16 {
+        match &self.target {
+            MappingTarget::Characters { characters, .. } if !characters.is_empty() => {
+                characters.chars().count() as u16
+            }
+            MappingTarget::VirtualKeys { virtual_keys } if !virtual_keys.is_empty() => {
+                virtual_keys.len() as u16
+            }
+            _ => 1,
+        }
+    }
+
+    fn scan_codes(&self) -> impl Iterator<Item = ScanCode> {
+        self.scan_code..self.scan_code + self.len()
+    }
+
+    /// Splits multi-key mappings into one mapping per scan code.
+    fn split(self) -> Vec<Mapping> {
+        let Mapping {
+            scan_code,
+            target,
+            location,
+        } = self;
+        let single = |i: usize, target| Mapping {
+            scan_code: scan_code + i as u16,
+            target,
+            location: location.clone(),
+        };
+        match target {
+            MappingTarget::Characters {
+                characters,
+                send_as,
+                wrap_modifiers,
+            } if !characters.is_empty() => characters
+                .chars()
+                .enumerate()
+                .map(|(i, c)| {
+                    single(
+                        i,
+                        MappingTarget::Characters {
+                            characters: c.to_string(),
+                            send_as,
+                            wrap_modifiers,
+                        },
+                    )
+                })
+                .collect(),
+            MappingTarget::VirtualKeys { virtual_keys } if !virtual_keys.is_empty() => virtual_keys
+                .into_iter()
+                .enumerate()
+                .map(|(i, vk)| {
+                    single(
+                        i,
+                        MappingTarget::VirtualKeys {
+                            virtual_keys: vec![vk],
+                        },
+                    )
+                })
+                .collect(),
+            target => vec![single(0, target)],
+        }
+    }
+}
+
+/// Name of the layout defined by the top level layers of a configuration.
+const DEFAULT_LAYOUT: &str = "default";
+
+impl Layouts {
+    /// Loads all layouts from a configuration file including all its includes.
+    pub fn load_toml(path: &Path) -> Result<Self> {
+        Self::from_config(Config::load(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn parse_toml(config: &str) -> Result<Self> {
+        Self::from_config(Config::parse(None, config, &mut Vec::new())?)
+    }
+
+    fn from_config(mut config: Config) -> Result<Self> {
+        let default_layout = config.default_layout.take();
+        let rules = mem::take(&mut config.rules);
+        let tests = mem::take(&mut config.tests);
+        let defines = config.defines.clone();
+        let files = mem::take(&mut config.files);
+        let persist_base_layer = config.persist_base_layer.unwrap_or(false);
+        let unicode_input = config.unicode_input.take().unwrap_or_default();
+        if let UnicodeInput::Command(args) = &unicode_input
+            && args.is_empty()
+        {
+            bail!("`unicode_input` command is empty");
+        }
+        let mut sections: Vec<_> = mem::take(&mut config.layouts).into_iter().collect();
+        sections.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        // The top level layers are the default layout, required unless there
+        // are `[layouts]` sections.
+        let mut layouts = Vec::new();
+        if config.base_layer.is_some() || sections.is_empty() {
+            if sections.iter().any(|(name, _)| name == DEFAULT_LAYOUT) {
+                bail!("layout `{DEFAULT_LAYOUT}` is already defined by the top level layers");
+            }
+            layouts.push((DEFAULT_LAYOUT.to_string(), config));
+        }
+        layouts.extend(sections);
+
+        let mut switches = LayoutSwitches {
+            names: layouts
+                .iter()
+                .enumerate()
+                .map(|(idx, (name, _))| (name.clone(), idx as LayoutIdx))
+                .collect(),
+            cycles: Vec::new(),
+        };
+
+        let default_layout = match &default_layout {
+            Some(name) => match switches.names.get(name) {
+                Some(layout_idx) => *layout_idx,
+                None => bail!("default layout `{name}` not found"),
+            },
+            None if layouts[0].0 == DEFAULT_LAYOUT => 0,
+            None => bail!("default layout not specified"),
+        };
+
+        let layouts: Vec<_> = layouts
+            .into_iter()
+            .map(|(name, config)| {
+                let layout = if name == DEFAULT_LAYOUT {
+                    Layout::from_config(config, &mut switches)?
+                } else {
+                    Layout::from_config(config, &mut switches)
+                        .with_context(|| format!("layout `{name}`"))?
+                };
+                Ok((name, layout))
+            })
+            .collect::<Result<_>>()?;
+
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let action = match (rule.layout, rule.passthrough) {
+                    (Some(name), false) => match switches.names.get(&name) {
+                        Some(layout_idx) => RuleAction::Layout(*layout_idx),
+                        None => bail!("rule layout `{name}` not found"),
+                    },
+                    (None, true) => RuleAction::Passthrough,
+                    _ => bail!("rule requires either `layout` or `passthrough = true`"),
+                };
+                if let Some(language) = &rule.input_language
+                    && let Some(hex) = language.strip_prefix("0x")
+                    && u16::from_str_radix(hex, 16).is_err()
+                {
+                    bail!("invalid input language identifier `{language}`");
+                }
+                Ok(Rule {
+                    process_name: rule.process,
+                    window_class: rule.window_class,
+                    input_language: rule.input_language,
+                    action,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let tests = tests
+            .into_iter()
+            .map(|test| {
+                let name = test.name.clone();
+                LayoutTest::from_config(test, &layouts, default_layout, &switches, &defines)
+                    .with_context(|| format!("test `{name}`"))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Layouts::new(
+            layouts,
+            default_layout,
+            switches.cycles,
+            rules,
+            GlobalOptions {
+                persist_base_layer,
+                unicode_input,
+            },
+            files,
+            tests,
+        ))
+    }
+}
+
+/// Resolves layout names of `switch_layout` and `cycle_layouts` mappings.
+struct LayoutSwitches {
+    names: HashMap<String, LayoutIdx>,
+    cycles: Vec<Vec<LayoutIdx>>,
+}
+
+impl LayoutSwitches {
+    fn find_layout(&self, name: &String, mapping: &Mapping) -> Result<LayoutIdx> {
+        match self.names.get(name) {
+            Some(layout_idx) => Ok(*layout_idx),
+            None => bail!("{}: layout `{name}` not found", mapping.location),
+        }
+    }
+}
+
+impl LayoutTest {
+    fn from_config(
+        test: ReadableTest,
+        layouts: &[(String, Layout)],
+        default_layout: LayoutIdx,
+        switches: &LayoutSwitches,
+        defines: &Defines,
+    ) -> Result<Self> {
+        let layout_idx = match &test.layout {
+            Some(name) => match switches.names.get(name) {
+                Some(layout_idx) => Some(*layout_idx),
+                None => bail!("layout `{name}` not found"),
+            },
+            None => None,
+        };
+        if let Some(layer) = &test.layer {
+            let layout = &layouts[usize::from(layout_idx.unwrap_or(default_layout))].1;
+            if layout.layer_idx(layer).is_none() {
+                bail!("layer `{layer}` not found");
+            }
+        }
+
+        let actions = match &test.actions {
+            Some(actions) => Some(
+                actions
+                    .iter()
+                    .map(|action| {
+                        self_test::parse_action(action, |name| switches.names.get(name).copied())
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            None => None,
+        };
+        if test.types.is_none() && actions.is_none() && test.layer.is_none() {
+            bail!("test requires `types`, `actions` or `layer`");
+        }
+
+        Ok(LayoutTest {
+            keys: self_test::parse_keys(&test.keys, |name| defines.key(name))?,
+            name: test.name,
+            layout: layout_idx,
+            types: test.types,
+            actions,
+            layer: test.layer,
+        })
+    }
+}
+
+impl Layout {
+    fn from_config(mut config: Config, switches: &mut LayoutSwitches) -> Result<Self> {
+        let Some(base_layer) = config.base_layer else {
+            bail!("base layer not specified");
+        };
+
+        let wrap_modifiers = config.wrap_modifiers.unwrap_or(false);
+        let mut layout = LayoutBuilder::new();
+        let mut name_to_idx = HashMap::new();
+        let mut mappings = Vec::new();
+
+        let mut add_layer = |name: String, layer: Layer| {
+            let layer_idx = layout.add_layer(name.clone());
+            if let Some(fallthrough) = layer.fallthrough {
+                layout.set_fallthrough(layer_idx, fallthrough);
+            }
+            if let Some(timeout_ms) = layer.lock_timeout_ms {
+                layout.set_lock_timeout(layer_idx, timeout_ms);
+            }
+            name_to_idx.insert(name, layer_idx);
+            mappings.push((layer_idx, layer.send_as, layer.mappings));
+        };
+
+        // Base layer must be added first.
+        let Some(layer) = config.layers.remove(&base_layer) else {
+            bail!("base layer not found");
+        };
+        add_layer(base_layer, layer);
+
+        // First pass: add layers and track their indices.
+        for (name, layer) in config.layers {
+            add_layer(name, layer)
+        }
+
+        let find_layer = |name: &String, mapping: &Mapping| match name_to_idx.get(name) {
+            Some(layer_idx) => Ok(*layer_idx),
+            None => Err(anyhow::anyhow!(
+                "{}: layer `{name}` not found",
+                mapping.location
+            )),
+        };
+
+        // Second pass: add mappings.
+        for (layer_idx, layer_send_as, mappings) in mappings {
+            for mapping in mappings {
+                match &mapping.target {
+                    MappingTarget::Characters {
+                        characters,
+                        send_as,
+                        wrap_modifiers: mapping_wrap_modifiers,
+                    } if !characters.is_empty() => {
+                        let output = CharacterOutput {
+                            send_as: send_as.or(layer_send_as).unwrap_or_default(),
+                            wrap_modifiers: mapping_wrap_modifiers.unwrap_or(wrap_modifiers),
+                        };
+                        for (i, c) in characters.chars().enumerate() {
+                            layout.add_key(
+                                mapping.scan_code + i as u16,
+                                layer_idx,
+                                KeyAction::Character(c, output),
+                            );
+                        }
+                    }
+                    MappingTarget::VirtualKeys { virtual_keys } if !virtual_keys.is_empty() => {
+                        for (i, vk) in virtual_keys.iter().enumerate() {
+                            layout.add_key(
+                                mapping.scan_code + i as u16,
+                                layer_idx,
+                                KeyAction::VirtualKey(*vk),
+                            );
+                        }
+                    }
+                    MappingTarget::SwitchLayout { layout: name } => {
+                        let target = switches.find_layout(name, &mapping)?;
+                        layout.add_key(
+                            mapping.scan_code,
+                            layer_idx,
+                            KeyAction::SwitchLayout(LayoutSwitch::To(target)),
+                        );
+                    }
+                    MappingTarget::CycleLayouts { layouts } if !layouts.is_empty() => {
+                        let cycle = layouts
+                            .iter()
+                            .map(|name| switches.find_layout(name, &mapping))
+                            .collect::<Result<_>>()?;
+                        let cycle_idx = switches.cycles.len();
+                        switches.cycles.push(cycle);
+                        layout.add_key(
+                            mapping.scan_code,
+                            layer_idx,
+                            KeyAction::SwitchLayout(LayoutSwitch::Cycle(cycle_idx)),
+                        );
+                    }
+                    MappingTarget::Layer {
+                        layer: target_layer,
+                        lock: lock_layer,
+                        virtual_key,
+                    } => {
+                        if let Some(target_layer) = target_layer {
+                            layout.add_modifier(
+                                mapping.scan_code,
+                                layer_idx,
+                                find_layer(target_layer, &mapping)?,
+                            );
+                        }
+
+                        match lock_layer {
+                            Some(LockTarget::Toggle(lock_layer)) => layout.add_layer_lock(
+                                mapping.scan_code,
+                                layer_idx,
+                                find_layer(lock_layer, &mapping)?,
+                            ),
+                            Some(LockTarget::Replace(lock_layer)) => layout.add_lock_action(
+                                mapping.scan_code,
+                                layer_idx,
+                                LayerLock::Replace(find_layer(lock_layer, &mapping)?),
+                            ),
+                            Some(LockTarget::Clear) => {
+                                layout.add_lock_action(
+                                    mapping.scan_code,
+                                    layer_idx,
+                                    LayerLock::Clear,
+                                );
+                            }
+                            Some(LockTarget::SetBase(base_layer)) => layout.add_lock_action(
+                                mapping.scan_code,
+                                layer_idx,
+                                LayerLock::SetBase(find_layer(base_layer, &mapping)?),
+                            ),
+                            None => {}
+                        }
+
+                        layout.add_key(
+                            mapping.scan_code,
+                            layer_idx,
+                            virtual_key.map_or(KeyAction::Ignore, KeyAction::VirtualKey),
+                        );
+                    }
+                    _ => {
+                        layout.add_key(mapping.scan_code, layer_idx, KeyAction::Ignore);
+                    }
+                }
+            }
+        }
+
+        for conditional_layer in &config.conditional_layers {
+            let find_layer = |name: &String| match name_to_idx.get(name) {
+                Some(layer_idx) => Ok(*layer_idx),
+                None => Err(anyhow::anyhow!(
+                    "{}: conditional layer `{name}` not found",
+                    conditional_layer.location
+                )),
+            };
+            let layers = conditional_layer
+                .layers
+                .iter()
+                .map(find_layer)
+                .collect::<Result<_>>()?;
+            layout.add_conditional_layer(layers, find_layer(&conditional_layer.activate)?);
+        }
+
+        let toggle_layers = [
+            (ToggleKey::Caps, &config.caps_lock_layer),
+            (ToggleKey::Num, &config.num_lock_layer),
+            (ToggleKey::Scroll, &config.scroll_lock_layer),
+        ];
+        for (key, layer) in toggle_layers {
+            if let Some(layer) = layer {
+                let Some(layer_idx) = name_to_idx.get(layer) else {
+                    bail!("{} layer `{layer}` not found", key.name());
+                };
+                layout.set_toggle_layer(key, *layer_idx);
+            }
+        }
+
+        Ok(layout.build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+    use crate::rules::{self, ForegroundContext};
+
+    /// Loads the default layout of a configuration file.
+    fn load_layout(path: &Path) -> Result<Layout> {
+        let layouts = Layouts::load_toml(path)?;
+        Ok(layouts.layout(layouts.default_layout()).clone())
+    }
+
+    /// Parses the default layout of a configuration.
+    fn parse_layout(config: &str) -> Result<Layout> {
+        let layouts = Layouts::parse_toml(config)?;
+        Ok(layouts.layout(layouts.default_layout()).clone())
+    }
+
+    /// Writes `files` to a fresh temporary directory, which the test removes.
+    fn write_files(test_name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("kbremap-{}-{test_name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn include_overrides_mappings() {
+        let dir = write_files(
+            "include_overrides_mappings",
+            &[
+                (
+                    "common.toml",
+                    r#"
+                    base_layer = "base"
+                    [layers]
+                    base = [
+                        { scan_code = 0x10, characters = "abc" },
+                        { scan_code = 0x2A, layer = "shift" },
+                    ]
+                    shift = [{ scan_code = 0x10, characters = "ABC" }]
+                    numpad = [{ scan_code = 0x10, characters = "123" }]
+                    "#,
+                ),
+                (
+                    "personal.toml",
+                    r#"
+                    include = ["common.toml"]
+                    remove_layers = ["numpad"]
+                    [layers]
+                    base = [{ scan_code = 0x11, characters = "x" }]
+                    "#,
+                ),
+            ],
+        );
+
+        let layout = load_layout(&dir.join("personal.toml")).unwrap();
+        assert_eq!(layout.layer_name(0), "base");
+        assert_eq!(
+            layout.action(0, 0x10),
+            Some(KeyAction::Character('a', CharacterOutput::default()))
+        );
+        assert_eq!(
+            layout.action(0, 0x11),
+            Some(KeyAction::Character('x', CharacterOutput::default()))
+        );
+        assert_eq!(
+            layout.action(0, 0x12),
+            Some(KeyAction::Character('c', CharacterOutput::default()))
+        );
+        assert!(layout.layer_modifier(0, 0x2A).is_some());
+        assert!(layout.layer_names().all(|name| name != "numpad"));
+
+        // All loaded files are watched for changes.
+        let layouts = Layouts::load_toml(&dir.join("personal.toml")).unwrap();
+        let files: Vec<_> = layouts
+            .files()
+            .iter()
+            .map(|path| path.file_name().unwrap())
+            .collect();
+        assert_eq!(files, ["personal.toml", "common.toml"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cyclic_include() {
+        let dir = write_files(
+            "cyclic_include",
+            &[
+                ("a.toml", r#"include = ["b.toml"]"#),
+                ("b.toml", r#"include = ["a.toml"]"#),
+            ],
+        );
+
+        let err = load_layout(&dir.join("a.toml")).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(format!("{err:#}").contains("cyclic include"));
+    }
+
+    #[test]
+    fn missing_include() {
+        let dir = write_files(
+            "missing_include",
+            &[("a.toml", r#"include = ["does_not_exist.toml"]"#)],
+        );
+
+        let err = load_layout(&dir.join("a.toml")).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(format!("{err:#}").contains("cannot resolve include does_not_exist.toml"));
+    }
+
+    #[test]
+    fn example_configs() {
+        load_layout(Path::new("config.toml")).unwrap();
+        load_layout(Path::new("neo.toml")).unwrap();
+
+        let layouts = Layouts::load_toml(Path::new("config.toml")).unwrap();
+        let (report, failures) = self_test::run(&layouts);
+        assert_eq!(failures, 0, "{report}");
+    }
+
+    #[test]
+    fn defines() {
+        let layout = parse_layout(
+            r#"
+            base_layer = "base"
+
+            [define.keys]
+            caps = 0x3A
+            q = 0x10
+
+            [define.characters]
+            digits = "1234567890"
+
+            [define.mappings]
+            numbers = [{ scan_code = 0x02, characters = { use = "digits" } }]
+            common = [{ use = "numbers" }, { scan_code = "caps", lock = "shift" }]
+
+            [layers]
+            base = [{ use = "common" }, { scan_code = "q", characters = "q" }]
+            shift = [{ use = "common" }, { scan_code = "q", characters = "Q" }]
+            "#,
+        )
+        .unwrap();
+
+        for layer in 0..2 {
+            assert_eq!(
+                layout.action(layer, 0x02),
+                Some(KeyAction::Character('1', CharacterOutput::default()))
+            );
+            assert_eq!(
+                layout.action(layer, 0x0B),
+                Some(KeyAction::Character('0', CharacterOutput::default()))
+            );
+            assert!(layout.layer_lock(layer, 0x3A).is_some());
+        }
+        assert_eq!(
+            layout.action(0, 0x10),
+            Some(KeyAction::Character('q', CharacterOutput::default()))
+        );
+        assert_eq!(
+            layout.action(1, 0x10),
+            Some(KeyAction::Character('Q', CharacterOutput::default()))
+        );
+    }
+
+    #[test]
+    fn layer_table() {
+        let layout = parse_layout(
+            r#"
+            base_layer = "base"
+            [layers]
+            base = [{ scan_code = 0x3A, layer = "numpad" }]
+            [layers.numpad]
+            fallthrough = "opaque"
+            lock_timeout_ms = 30000
+            mappings = [{ scan_code = 0x10, characters = "789" }]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(layout.fallthrough(0), Fallthrough::Transparent);
+        assert_eq!(layout.fallthrough(1), Fallthrough::Opaque);
+        assert_eq!(layout.lock_timeout(0), None);
+        assert_eq!(layout.lock_timeout(1), Some(30000));
+        assert_eq!(
+            layout.action(1, 0x11),
+            Some(KeyAction::Character('8', CharacterOutput::default()))
+        );
+    }
+
+    #[test]
+    fn conditional_layers() {
+        let layout = parse_layout(
+            r#"
+            base_layer = "base"
+            [layers]
+            base = []
+            a = []
+            b = []
+            c = []
+            [[conditional_layers]]
+            layers = ["a", "b"]
+            activate = "c"
+            "#,
+        )
+        .unwrap();
+
+        let idx = |name| layout.layer_names().position(|n| n == name).unwrap() as u8;
+        let active = [idx("a"), idx("b")];
+        assert_eq!(
+            layout.conditional_layer(|layer| active.contains(&layer)),
+            Some(idx("c"))
+        );
+        assert_eq!(layout.conditional_layer(|layer| layer == idx("a")), None);
+    }
+
+    #[test]
+    fn lock_targets() {
+        let layout = parse_layout(
+            r#"
+            base_layer = "base"
+            [layers]
+            base = [{ scan_code = 0x01, lock = "a" }, { scan_code = 0x02, to = "b" }]
+            a = [{ scan_code = 0x01, clear_locks = true }]
+            b = [{ scan_code = 0x01, set_base = "b" }]
+            "#,
+        )
+        .unwrap();
+
+        let idx = |name| layout.layer_names().position(|n| n == name).unwrap() as u8;
+        assert_eq!(
+            layout.layer_lock(0, 0x01),
+            Some(LayerLock::Toggle(idx("a")))
+        );
+        assert_eq!(
+            layout.layer_lock(0, 0x02),
+            Some(LayerLock::Replace(idx("b")))
+        );
+        assert_eq!(layout.layer_lock(idx("a"), 0x01), Some(LayerLock::Clear));
+        assert_eq!(layout.action(idx("a"), 0x01), Some(KeyAction::Ignore));
+        assert_eq!(
+            layout.layer_lock(idx("b"), 0x01),
+            Some(LayerLock::SetBase(idx("b")))
+        );
+
+        let err = parse_layout(
+            r#"
+            base_layer = "base"
+            [layers]
+            base = [{ scan_code = 0x01, lock = "base", to = "base" }]
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "4:21: only one of `lock`, `to`, `clear_locks` and `set_base` can be used"
+        );
+    }
+
+    #[test]
+    fn undefined_names() {
+        let err = parse_layout(
+            r#"
+            base_layer = "base"
+            [layers]
+            base = [{ scan_code = 0x10, characters = "q" }, { use = "missing" }]
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "4:69: mapping group `missing` is not defined"
+        );
+
+        let err = parse_layout(
+            r#"
+            base_layer = "base"
+            [define.mappings]
+            a = [{ use = "b" }]
+            b = [{ use = "a" }]
+            [layers]
+            base = []
+            "#,
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").starts_with("cyclic mapping group"));
+    }
+
+    #[test]
+    fn undefined_conditional_layer() {
+        let err = parse_layout(
+            r#"
+            base_layer = "base"
+            [layers]
+            base = []
+            [[conditional_layers]]
+            layers = ["base", "missing"]
+            activate = "base"
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "6:22: conditional layer `missing` not found"
+        );
+    }
+
+    #[test]
+    fn wrap_modifiers() {
+        let layouts = Layouts::parse_toml(
+            r#"
+            base_layer = "base"
+            [layers]
+            base = [
+                { scan_code = 0x10, characters = "ab", wrap_modifiers = true },
+                { scan_code = 0x12, characters = "c" },
+            ]
+
+            [layouts.wrapped]
+            base_layer = "base"
+            wrap_modifiers = true
+            [layouts.wrapped.layers]
+            base = [
+                { scan_code = 0x10, characters = "a" },
+                { scan_code = 0x11, characters = "b", wrap_modifiers = false },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        let wrap_modifiers = |layout, scan_code| match layouts.layout(layout).action(0, scan_code) {
+            Some(KeyAction::Character(_, output)) => output.wrap_modifiers,
+            action => panic!("unexpected action {action:?}"),
+        };
+        assert!(wrap_modifiers(0, 0x10));
+        assert!(wrap_modifiers(0, 0x11));
+        assert!(!wrap_modifiers(0, 0x12));
+        assert!(wrap_modifiers(1, 0x10));
+        assert!(!wrap_modifiers(1, 0x11));
+
+        let err = Layouts::parse_toml(
+            r#"
+            base_layer = "base"
+            [layers]
+            base = [{ scan_code = 0x10, virtual_keys = [0x41], wrap_modifiers = true }]
+            "#,
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").ends_with("`wrap_modifiers` requires `characters`"));
+    }
+
+    #[test]
+    fn send_as() {
+        let layouts = Layouts::parse_toml(
+            r#"
+            base_layer = "base"
+            [layers]
+            base = [
+                { scan_code = 0x10, characters = "ab", send_as = "virtual_key" },
+                { scan_code = 0x12, characters = "c" },
+            ]
+            quotes = { send_as = "unicode", mappings = [
+                { scan_code = 0x10, characters = "“" },
+                { scan_code = 0x11, characters = "é", send_as = "alt_code" },
+            ] }
+            "#,
+        )
+        .unwrap();
+
+        let layout = layouts.layout(layouts.default_layout());
+        let quotes = layout.layer_idx("quotes").unwrap();
+        let send_as = |layer, scan_code| match layout.action(layer, scan_code) {
+            Some(KeyAction::Character(_, output)) => output.send_as,
+            action => panic!("unexpected action {action:?}"),
+        };
+        assert_eq!(send_as(0, 0x10), SendAs::VirtualKey);
+        assert_eq!(send_as(0, 0x11), SendAs::VirtualKey);
+        assert_eq!(send_as(0, 0x12), SendAs::Auto);
+        assert_eq!(send_as(quotes, 0x10), SendAs::Unicode);
+        assert_eq!(send_as(quotes, 0x11), SendAs::AltCode);
+
+        let err = Layouts::parse_toml(
+            r#"
+            base_layer = "base"
+            [layers]
+            base = [{ scan_code = 0x10, layer = "base", send_as = "unicode" }]
+            "#,
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").ends_with("`send_as` requires `characters`"));
+    }
+
+    #[test]
+    fn layouts() {
+        let layouts = Layouts::parse_toml(
+            r#"
+            base_layer = "base"
+            [define.mappings]
+            switch = [
+                { scan_code = 0x3B, switch_layout = "default" },
+                { scan_code = 0x3C, cycle_layouts = ["gaming", "neo"] },
+            ]
+            [layers]
+            base = [{ scan_code = 0x10, characters = "'" }, { use = "switch" }]
+
+            [layouts.neo]
+            base_layer = "neo"
+            [layouts.neo.layers]
+            neo = [{ scan_code = 0x10, characters = "x" }, { use = "switch" }]
+
+            [layouts.gaming]
+            base_layer = "passthrough"
+            [layouts.gaming.layers.passthrough]
+            fallthrough = "passthrough"
+            mappings = [{ use = "switch" }]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            layouts.names().collect::<Vec<_>>(),
+            ["default", "gaming", "neo"]
+        );
+        assert_eq!(layouts.default_layout(), 0);
+        assert_eq!(
+            layouts.layout(2).action(0, 0x10),
+            Some(KeyAction::Character('x', CharacterOutput::default()))
+        );
+
+        let switch = |layout, scan_code| match layouts.layout(layout).action(0, scan_code) {
+            Some(KeyAction::SwitchLayout(switch)) => layouts.switch_target(layout, switch),
+            action => panic!("unexpected action {action:?}"),
+        };
+        assert_eq!(switch(0, 0x3C), 1);
+        assert_eq!(switch(1, 0x3C), 2);
+        assert_eq!(switch(2, 0x3C), 1);
+        assert_eq!(switch(2, 0x3B), 0);
+
+        // Only layouts sections.
+        let layouts = Layouts::parse_toml(
+            r#"
+            default_layout = "b"
+            [layouts.a]
+            base_layer = "base"
+            layers = { base = [] }
+            [layouts.b]
+            base_layer = "base"
+            layers = { base = [] }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(layouts.name(layouts.default_layout()), "b");
+
+        let err = Layouts::parse_toml(
+            r#"
+            [layouts.a]
+            base_layer = "base"
+            layers = { base = [{ scan_code = 0x01, switch_layout = "b" }] }
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(format!("{err:#}"), "default layout not specified");
+
+        let err = Layouts::parse_toml(
+            r#"
+            default_layout = "a"
+            [layouts.a]
+            base_layer = "base"
+            layers = { base = [{ scan_code = 0x01, switch_layout = "b" }] }
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(format!("{err:#}"), "layout `a`: 5:32: layout `b` not found");
+
+        let err = Layouts::parse_toml(
+            r#"
+            [layouts.a.layouts.b]
+            base_layer = "base"
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(format!("{err:#}"), "layout `a`: layouts cannot be nested");
+
+        let err = Layouts::parse_toml(
+            r#"
+            [layouts.a]
+            base_layer = "base"
+            persist_base_layer = true
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "layout `a`: `persist_base_layer` is only supported at the top level"
+        );
+    }
+
+    #[test]
+    fn rules() {
+        let layouts = Layouts::parse_toml(
+            r#"
+            base_layer = "base"
+            layers = { base = [] }
+            [[rules]]
+            process = "vmconnect.exe"
+            passthrough = true
+            [[rules]]
+            window_class = "ConsoleWindowClass"
+            layout = "qwerty"
+            [[rules]]
+            input_language = "de"
+            passthrough = true
+            [layouts.qwerty]
+            base_layer = "base"
+            layers = { base = [] }
+            "#,
+        )
+        .unwrap();
+
+        let context = |process_name: &str, window_class: &str| ForegroundContext {
+            process_name: process_name.to_string(),
+            window_class: window_class.to_string(),
+            language_id: 0x0407,
+            language_tag: String::from("de-DE"),
+            layout_id: 0x0407,
+        };
+        let rules = layouts.rules();
+        assert_eq!(
+            rules::evaluate(rules, &context("vmconnect.exe", "")),
+            Some(RuleAction::Passthrough)
+        );
+        assert_eq!(
+            rules::evaluate(rules, &context("cmd.exe", "ConsoleWindowClass")),
+            Some(RuleAction::Layout(1))
+        );
+        assert_eq!(
+            rules::evaluate(rules, &context("notepad.exe", "")),
+            Some(RuleAction::Passthrough)
+        );
+
+        let err = Layouts::parse_toml(
+            r#"
+            base_layer = "base"
+            layers = { base = [] }
+            rules = [{ process = "game.exe" }]
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "rule requires either `layout` or `passthrough = true`"
+        );
+    }
+
+    #[test]
+    fn layout_tests() {
+        let config = |test: &str| {
+            let config = format!(
+                r#"
+                base_layer = "base"
+                layers = {{ base = [] }}
+                tests = [{{ name = "test", {test} }}]
+                [define.keys]
+                caps = 0x3A
+                "#
+            );
+            Layouts::parse_toml(&config).map_err(|err| format!("{err:#}"))
+        };
+
+        assert_eq!(
+            config(r#"keys = "caps+0x1E""#).unwrap_err(),
+            "test `test`: test requires `types`, `actions` or `layer`"
+        );
+
+        let layouts = config(r#"keys = "caps+a", layer = "base""#).unwrap();
+        assert_eq!(
+            layouts.tests()[0].keys,
+            [(0x3A, false), (0x1E, false), (0x1E, true), (0x3A, true)]
+        );
+
+        assert_eq!(
+            config(r#"keys = "a", layer = "shift""#).unwrap_err(),
+            "test `test`: layer `shift` not found"
+        );
+        assert_eq!(
+            config(r#"keys = "a", actions = ["layout:neo"]"#).unwrap_err(),
+            "test `test`: layout `neo` not found"
+        );
+        assert_eq!(
+            config(r#"keys = "caps+hyper", types = """#).unwrap_err(),
+            "test `test`: key `hyper` is not defined"
+        );
+    }
+
+    #[test]
+    fn unicode_input() {
+        let config = |unicode_input: &str| {
+            let config = format!(
+                r#"
+                base_layer = "base"
+                layers = {{ base = [] }}
+                {unicode_input}
+                "#
+            );
+            Layouts::parse_toml(&config).map(|layouts| layouts.options().unicode_input.clone())
+        };
+
+        assert_eq!(config("").unwrap(), UnicodeInput::CtrlShiftU);
+        assert_eq!(
+            config(r#"unicode_input = "ctrl_shift_u""#).unwrap(),
+            UnicodeInput::CtrlShiftU
+        );
+        assert_eq!(
+            config(r#"unicode_input = { compose = { virtual_key = 0x5D, sequences = { "é" = "'e" } } }"#)
+                .unwrap(),
+            UnicodeInput::Compose {
+                virtual_key: 0x5D,
+                sequences: [('é', "'e".to_string())].into(),
+            }
+        );
+        assert_eq!(
+            config(r#"unicode_input = { command = ["wtype", "{}"] }"#).unwrap(),
+            UnicodeInput::Command(vec!["wtype".to_string(), "{}".to_string()])
+        );
+
+        let err = config("unicode_input = { command = [] }").unwrap_err();
+        assert_eq!(format!("{err:#}"), "`unicode_input` command is empty");
+
+        let err = config(
+            r#"
+            [layouts.a]
+            base_layer = "base"
+            unicode_input = "ctrl_shift_u"
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "layout `a`: `unicode_input` is only supported at the top level"
+        );
+    }
+}
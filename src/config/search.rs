@@ -0,0 +1,226 @@
+//! Locating the configuration file and named profiles.
+
+use std::collections::BTreeSet;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, bail};
+
+/// File name of the configuration when none is specified.
+const DEFAULT_CONFIG: &str = "config.toml";
+
+/// Directory with named profiles, inside each search directory.
+const PROFILES_DIR: &str = "profiles";
+
+/// Which configuration to load, as requested on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// `config.toml` from the search directories.
+    Default,
+
+    /// Explicit path, relative paths are resolved against the working
+    /// directory first.
+    Path(PathBuf),
+
+    /// Named profile `<name>.toml` from the `profiles` subdirectory of the
+    /// search directories.
+    Profile(String),
+}
+
+/// Directories searched for configuration files, in order of precedence.
+#[derive(Debug, Clone, Default)]
+pub struct SearchDirs {
+    pub current_dir: PathBuf,
+
+    /// Per-user configuration directory, `%APPDATA%\kbremap` on Windows.
+    pub user_dir: Option<PathBuf>,
+
+    /// Directory of our executable.
+    pub exe_dir: Option<PathBuf>,
+}
+
+impl SearchDirs {
+    /// Search directories of the running process.
+    pub fn from_env() -> Result<Self> {
+        let user_dir = env::var_os("APPDATA").map(user_dir);
+        let exe_dir = env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(Path::to_path_buf));
+        Ok(Self {
+            current_dir: env::current_dir()?,
+            user_dir,
+            exe_dir,
+        })
+    }
+
+    /// Per-user directory followed by the executable directory.
+    fn install_dirs(&self) -> impl Iterator<Item = &Path> {
+        self.user_dir
+            .iter()
+            .chain(&self.exe_dir)
+            .map(PathBuf::as_path)
+    }
+
+    /// Paths to try for `source`, in order of precedence.
+    pub fn candidates(&self, source: &ConfigSource) -> Result<Vec<PathBuf>> {
+        let candidates = match source {
+            ConfigSource::Default => self
+                .install_dirs()
+                .map(|dir| dir.join(DEFAULT_CONFIG))
+                .collect(),
+            ConfigSource::Path(path) if path.is_absolute() => vec![path.clone()],
+            ConfigSource::Path(path) => std::iter::once(self.current_dir.as_path())
+                .chain(self.install_dirs())
+                .map(|dir| dir.join(path))
+                .collect(),
+            ConfigSource::Profile(name) => {
+                if name.is_empty() || name.contains(['/', '\\', '.']) {
+                    bail!("invalid profile name `{name}`");
+                }
+                self.profile_dirs()
+                    .map(|dir| dir.join(name).with_extension("toml"))
+                    .collect()
+            }
+        };
+        Ok(candidates)
+    }
+
+    /// Returns the first existing candidate for `source`.
+    pub fn find(&self, source: &ConfigSource) -> Result<PathBuf> {
+        find_existing(&self.candidates(source)?, Path::exists)
+    }
+
+    fn profile_dirs(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.install_dirs().map(|dir| dir.join(PROFILES_DIR))
+    }
+
+    /// Names of all profiles found in the profile directories.
+    pub fn profiles(&self) -> Vec<String> {
+        let files = self
+            .profile_dirs()
+            .filter_map(|dir| fs::read_dir(dir).ok())
+            .flatten()
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()));
+        profile_names(files)
+    }
+}
+
+fn user_dir(appdata: OsString) -> PathBuf {
+    PathBuf::from(appdata).join("kbremap")
+}
+
+/// Returns the first of `candidates` that exists.
+pub fn find_existing(candidates: &[PathBuf], exists: impl Fn(&Path) -> bool) -> Result<PathBuf> {
+    if let Some(path) = candidates.iter().find(|p| exists(p)) {
+        return Ok(path.clone());
+    }
+
+    let tried: Vec<_> = candidates.iter().map(|p| p.display().to_string()).collect();
+    bail!("file not found, tried: {}", tried.join(", "));
+}
+
+/// Sorted profile names of the `.toml` files in `files`, without duplicates.
+fn profile_names(files: impl IntoIterator<Item = PathBuf>) -> Vec<String> {
+    files
+        .into_iter()
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .filter_map(|path| Some(path.file_stem()?.to_str()?.to_string()))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dirs() -> SearchDirs {
+        SearchDirs {
+            current_dir: PathBuf::from("/work"),
+            user_dir: Some(user_dir("/appdata".into())),
+            exe_dir: Some(PathBuf::from("/bin")),
+        }
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn candidates() {
+        let dirs = dirs();
+
+        assert_eq!(
+            dirs.candidates(&ConfigSource::Default).unwrap(),
+            paths(&["/appdata/kbremap/config.toml", "/bin/config.toml"])
+        );
+        assert_eq!(
+            dirs.candidates(&ConfigSource::Path("neo.toml".into()))
+                .unwrap(),
+            paths(&[
+                "/work/neo.toml",
+                "/appdata/kbremap/neo.toml",
+                "/bin/neo.toml"
+            ])
+        );
+        assert_eq!(
+            dirs.candidates(&ConfigSource::Path("/etc/neo.toml".into()))
+                .unwrap(),
+            paths(&["/etc/neo.toml"])
+        );
+        assert_eq!(
+            dirs.candidates(&ConfigSource::Profile("neo".into()))
+                .unwrap(),
+            paths(&[
+                "/appdata/kbremap/profiles/neo.toml",
+                "/bin/profiles/neo.toml"
+            ])
+        );
+
+        // Without a per-user directory.
+        let dirs = SearchDirs {
+            user_dir: None,
+            ..dirs
+        };
+        assert_eq!(
+            dirs.candidates(&ConfigSource::Default).unwrap(),
+            paths(&["/bin/config.toml"])
+        );
+
+        for name in ["", "../neo", "sub/neo", "neo.toml"] {
+            let err = dirs
+                .candidates(&ConfigSource::Profile(name.into()))
+                .unwrap_err();
+            assert_eq!(err.to_string(), format!("invalid profile name `{name}`"));
+        }
+    }
+
+    #[test]
+    fn find_first_existing() {
+        let candidates = paths(&["/appdata/kbremap/config.toml", "/bin/config.toml"]);
+
+        let path = find_existing(&candidates, |p| p.starts_with("/bin")).unwrap();
+        assert_eq!(path, PathBuf::from("/bin/config.toml"));
+        let path = find_existing(&candidates, |_| true).unwrap();
+        assert_eq!(path, PathBuf::from("/appdata/kbremap/config.toml"));
+
+        let err = find_existing(&candidates, |_| false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "file not found, tried: /appdata/kbremap/config.toml, /bin/config.toml"
+        );
+    }
+
+    #[test]
+    fn list_profiles() {
+        let files = paths(&[
+            "/appdata/kbremap/profiles/neo.toml",
+            "/appdata/kbremap/profiles/notes.txt",
+            "/bin/profiles/dvorak.toml",
+            "/bin/profiles/neo.toml",
+        ]);
+        assert_eq!(profile_names(files), ["dvorak", "neo"]);
+    }
+}
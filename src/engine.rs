@@ -0,0 +1,659 @@
+//! Main loop logic of the keyboard hook, independent of the system through
+//! [`InputBackend`] and [`OutputSink`]. Each key event results in a list of
+//! [`OutputEvent`]s, which the main loop sends to the output at once.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::backend::{
+    InputBackend, InternalEvent, KeyEvent, KeyType, OutputEvent, OutputSink, OutputToggleKeys,
+};
+use crate::host_layout::{self, HostLayout};
+use crate::layout::{CharacterOutput, Layouts, ScanCode, SendAs};
+use crate::log::{debug, error, info};
+use crate::recording::{Entry, Recorder};
+use crate::remapper::{Action, Remapper};
+use crate::rules::ForegroundContext;
+use crate::toggle_keys::{self, ToggleKey};
+
+/// File next to the configuration to remember the base layer selected with a
+/// `set_base` key.
+fn base_layer_state_path(config_file: &Path) -> PathBuf {
+    config_file.with_extension("state")
+}
+
+/// State of the keyboard hook around the platform independent [`Remapper`].
+pub struct Engine {
+    remapper: Remapper,
+    recorder: Option<Recorder>,
+
+    /// Last foreground application, to evaluate the rules again after a
+    /// reload.
+    foreground: Option<ForegroundContext>,
+
+    /// Keyboard layout of the foreground application, if there is a model of
+    /// it. Otherwise the output is asked for every character.
+    host_layout: Option<&'static HostLayout>,
+
+    /// How the characters of pressed keys were sent, to release the same key
+    /// even when the modifier keys changed in between. `None` when there is
+    /// nothing to release, e.g. for alt codes.
+    pressed_characters: Vec<(ScanCode, Option<KeyType>)>,
+
+    /// File to remember the base layer of the default layout.
+    base_layer_state: Option<PathBuf>,
+}
+
+impl Engine {
+    /// Creates the engine. With `persist_base_layer`, the base layer is
+    /// remembered in a file next to `config_file`, if there is one.
+    pub fn new(
+        layouts: Layouts,
+        config_file: Option<&Path>,
+        recorder: Option<Recorder>,
+        output: &impl OutputSink,
+    ) -> Self {
+        let mut engine = Self {
+            remapper: Remapper::new(layouts),
+            recorder,
+            foreground: None,
+            host_layout: None,
+            pressed_characters: Vec::new(),
+            base_layer_state: None,
+        };
+        if let Some(config_file) = config_file {
+            engine.load_base_layer_state(config_file);
+        }
+
+        // Replays start from the same state.
+        let base_layer = engine.remapper.kb().base_layer().to_string();
+        let toggle_keys = (ToggleKey::ALL.into_iter())
+            .filter(|key| output.toggle_key_enabled(*key))
+            .collect();
+        engine.record(Entry::Start {
+            base_layer,
+            toggle_keys,
+        });
+        engine
+    }
+
+    /// Restores the base layer remembered with `persist_base_layer`.
+    fn load_base_layer_state(&mut self, config_file: &Path) {
+        self.base_layer_state = (self.remapper.layouts().options().persist_base_layer)
+            .then(|| base_layer_state_path(config_file));
+
+        if let Some(path) = &self.base_layer_state
+            && let Ok(base_layer) = fs::read_to_string(path)
+            && !self.remapper.set_base_layer(base_layer.trim())
+        {
+            error!(
+                "base layer `{}` from {} not found",
+                base_layer.trim(),
+                path.display()
+            );
+        }
+    }
+
+    /// Changes the base layer of the active layout by name. Returns `false`
+    /// if there is no such layer.
+    pub fn set_base_layer(&mut self, name: &str) -> bool {
+        self.remapper.set_base_layer(name)
+    }
+
+    /// Writes the entry to the recording, if recording is enabled.
+    fn record(&mut self, entry: Entry) {
+        if let Some(recorder) = &mut self.recorder
+            && let Err(e) = recorder.record(&entry)
+        {
+            error!("cannot write recording, recording stopped: {e:#}");
+            self.recorder = None;
+        }
+    }
+
+    /// Replaces the layouts after the configuration was reloaded.
+    #[cfg_attr(
+        not(any(windows, all(target_os = "linux", feature = "evdev"))),
+        allow(dead_code)
+    )]
+    pub fn reload(
+        &mut self,
+        layouts: Layouts,
+        config_file: &Path,
+        output: &impl OutputSink,
+    ) -> Vec<OutputEvent> {
+        self.record(Entry::Reload);
+        self.remapper.reload(layouts);
+        if let Some(context) = &self.foreground {
+            self.remapper.update_foreground(context);
+        }
+        self.base_layer_state = (self.remapper.layouts().options().persist_base_layer)
+            .then(|| base_layer_state_path(config_file));
+
+        let mut events = Vec::new();
+        self.sync_toggle_keys(output, &mut events);
+        events
+    }
+
+    /// Releases all keys and unlocks all layers after the keyboard hook was
+    /// re-enabled.
+    pub fn reset(&mut self) {
+        self.remapper.reset();
+        self.pressed_characters.clear();
+        self.record(Entry::Reset);
+    }
+
+    /// Returns how long to wait for the next key event, at most `timeout_ms`,
+    /// so that layer locks expire in time.
+    #[cfg_attr(
+        not(any(windows, all(target_os = "linux", feature = "evdev"))),
+        allow(dead_code)
+    )]
+    pub fn timeout_ms(&self, input: &impl InputBackend, timeout_ms: u32) -> u32 {
+        match self.remapper.lock_deadline() {
+            Some(deadline) => {
+                // Already expired deadlines wrap to negative values.
+                let remaining = deadline.wrapping_sub(input.time()) as i32;
+                timeout_ms.min(remaining.max(0) as u32)
+            }
+            None => timeout_ms,
+        }
+    }
+
+    /// Unlocks layers when their lock timeout expired at `time`, after waiting
+    /// for a key event timed out.
+    pub fn expire_locks(&mut self, output: &impl OutputSink, time: u32) -> Vec<OutputEvent> {
+        let mut events = Vec::new();
+        if self.remapper.expire_locks(time) {
+            info!("layer lock timed out");
+            self.record(Entry::LockTimeout { time });
+            self.sync_toggle_keys(output, &mut events);
+        }
+        events
+    }
+
+    /// Makes sure the toggle key states stay in sync with our layout.
+    fn sync_toggle_keys(&self, output: &impl OutputSink, events: &mut Vec<OutputEvent>) {
+        let mut toggle_keys = OutputToggleKeys { output, events };
+        let kb = self.remapper.kb();
+        for key in toggle_keys::sync(&mut toggle_keys, |key| kb.toggle_key_enabled(key)) {
+            debug!("{} toggled", key.name());
+        }
+    }
+
+    /// Evaluates the rules when the foreground application changed.
+    fn update_foreground(&mut self, input: &mut impl InputBackend, events: &mut Vec<OutputEvent>) {
+        let Some(context) = input.foreground_changed() else {
+            return;
+        };
+
+        debug!(
+            "foreground {} ({}) with input language {} ({:#06X})",
+            context.process_name, context.window_class, context.language_tag, context.language_id
+        );
+        self.host_layout = HostLayout::builtin(context.layout_id);
+        let layout_changed = self.remapper.update_foreground(&context);
+        self.record(Entry::Foreground(context.clone()));
+        self.foreground = Some(context);
+        if layout_changed {
+            let remapper = &self.remapper;
+            info!(
+                "switched to layout `{}`",
+                remapper.layouts().name(remapper.layout_idx())
+            );
+            let layout_idx = remapper.layout_idx();
+            events.push(OutputEvent::Internal(InternalEvent::LayoutChanged(
+                layout_idx,
+            )));
+        }
+    }
+
+    /// Remembers the base layer when changed by a `set_base` key.
+    fn update_base_layer(&mut self, events: &mut Vec<OutputEvent>) {
+        if !self.remapper.base_layer_changed() {
+            return;
+        }
+        events.push(OutputEvent::Internal(InternalEvent::BaseLayerChanged));
+
+        let base_layer = self.remapper.kb().base_layer();
+        info!("base layer changed to `{base_layer}`");
+
+        // The base layer is remembered for the default layout only.
+        if self.remapper.layout_idx() == self.remapper.layouts().default_layout()
+            && let Some(path) = &self.base_layer_state
+            && let Err(e) = fs::write(path, base_layer)
+        {
+            error!("cannot save base layer to {}: {e}", path.display());
+        }
+    }
+
+    /// Adds the events which type `c` when the key of `key_event` is pressed.
+    /// Returns the key to release when the key is released.
+    fn press_character(
+        &self,
+        c: char,
+        character_output: CharacterOutput,
+        key_event: KeyEvent,
+        output: &impl OutputSink,
+        events: &mut Vec<OutputEvent>,
+    ) -> Option<KeyType> {
+        let modifier = |(virtual_key, up)| OutputEvent::virtual_key(virtual_key, up);
+        let modifiers = output.modifiers();
+
+        let send_as = character_output.send_as;
+        if send_as == SendAs::AltCode
+            && let Some(alt_code) = host_layout::alt_code(c, modifiers)
+        {
+            debug!("{key_event} remapped to `{c}` as alt code");
+            events.extend(alt_code.into_iter().map(modifier));
+            return None;
+        }
+
+        let host_key = match (send_as, self.host_layout) {
+            (SendAs::Unicode | SendAs::AltCode, _) => None,
+            (_, Some(host_layout)) => host_layout.key(c),
+            (_, None) => output.host_key(c),
+        };
+
+        let wrap = match send_as {
+            SendAs::Auto => character_output.wrap_modifiers,
+            SendAs::VirtualKey => true,
+            SendAs::Unicode | SendAs::AltCode => false,
+        };
+        if wrap && let Some(wrapped_key) = host_key.and_then(|key| key.wrap(modifiers)) {
+            debug!("{key_event} remapped to `{c}` as virtual key with modifiers");
+            let key = KeyType::VirtualKey(wrapped_key.virtual_key);
+            events.extend(wrapped_key.before.iter().copied().map(modifier));
+            events.push(OutputEvent::key(key, key_event.scan_code, false));
+            events.extend(wrapped_key.after().map(modifier));
+            return Some(key);
+        }
+
+        let key = match host_key.and_then(|key| key.virtual_key(modifiers)) {
+            Some(virtual_key) => {
+                debug!("{key_event} remapped to `{c}` as virtual key");
+                KeyType::VirtualKey(virtual_key)
+            }
+            None => {
+                debug!("{key_event} remapped to `{c}` as unicode input");
+                KeyType::Unicode(c)
+            }
+        };
+        events.push(OutputEvent::key(key, key_event.scan_code, false));
+        Some(key)
+    }
+
+    /// Remaps a key event from the input. Returns the events to send to the
+    /// output in order.
+    pub fn process(
+        &mut self,
+        key_event: KeyEvent,
+        input: &mut impl InputBackend,
+        output: &impl OutputSink,
+    ) -> Vec<OutputEvent> {
+        let mut events = Vec::new();
+        self.update_foreground(input, &mut events);
+        self.remap(key_event, output, &mut events);
+        self.record(Entry::Key {
+            scan_code: key_event.scan_code,
+            virtual_key: match key_event.key {
+                KeyType::VirtualKey(virtual_key) => virtual_key,
+                KeyType::Unicode(_) => 0,
+            },
+            up: key_event.up,
+            time: key_event.time,
+            output: events.clone(),
+        });
+        events
+    }
+
+    /// Adds the events for the key event to `events`.
+    fn remap(
+        &mut self,
+        key_event: KeyEvent,
+        output: &impl OutputSink,
+        events: &mut Vec<OutputEvent>,
+    ) {
+        let action = self
+            .remapper
+            .process(key_event.scan_code, key_event.up, key_event.time);
+        if action != Action::Passthrough {
+            self.sync_toggle_keys(output, events);
+            self.update_base_layer(events);
+        }
+
+        let remapper = &self.remapper;
+        let key = match action {
+            Action::Passthrough | Action::Forward => key_event.key,
+            Action::Ignore => {
+                debug!("{key_event} {}", remapper.describe(action));
+                return;
+            }
+            Action::SwitchLayout(layout_idx) => {
+                debug!("{key_event} {}", remapper.describe(action));
+                events.push(OutputEvent::Internal(InternalEvent::LayoutChanged(
+                    layout_idx,
+                )));
+                return;
+            }
+            Action::Character(c, _) if key_event.up => {
+                let pressed = (self.pressed_characters.iter())
+                    .position(|(scan_code, _)| *scan_code == key_event.scan_code);
+                match pressed {
+                    Some(idx) => match self.pressed_characters.remove(idx).1 {
+                        Some(key) => key,
+                        None => return,
+                    },
+                    None => KeyType::Unicode(c),
+                }
+            }
+            Action::Character(c, character_output) => {
+                let pressed = self.press_character(c, character_output, key_event, output, events);
+                self.pressed_characters
+                    .retain(|(scan_code, _)| *scan_code != key_event.scan_code);
+                self.pressed_characters.push((key_event.scan_code, pressed));
+                return;
+            }
+            Action::VirtualKey(virtual_key) => KeyType::VirtualKey(virtual_key),
+        };
+
+        debug!("{key_event} {}", remapper.describe(action));
+        events.push(OutputEvent::key(key, key_event.scan_code, key_event.up));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake::{FakeInput, FakeOutput};
+
+    const CONFIG: &str = r#"
+        base_layer = "base"
+        caps_lock_layer = "shift"
+
+        [layers]
+        base = [
+            { scan_code = 0x1E, characters = "aä" },
+            { scan_code = 0x2A, layer = "shift", virtual_key = 0xA0 },
+            { scan_code = 0x3A, lock = "shift" },
+            { scan_code = 0x3B, virtual_keys = [] },
+        ]
+        shift = { lock_timeout_ms = 1000, mappings = [{ scan_code = 0x1E, characters = "A" }] }
+
+        [[rules]]
+        process = "vmconnect.exe"
+        passthrough = true
+        "#;
+
+    /// Engine with fake input and output, sending the events to the output
+    /// like the main loop.
+    struct Test {
+        engine: Engine,
+        input: FakeInput,
+        output: FakeOutput,
+    }
+
+    impl Test {
+        fn new(config: &str, layout_id: u16) -> Self {
+            let layouts = Layouts::parse_toml(config).unwrap();
+            let output = FakeOutput::default();
+            Self {
+                engine: Engine::new(layouts, None, None, &output),
+                input: FakeInput {
+                    foreground: Some(ForegroundContext {
+                        layout_id,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                output,
+            }
+        }
+
+        fn key(&mut self, scan_code: u16, virtual_key: u8, up: bool) -> Vec<OutputEvent> {
+            let key_event = KeyEvent {
+                key: KeyType::VirtualKey(virtual_key),
+                scan_code,
+                up,
+                time: self.input.time,
+            };
+            let events = (self.engine).process(key_event, &mut self.input, &self.output);
+            self.output.send(&events, key_event.time);
+            events
+        }
+    }
+
+    fn press(virtual_key: u8, scan_code: u16) -> OutputEvent {
+        OutputEvent::Press {
+            virtual_key,
+            scan_code,
+        }
+    }
+
+    fn release(virtual_key: u8, scan_code: u16) -> OutputEvent {
+        OutputEvent::Release {
+            virtual_key,
+            scan_code,
+        }
+    }
+
+    fn text(character: char, up: bool) -> OutputEvent {
+        OutputEvent::Text { character, up }
+    }
+
+    /// Synthetic modifier key event.
+    fn modifier(virtual_key: u8, up: bool) -> OutputEvent {
+        OutputEvent::virtual_key(virtual_key, up)
+    }
+
+    #[test]
+    fn characters() {
+        // No model of the system layout.
+        let mut test = Test::new(CONFIG, 0);
+        test.output.virtual_keys = [('a', 0x41)].into();
+
+        // Characters on the system layout are sent as virtual key, others as
+        // Unicode input.
+        assert_eq!(test.key(0x1E, 0x41, false), [press(0x41, 0x1E)]);
+        assert_eq!(test.key(0x1F, 0x53, false), [text('ä', false)]);
+
+        // Ignored keys are not sent, keys without mapping are forwarded.
+        assert_eq!(test.key(0x3B, 0x70, false), []);
+        assert_eq!(test.key(0x30, 0x42, false), [press(0x42, 0x30)]);
+
+        // Passthrough sends the original key.
+        test.input.foreground = Some(ForegroundContext {
+            process_name: String::from("vmconnect.exe"),
+            ..Default::default()
+        });
+        assert_eq!(test.key(0x31, 0x4E, false), [press(0x4E, 0x31)]);
+    }
+
+    #[test]
+    fn host_layout() {
+        let mut test = Test::new(CONFIG, 0x0407);
+
+        // Characters are resolved with the German keyboard layout model
+        // instead of asking the output.
+        assert_eq!(test.key(0x1F, 0x53, false), [press(0xDE, 0x1F)]);
+        assert_eq!(test.key(0x1F, 0x53, true), [release(0xDE, 0x1F)]);
+        // Shift would type `Ä`.
+        test.output.modifiers.left_shift = true;
+        assert_eq!(test.key(0x1F, 0x53, false), [text('ä', false)]);
+    }
+
+    #[test]
+    fn wrap_modifiers() {
+        let config = r#"
+            base_layer = "base"
+            wrap_modifiers = true
+
+            [layers]
+            base = [
+                { scan_code = 0x10, characters = "@A1" },
+                { scan_code = 0x13, characters = "ä", wrap_modifiers = false },
+            ]
+            "#;
+        let mut test = Test::new(config, 0x0407);
+
+        // AltGr and Shift are added around the virtual key press.
+        assert_eq!(
+            test.key(0x10, 0x51, false),
+            [
+                modifier(0xA2, false),
+                modifier(0xA4, false),
+                press(0x51, 0x10),
+                modifier(0xA4, true),
+                modifier(0xA2, true),
+            ]
+        );
+        assert_eq!(test.key(0x10, 0x51, true), [release(0x51, 0x10)]);
+        assert_eq!(
+            test.key(0x11, 0x57, false),
+            [
+                modifier(0xA0, false),
+                press(0x41, 0x11),
+                modifier(0xA0, true)
+            ]
+        );
+
+        // Held shift is cancelled and pressed again afterwards. The key is
+        // released as pressed, even though shift was released in between.
+        test.output.modifiers.left_shift = true;
+        assert_eq!(
+            test.key(0x12, 0x45, false),
+            [
+                modifier(0xA0, true),
+                press(0x31, 0x12),
+                modifier(0xA0, false)
+            ]
+        );
+        test.output.modifiers.left_shift = false;
+        assert_eq!(test.key(0x12, 0x45, true), [release(0x31, 0x12)]);
+
+        // Keys without modifier wrapping fall back to Unicode input.
+        test.output.modifiers.left_shift = true;
+        assert_eq!(test.key(0x13, 0x52, false), [text('ä', false)]);
+    }
+
+    #[test]
+    fn send_as() {
+        let config = r#"
+            base_layer = "base"
+
+            [layers]
+            base = [
+                { scan_code = 0x10, characters = "a", send_as = "unicode" },
+                { scan_code = 0x11, characters = "A", send_as = "virtual_key" },
+                { scan_code = 0x12, characters = "é→", send_as = "alt_code" },
+            ]
+            "#;
+        let mut test = Test::new(config, 0x0409);
+
+        // Unicode input although the character is on the system layout.
+        assert_eq!(test.key(0x10, 0x51, false), [text('a', false)]);
+        assert_eq!(test.key(0x10, 0x51, true), [text('a', true)]);
+
+        // Virtual key with the missing shift key added.
+        assert_eq!(
+            test.key(0x11, 0x57, false),
+            [
+                modifier(0xA0, false),
+                press(0x41, 0x11),
+                modifier(0xA0, true)
+            ]
+        );
+
+        // Alt code on key press, nothing on release.
+        let digits = [0x60, 0x62, 0x63, 0x63]
+            .into_iter()
+            .flat_map(|digit| [modifier(digit, false), modifier(digit, true)]);
+        let alt_code: Vec<_> = [modifier(0xA4, false)]
+            .into_iter()
+            .chain(digits)
+            .chain([modifier(0xA4, true)])
+            .collect();
+        assert_eq!(test.key(0x12, 0x45, false), alt_code);
+        assert_eq!(test.key(0x12, 0x45, true), []);
+
+        // Characters without alt code are sent as Unicode input.
+        assert_eq!(test.key(0x13, 0x52, false), [text('→', false)]);
+    }
+
+    #[test]
+    fn internal_events() {
+        let config = r#"
+            base_layer = "base"
+
+            [layers]
+            base = [
+                { scan_code = 0x3B, switch_layout = "gaming" },
+                { scan_code = 0x3C, set_base = "qwerty" },
+            ]
+            qwerty = []
+
+            [layouts.gaming]
+            base_layer = "game"
+            [layouts.gaming.layers]
+            game = [{ scan_code = 0x3B, switch_layout = "default" }]
+
+            [[rules]]
+            process = "game.exe"
+            layout = "gaming"
+            "#;
+        let mut test = Test::new(config, 0x0409);
+        let layout_changed =
+            |layout_idx| OutputEvent::Internal(InternalEvent::LayoutChanged(layout_idx));
+
+        // Layout switching keys.
+        assert_eq!(test.key(0x3B, 0x70, false), [layout_changed(1)]);
+        assert_eq!(test.key(0x3B, 0x70, true), []);
+        assert_eq!(test.key(0x3B, 0x70, false), [layout_changed(0)]);
+        assert_eq!(test.key(0x3B, 0x70, true), []);
+
+        assert_eq!(
+            test.key(0x3C, 0x71, false),
+            [OutputEvent::Internal(InternalEvent::BaseLayerChanged)]
+        );
+
+        // Rules are evaluated before the key is remapped. Internal events are
+        // not sent to the system.
+        test.input.foreground = Some(ForegroundContext {
+            process_name: String::from("game.exe"),
+            ..Default::default()
+        });
+        assert_eq!(
+            test.key(0x30, 0x42, false),
+            [layout_changed(1), press(0x42, 0x30)]
+        );
+        assert_eq!(test.output.sent, [press(0x42, 0x30)]);
+    }
+
+    #[test]
+    fn caps_lock_resync() {
+        let mut test = Test::new(CONFIG, 0);
+        // Enabled externally, e.g. while an elevated window had focus.
+        test.output.enabled_toggle_keys = vec![ToggleKey::Caps];
+        let caps_lock = |up| modifier(0x14, up);
+
+        assert_eq!(
+            test.key(0x30, 0x42, false),
+            [caps_lock(false), caps_lock(true), press(0x42, 0x30)]
+        );
+        assert!(test.output.enabled_toggle_keys.is_empty());
+
+        // Locking the caps lock layer enables caps lock.
+        assert_eq!(
+            test.key(0x3A, 0x14, false),
+            [caps_lock(false), caps_lock(true)]
+        );
+        assert_eq!(test.output.enabled_toggle_keys, [ToggleKey::Caps]);
+
+        // The lock times out while waiting for the next key event.
+        assert_eq!(test.engine.timeout_ms(&test.input, 5000), 1000);
+        assert_eq!(test.engine.expire_locks(&test.output, 999), []);
+        let events = test.engine.expire_locks(&test.output, 1000);
+        assert_eq!(events, [caps_lock(false), caps_lock(true)]);
+        assert_eq!(test.engine.remapper.kb().active_layer(), "base");
+        assert_eq!(test.engine.timeout_ms(&test.input, 5000), 5000);
+    }
+}
@@ -0,0 +1,526 @@
+//! Model of the keyboard layout of the system, to decide offline which
+//! characters can be typed with a virtual key.
+
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+/// Keyboard layouts shipped with kbremap.
+static BUILTIN: LazyLock<Vec<HostLayout>> = LazyLock::new(|| {
+    [
+        include_str!("us.toml"),
+        include_str!("de.toml"),
+        include_str!("fr.toml"),
+    ]
+    .into_iter()
+    .map(|layout| HostLayout::parse_toml(layout).unwrap())
+    .collect()
+});
+
+/// Data file as written, see `us.toml` for the format.
+#[derive(Debug, Deserialize)]
+struct ReadableHostLayout {
+    layout_ids: Vec<u16>,
+    virtual_keys: HashMap<String, Vec<ReadableCharacter>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ReadableCharacter {
+    Character(String),
+    Dead { dead: String },
+}
+
+/// How to type a character on the system keyboard layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostKey {
+    pub virtual_key: u8,
+
+    /// Shift must be pressed.
+    pub shift: bool,
+
+    /// AltGr (Ctrl+Alt) must be pressed.
+    pub ctrl_alt: bool,
+
+    /// Caps lock inverts the shift state for this key, e.g. for letters.
+    pub caps_lock: bool,
+
+    /// Dead key which combines with the next character instead of typing the
+    /// character immediately.
+    pub dead: bool,
+}
+
+impl HostKey {
+    /// Returns `true` if shift must be held, caps lock inverts the shift state
+    /// for letters.
+    fn needs_shift(self, modifiers: Modifiers) -> bool {
+        self.shift != (self.caps_lock && modifiers.caps_lock)
+    }
+
+    /// Returns the virtual key if it types the character with the modifiers
+    /// as currently held, `None` when the character must be sent as Unicode
+    /// input.
+    pub fn virtual_key(self, modifiers: Modifiers) -> Option<u8> {
+        if self.dead {
+            // Would combine with the next character.
+            return None;
+        }
+
+        if self.needs_shift(modifiers) != modifiers.shift() {
+            return None;
+        }
+
+        // Ctrl alone is fine, the virtual key is part of a shortcut then.
+        let ctrl_alt = modifiers.ctrl && modifiers.alt;
+        if self.ctrl_alt != ctrl_alt {
+            return None;
+        }
+
+        Some(self.virtual_key)
+    }
+
+    /// Returns the virtual key with the modifier key events to type the
+    /// character regardless of the modifiers currently held.
+    ///
+    /// Shift is pressed or released as needed, AltGr is pressed when missing.
+    /// `None` for dead keys and when AltGr is held but not needed, as
+    /// releasing it would also cancel Ctrl+Alt shortcuts.
+    pub fn wrap(self, modifiers: Modifiers) -> Option<WrappedKey> {
+        let ctrl_alt = modifiers.ctrl && modifiers.alt;
+        if self.dead || (ctrl_alt && !self.ctrl_alt) {
+            return None;
+        }
+
+        let mut before = Vec::new();
+        if self.needs_shift(modifiers) {
+            if !modifiers.shift() {
+                before.push((VK_LSHIFT, false));
+            }
+        } else {
+            if modifiers.left_shift {
+                before.push((VK_LSHIFT, true));
+            }
+            if modifiers.right_shift {
+                before.push((VK_RSHIFT, true));
+            }
+        }
+        if self.ctrl_alt && !modifiers.ctrl {
+            before.push((VK_LCONTROL, false));
+        }
+        if self.ctrl_alt && !modifiers.alt {
+            before.push((VK_LMENU, false));
+        }
+
+        Some(WrappedKey {
+            virtual_key: self.virtual_key,
+            before,
+        })
+    }
+}
+
+const VK_LSHIFT: u8 = 0xA0;
+const VK_RSHIFT: u8 = 0xA1;
+const VK_LCONTROL: u8 = 0xA2;
+const VK_LMENU: u8 = 0xA4;
+const VK_NUMPAD0: u8 = 0x60;
+
+/// Characters 0x80 to 0x9F of the Windows-1252 code page, the others are the
+/// same as in Unicode.
+const WINDOWS_1252: [Option<char>; 32] = [
+    Some('€'),
+    None,
+    Some('‚'),
+    Some('ƒ'),
+    Some('„'),
+    Some('…'),
+    Some('†'),
+    Some('‡'),
+    Some('ˆ'),
+    Some('‰'),
+    Some('Š'),
+    Some('‹'),
+    Some('Œ'),
+    None,
+    Some('Ž'),
+    None,
+    None,
+    Some('‘'),
+    Some('’'),
+    Some('“'),
+    Some('”'),
+    Some('•'),
+    Some('–'),
+    Some('—'),
+    Some('˜'),
+    Some('™'),
+    Some('š'),
+    Some('›'),
+    Some('œ'),
+    None,
+    Some('ž'),
+    Some('Ÿ'),
+];
+
+/// Returns the virtual key events to type `c` as Alt code, Alt+0 followed by
+/// the decimal Windows-1252 code on the numeric keypad.
+///
+/// Held shift keys are released during the Alt code. `None` for characters
+/// outside of Windows-1252 and when Ctrl is held.
+pub fn alt_code(c: char, modifiers: Modifiers) -> Option<Vec<(u8, bool)>> {
+    let code = match u32::from(c) {
+        code @ (0x20..=0x7E | 0xA0..=0xFF) => code as u8,
+        _ => 0x80 + WINDOWS_1252.iter().position(|x| *x == Some(c))? as u8,
+    };
+    if modifiers.ctrl {
+        return None;
+    }
+
+    let mut before = Vec::new();
+    if modifiers.left_shift {
+        before.push((VK_LSHIFT, true));
+    }
+    if modifiers.right_shift {
+        before.push((VK_RSHIFT, true));
+    }
+    if !modifiers.alt {
+        before.push((VK_LMENU, false));
+    }
+
+    let mut events = before.clone();
+    for digit in format!("0{code:03}").bytes() {
+        let virtual_key = VK_NUMPAD0 + (digit - b'0');
+        events.extend([(virtual_key, false), (virtual_key, true)]);
+    }
+    events.extend(
+        before
+            .iter()
+            .rev()
+            .map(|(virtual_key, up)| (*virtual_key, !up)),
+    );
+    Some(events)
+}
+
+/// Virtual key press wrapped with synthetic modifier key events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WrappedKey {
+    pub virtual_key: u8,
+
+    /// Modifier keys pressed (`false`) or released (`true`) before the key
+    /// press, in order.
+    pub before: Vec<(u8, bool)>,
+}
+
+impl WrappedKey {
+    /// Modifier key events after the key press, which restore the physical
+    /// modifier state.
+    pub fn after(&self) -> impl Iterator<Item = (u8, bool)> + '_ {
+        self.before
+            .iter()
+            .rev()
+            .map(|(virtual_key, up)| (*virtual_key, !up))
+    }
+}
+
+/// Modifier keys currently held on the system, and the caps lock state.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub caps_lock: bool,
+}
+
+impl Modifiers {
+    pub fn shift(self) -> bool {
+        self.left_shift || self.right_shift
+    }
+}
+
+/// Characters of a keyboard layout of the system.
+#[derive(Debug)]
+pub struct HostLayout {
+    /// Low word of the Windows keyboard layout identifiers (KLID) using this
+    /// layout, e.g. `0x0407` for German.
+    layout_ids: Vec<u16>,
+
+    keys: HashMap<char, HostKey>,
+}
+
+impl HostLayout {
+    pub fn parse_toml(layout: &str) -> Result<Self> {
+        let layout: ReadableHostLayout =
+            toml::from_str(layout).context("invalid host keyboard layout")?;
+
+        let mut virtual_keys = Vec::new();
+        for (virtual_key, characters) in layout.virtual_keys {
+            let hex = virtual_key.strip_prefix("0x").unwrap_or(&virtual_key);
+            let Ok(virtual_key) = u8::from_str_radix(hex, 16) else {
+                bail!("invalid virtual key `{virtual_key}`");
+            };
+            if characters.len() > 3 {
+                bail!("virtual key {virtual_key:#04X}: expected at most 3 characters");
+            }
+
+            let mut levels = [None; 3];
+            for (level, character) in levels.iter_mut().zip(characters) {
+                let (character, dead) = match character {
+                    ReadableCharacter::Character(c) => (c, false),
+                    ReadableCharacter::Dead { dead } => (dead, true),
+                };
+                let mut chars = character.chars();
+                *level = match (chars.next(), chars.next()) {
+                    (None, _) => None,
+                    (Some(c), None) => Some((c, dead)),
+                    _ => bail!(
+                        "virtual key {virtual_key:#04X}: `{character}` is not a single character"
+                    ),
+                };
+            }
+            virtual_keys.push((virtual_key, levels));
+        }
+        virtual_keys.sort_by_key(|(virtual_key, _)| *virtual_key);
+
+        // Characters on multiple keys are typed with the fewest modifiers,
+        // dead keys only if there is no other way.
+        let mut keys: HashMap<char, HostKey> = HashMap::new();
+        for level in 0..3 {
+            for (virtual_key, levels) in &virtual_keys {
+                let Some((c, dead)) = levels[level] else {
+                    continue;
+                };
+                if keys.get(&c).is_some_and(|key| !key.dead || dead) {
+                    continue;
+                }
+
+                // Caps lock does not affect AltGr characters.
+                let shifted = levels[1].map(|(c, _)| c);
+                let caps_lock = match levels[0] {
+                    Some((c, _)) if level < 2 => c.is_lowercase() && c.to_uppercase().eq(shifted),
+                    _ => false,
+                };
+                let key = HostKey {
+                    virtual_key: *virtual_key,
+                    shift: level == 1,
+                    ctrl_alt: level == 2,
+                    caps_lock,
+                    dead,
+                };
+                keys.insert(c, key);
+            }
+        }
+
+        Ok(Self {
+            layout_ids: layout.layout_ids,
+            keys,
+        })
+    }
+
+    /// Layout shipped with kbremap for the Windows keyboard layout identifier.
+    pub fn builtin(layout_id: u16) -> Option<&'static HostLayout> {
+        BUILTIN
+            .iter()
+            .find(|layout| layout.layout_ids.contains(&layout_id))
+    }
+
+    pub fn key(&self, c: char) -> Option<HostKey> {
+        self.keys.get(&c).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONE: Modifiers = Modifiers {
+        left_shift: false,
+        right_shift: false,
+        ctrl: false,
+        alt: false,
+        caps_lock: false,
+    };
+    const SHIFT: Modifiers = Modifiers {
+        left_shift: true,
+        ..NONE
+    };
+    const ALTGR: Modifiers = Modifiers {
+        ctrl: true,
+        alt: true,
+        ..NONE
+    };
+    const CAPS_LOCK: Modifiers = Modifiers {
+        caps_lock: true,
+        ..NONE
+    };
+
+    #[test]
+    fn builtin_layouts() {
+        assert!(HostLayout::builtin(0x0409).is_some());
+        let de = HostLayout::builtin(0x0407).unwrap();
+        let fr = HostLayout::builtin(0x040C).unwrap();
+        assert!(HostLayout::builtin(0x0411).is_none());
+
+        assert_eq!(
+            de.key('@'),
+            Some(HostKey {
+                virtual_key: 0x51,
+                shift: false,
+                ctrl_alt: true,
+                caps_lock: false,
+                dead: false,
+            })
+        );
+        assert!(de.key('^').unwrap().dead);
+        assert!(de.key('ü').unwrap().caps_lock);
+        assert!(!fr.key('é').unwrap().caps_lock);
+
+        // Non-dead alternatives are preferred.
+        assert_eq!(fr.key('^').unwrap().virtual_key, 0x39);
+        assert!(!fr.key('^').unwrap().dead);
+    }
+
+    #[test]
+    fn virtual_keys() {
+        let us = HostLayout::builtin(0x0409).unwrap();
+        let de = HostLayout::builtin(0x0407).unwrap();
+        let fr = HostLayout::builtin(0x040C).unwrap();
+        let virtual_key = |layout: &HostLayout, c, modifiers| layout.key(c)?.virtual_key(modifiers);
+
+        assert_eq!(virtual_key(us, 'a', NONE), Some(0x41));
+        assert_eq!(virtual_key(us, 'A', SHIFT), Some(0x41));
+        assert_eq!(virtual_key(us, 'A', CAPS_LOCK), Some(0x41));
+        // Shift missing, or held but not wanted.
+        assert_eq!(virtual_key(us, 'A', NONE), None);
+        assert_eq!(virtual_key(us, '1', SHIFT), None);
+        // Caps lock does not affect digits.
+        assert_eq!(virtual_key(us, '!', CAPS_LOCK), None);
+        assert_eq!(virtual_key(us, 'ä', NONE), None);
+
+        // Shortcuts with ctrl.
+        let ctrl = Modifiers { ctrl: true, ..NONE };
+        assert_eq!(virtual_key(us, 'c', ctrl), Some(0x43));
+
+        assert_eq!(virtual_key(de, '@', ALTGR), Some(0x51));
+        assert_eq!(virtual_key(de, '@', NONE), None);
+        assert_eq!(virtual_key(de, 'q', ALTGR), None);
+        assert_eq!(virtual_key(de, '`', SHIFT), None);
+
+        assert_eq!(virtual_key(fr, '1', SHIFT), Some(0x31));
+        assert_eq!(virtual_key(fr, '&', NONE), Some(0x31));
+    }
+
+    #[test]
+    fn wrap() {
+        let us = HostLayout::builtin(0x0409).unwrap();
+        let de = HostLayout::builtin(0x0407).unwrap();
+        let wrap = |layout: &HostLayout, c, modifiers| {
+            let key = layout.key(c)?.wrap(modifiers)?;
+            Some((key.virtual_key, key.before.clone(), key.after().collect()))
+        };
+
+        // Add shift, or nothing when already held.
+        assert_eq!(
+            wrap(us, 'A', NONE),
+            Some((0x41, vec![(0xA0, false)], vec![(0xA0, true)]))
+        );
+        assert_eq!(wrap(us, 'A', SHIFT), Some((0x41, vec![], vec![])));
+        assert_eq!(wrap(us, 'A', CAPS_LOCK), Some((0x41, vec![], vec![])));
+
+        // Cancel both shift keys and press them again afterwards.
+        let both_shift = Modifiers {
+            right_shift: true,
+            ..SHIFT
+        };
+        assert_eq!(
+            wrap(us, '1', both_shift),
+            Some((
+                0x31,
+                vec![(0xA0, true), (0xA1, true)],
+                vec![(0xA1, false), (0xA0, false)]
+            ))
+        );
+        // Caps lock with shift types a lowercase letter.
+        let shift_caps_lock = Modifiers {
+            caps_lock: true,
+            ..SHIFT
+        };
+        assert_eq!(wrap(us, 'a', shift_caps_lock), Some((0x41, vec![], vec![])));
+
+        // Add AltGr, only the missing part of it.
+        assert_eq!(
+            wrap(de, '@', SHIFT),
+            Some((
+                0x51,
+                vec![(0xA0, true), (0xA2, false), (0xA4, false)],
+                vec![(0xA4, true), (0xA2, true), (0xA0, false)]
+            ))
+        );
+        let alt = Modifiers { alt: true, ..NONE };
+        assert_eq!(
+            wrap(de, '@', alt),
+            Some((0x51, vec![(0xA2, false)], vec![(0xA2, true)]))
+        );
+
+        // AltGr held but not needed, and dead keys.
+        assert_eq!(wrap(de, 'q', ALTGR), None);
+        assert_eq!(wrap(de, '^', NONE), None);
+    }
+
+    #[test]
+    fn alt_codes() {
+        let digits = |events: Vec<(u8, bool)>| -> String {
+            (events.iter())
+                .filter(|(virtual_key, up)| !up && (0x60..=0x69).contains(virtual_key))
+                .map(|(virtual_key, _)| char::from(b'0' + virtual_key - 0x60))
+                .collect()
+        };
+        assert_eq!(digits(alt_code('é', NONE).unwrap()), "0233");
+        assert_eq!(digits(alt_code('“', NONE).unwrap()), "0147");
+        assert_eq!(digits(alt_code('A', NONE).unwrap()), "0065");
+
+        assert_eq!(
+            alt_code('€', SHIFT).unwrap(),
+            [
+                (0xA0, true),
+                (0xA4, false),
+                (0x60, false),
+                (0x60, true),
+                (0x61, false),
+                (0x61, true),
+                (0x62, false),
+                (0x62, true),
+                (0x68, false),
+                (0x68, true),
+                (0xA4, true),
+                (0xA0, false),
+            ]
+        );
+
+        // Held alt stays pressed.
+        let alt = Modifiers { alt: true, ..NONE };
+        assert_eq!(alt_code('é', alt).unwrap().len(), 8);
+
+        assert_eq!(alt_code('→', NONE), None);
+        assert_eq!(alt_code('\n', NONE), None);
+        assert_eq!(alt_code('é', ALTGR), None);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |virtual_keys| {
+            let layout = format!("layout_ids = []\n[virtual_keys]\n{virtual_keys}");
+            format!("{:#}", HostLayout::parse_toml(&layout).unwrap_err())
+        };
+        assert_eq!(error("0x1FF = [\"a\"]"), "invalid virtual key `0x1FF`");
+        assert_eq!(
+            error("0x41 = [\"ab\"]"),
+            "virtual key 0x41: `ab` is not a single character"
+        );
+        assert_eq!(
+            error("0x41 = [\"a\", \"b\", \"c\", \"d\"]"),
+            "virtual key 0x41: expected at most 3 characters"
+        );
+    }
+}
@@ -1,4 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::rules::Rule;
+use crate::self_test::LayoutTest;
+use crate::toggle_keys::ToggleKey;
 
 /// Action associated with the key. Returned by the user provided hook callback.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -7,15 +14,116 @@ pub enum KeyAction {
     Ignore,
 
     /// Sends a (Unicode) character, if possible as virtual key press.
-    Character(char),
+    Character(char, CharacterOutput),
 
     /// Sends a virtual key press.
     /// Reference: <https://docs.microsoft.com/en-us/windows/win32/inputdev/virtual-key-codes>
     VirtualKey(u8),
+
+    /// Switches to another layout of the [`Layouts`] on key press.
+    SwitchLayout(LayoutSwitch),
+}
+
+/// How a character is sent to the system.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CharacterOutput {
+    pub send_as: SendAs,
+
+    /// Presses or releases Shift and AltGr around the virtual key press when
+    /// the held modifier keys would type a different character, instead of
+    /// falling back to Unicode input.
+    pub wrap_modifiers: bool,
+}
+
+/// Kind of input used to send a character.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SendAs {
+    /// Virtual key if the character can be typed with the modifier keys as
+    /// held, Unicode input otherwise.
+    #[default]
+    Auto,
+
+    /// Always Unicode input, e.g. for characters which are dead keys on the
+    /// keyboard layout of the system.
+    Unicode,
+
+    /// Always a virtual key, with modifier wrapping if needed, so that
+    /// shortcuts work. Unicode input only if the keyboard layout of the system
+    /// has no key for the character.
+    VirtualKey,
+
+    /// Alt code of the Windows-1252 code page (Alt+0nnn on the numeric
+    /// keypad), Unicode input for characters outside of it.
+    AltCode,
+}
+
+/// How Unicode input is typed on linux, which has no Unicode key events like
+/// `SendInput()` on windows.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnicodeInput {
+    /// Ctrl+Shift+U, the hexadecimal code point and space, understood by GTK
+    /// and IBus.
+    #[default]
+    CtrlShiftU,
+
+    /// Compose key followed by the characters of the compose sequence.
+    Compose {
+        virtual_key: u8,
+        sequences: BTreeMap<char, String>,
+    },
+
+    /// Helper program typing the character, `{}` in the arguments is replaced
+    /// with the character.
+    Command(Vec<String>),
+}
+
+/// Layout selected by a layout switching key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutSwitch {
+    /// Switches to the layout.
+    To(LayoutIdx),
+
+    /// Switches to the next layout of a list, see [`Layouts::switch_target()`].
+    Cycle(usize),
+}
+
+/// Behavior of a layer for keys without an action on that layer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fallthrough {
+    /// Use the action of the previously active layer.
+    #[default]
+    Transparent,
+
+    /// Ignore the key.
+    Opaque,
+
+    /// Forward the original key without remapping.
+    Passthrough,
+}
+
+/// Change of the locked layers when a lock key is pressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerLock {
+    /// Locks the layer on top of the already locked layers.
+    /// Pressing the same key from the layer below unlocks it again.
+    Toggle(LayerIdx),
+
+    /// Replaces the top most locked layer.
+    Replace(LayerIdx),
+
+    /// Unlocks all layers and returns to the base layer.
+    Clear,
+
+    /// Makes the layer the new base layer.
+    SetBase(LayerIdx),
 }
 
 pub type ScanCode = u16;
 pub type LayerIdx = u8;
+pub type LayoutIdx = u8;
 
 const INVALID_LAYER_IDX: LayerIdx = LayerIdx::MAX;
 
@@ -27,14 +135,23 @@ pub struct Layout {
     /// Map of keys that change layer when pressed.
     modifiers: HashMap<(LayerIdx, ScanCode), LayerIdx>,
 
-    /// Map of keys that lock a specific layer when pressed.
-    locks: HashMap<(LayerIdx, ScanCode), LayerIdx>,
+    /// Map of keys that change the locked layers when pressed.
+    locks: HashMap<(LayerIdx, ScanCode), LayerLock>,
+
+    /// Layers activated when all of the listed layers are active.
+    conditional_layers: Vec<(Vec<LayerIdx>, LayerIdx)>,
 
     /// Names of the layers.
     layer_names: Vec<String>,
 
-    /// Optional layer tied to the caps lock state.
-    caps_lock_layer: LayerIdx,
+    /// Behavior of each layer for unmapped keys.
+    fallthrough: Vec<Fallthrough>,
+
+    /// Idle time in milliseconds after which a locked layer is unlocked.
+    lock_timeouts: Vec<Option<u32>>,
+
+    /// Optional layers tied to the caps lock, num lock and scroll lock state.
+    toggle_layers: [LayerIdx; ToggleKey::ALL.len()],
 }
 
 pub struct LayoutBuilder(Layout);
@@ -45,8 +162,11 @@ impl LayoutBuilder {
             keymap: HashMap::new(),
             modifiers: HashMap::new(),
             locks: HashMap::new(),
+            conditional_layers: Vec::new(),
             layer_names: Vec::new(),
-            caps_lock_layer: INVALID_LAYER_IDX,
+            fallthrough: Vec::new(),
+            lock_timeouts: Vec::new(),
+            toggle_layers: [INVALID_LAYER_IDX; ToggleKey::ALL.len()],
         })
     }
 
@@ -56,6 +176,8 @@ impl LayoutBuilder {
             panic!("too many layers");
         }
         self.0.layer_names.push(name);
+        self.0.fallthrough.push(Fallthrough::default());
+        self.0.lock_timeouts.push(None);
         layer_idx
     }
 
@@ -68,11 +190,27 @@ impl LayoutBuilder {
     }
 
     pub fn add_layer_lock(&mut self, scan_code: ScanCode, layer: LayerIdx, target_layer: LayerIdx) {
-        self.0.locks.insert((layer, scan_code), target_layer);
+        self.add_lock_action(scan_code, layer, LayerLock::Toggle(target_layer));
     }
 
-    pub fn set_caps_lock_layer(&mut self, layer: LayerIdx) {
-        self.0.caps_lock_layer = layer;
+    pub fn add_lock_action(&mut self, scan_code: ScanCode, layer: LayerIdx, lock: LayerLock) {
+        self.0.locks.insert((layer, scan_code), lock);
+    }
+
+    pub fn add_conditional_layer(&mut self, layers: Vec<LayerIdx>, target_layer: LayerIdx) {
+        self.0.conditional_layers.push((layers, target_layer));
+    }
+
+    pub fn set_fallthrough(&mut self, layer: LayerIdx, fallthrough: Fallthrough) {
+        self.0.fallthrough[usize::from(layer)] = fallthrough;
+    }
+
+    pub fn set_lock_timeout(&mut self, layer: LayerIdx, timeout_ms: u32) {
+        self.0.lock_timeouts[usize::from(layer)] = Some(timeout_ms);
+    }
+
+    pub fn set_toggle_layer(&mut self, key: ToggleKey, layer: LayerIdx) {
+        self.0.toggle_layers[key as usize] = layer;
     }
 
     pub fn build(self) -> Layout {
@@ -85,14 +223,32 @@ impl Layout {
         &self.layer_names[usize::from(layer)]
     }
 
-    pub fn caps_lock_layer(&self) -> Option<LayerIdx> {
-        if self.caps_lock_layer == INVALID_LAYER_IDX {
+    pub fn layer_names(&self) -> impl Iterator<Item = &str> {
+        self.layer_names.iter().map(String::as_str)
+    }
+
+    pub fn layer_idx(&self, name: &str) -> Option<LayerIdx> {
+        let idx = self.layer_names.iter().position(|n| n == name)?;
+        Some(idx as LayerIdx)
+    }
+
+    pub fn toggle_layer(&self, key: ToggleKey) -> Option<LayerIdx> {
+        let layer = self.toggle_layers[key as usize];
+        if layer == INVALID_LAYER_IDX {
             None
         } else {
-            Some(self.caps_lock_layer)
+            Some(layer)
         }
     }
 
+    pub fn fallthrough(&self, layer: LayerIdx) -> Fallthrough {
+        self.fallthrough[usize::from(layer)]
+    }
+
+    pub fn lock_timeout(&self, layer: LayerIdx) -> Option<u32> {
+        self.lock_timeouts[usize::from(layer)]
+    }
+
     pub fn action(&self, layer: LayerIdx, scan_code: ScanCode) -> Option<KeyAction> {
         self.keymap.get(&(layer, scan_code)).copied()
     }
@@ -101,7 +257,144 @@ impl Layout {
         self.modifiers.get(&(layer, scan_code)).copied()
     }
 
-    pub fn layer_lock(&self, layer: LayerIdx, scan_code: ScanCode) -> Option<LayerIdx> {
+    /// Returns the first conditional layer whose conditions are all satisfied.
+    pub fn conditional_layer(&self, is_active: impl Fn(LayerIdx) -> bool) -> Option<LayerIdx> {
+        self.conditional_layers
+            .iter()
+            .find(|(layers, _)| layers.iter().all(|layer| is_active(*layer)))
+            .map(|(_, target_layer)| *target_layer)
+    }
+
+    pub fn layer_lock(&self, layer: LayerIdx, scan_code: ScanCode) -> Option<LayerLock> {
         self.locks.get(&(layer, scan_code)).copied()
     }
+
+    /// Sorted scan codes with an action, modifier or lock on the layer.
+    pub fn scan_codes(&self, layer: LayerIdx) -> Vec<ScanCode> {
+        let mut scan_codes: Vec<_> = (self.keymap.keys())
+            .chain(self.modifiers.keys())
+            .chain(self.locks.keys())
+            .filter(|(l, _)| *l == layer)
+            .map(|(_, scan_code)| *scan_code)
+            .collect();
+        scan_codes.sort_unstable();
+        scan_codes.dedup();
+        scan_codes
+    }
+
+    /// Conditions and target layer of all conditional layers in order.
+    pub fn conditional_layers(&self) -> &[(Vec<LayerIdx>, LayerIdx)] {
+        &self.conditional_layers
+    }
+}
+
+/// Options of the configuration which apply to all layouts.
+#[derive(Debug, Default, Clone)]
+pub struct GlobalOptions {
+    /// Remember the base layer selected with `set_base` across restarts.
+    pub persist_base_layer: bool,
+
+    /// How Unicode characters are typed on linux.
+    #[cfg_attr(not(all(target_os = "linux", feature = "evdev")), allow(dead_code))]
+    pub unicode_input: UnicodeInput,
+}
+
+/// Named layouts of a configuration, one of them active at a time.
+#[derive(Debug, Clone)]
+pub struct Layouts {
+    layouts: Vec<(String, Layout)>,
+
+    /// Layout to start with.
+    default_layout: LayoutIdx,
+
+    /// Lists of layouts to cycle through with [`LayoutSwitch::Cycle`].
+    cycles: Vec<Vec<LayoutIdx>>,
+
+    /// Layout selection depending on the foreground application.
+    rules: Vec<Rule>,
+
+    options: GlobalOptions,
+
+    /// Configuration files the layouts were loaded from.
+    files: Vec<PathBuf>,
+
+    /// Tests declared in the configuration.
+    tests: Vec<LayoutTest>,
+}
+
+impl Layouts {
+    pub fn new(
+        layouts: Vec<(String, Layout)>,
+        default_layout: LayoutIdx,
+        cycles: Vec<Vec<LayoutIdx>>,
+        rules: Vec<Rule>,
+        options: GlobalOptions,
+        files: Vec<PathBuf>,
+        tests: Vec<LayoutTest>,
+    ) -> Self {
+        Self {
+            layouts,
+            default_layout,
+            cycles,
+            rules,
+            options,
+            files,
+            tests,
+        }
+    }
+
+    pub fn default_layout(&self) -> LayoutIdx {
+        self.default_layout
+    }
+
+    pub fn layout(&self, layout: LayoutIdx) -> &Layout {
+        &self.layouts[usize::from(layout)].1
+    }
+
+    pub fn name(&self, layout: LayoutIdx) -> &str {
+        &self.layouts[usize::from(layout)].0
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.layouts.iter().map(|(name, _)| name.as_str())
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn options(&self) -> &GlobalOptions {
+        &self.options
+    }
+
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    pub fn tests(&self) -> &[LayoutTest] {
+        &self.tests
+    }
+
+    /// Layouts of a [`LayoutSwitch::Cycle`] list.
+    pub fn cycle(&self, cycle: usize) -> &[LayoutIdx] {
+        &self.cycles[cycle]
+    }
+
+    /// Returns the layout to switch to from the `current` layout.
+    ///
+    /// Cycling continues with the layout after `current` in the list, or
+    /// starts with the first layout when `current` is not part of the list.
+    pub fn switch_target(&self, current: LayoutIdx, switch: LayoutSwitch) -> LayoutIdx {
+        match switch {
+            LayoutSwitch::To(layout) => layout,
+            LayoutSwitch::Cycle(cycle) => {
+                let cycle = self.cycle(cycle);
+                let next = cycle
+                    .iter()
+                    .position(|l| *l == current)
+                    .map_or(0, |i| i + 1);
+                cycle[next % cycle.len()]
+            }
+        }
+    }
 }
@@ -0,0 +1,187 @@
+//! Key events read from a grabbed evdev device.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use super::{
+    EV_KEY, EV_SYN, SYN_DROPPED, SYN_REPORT, event_time, input_event, ioctl, ioctl_read,
+    ioctl_write, keycode, monotonic_ms,
+};
+use crate::backend::{InputBackend, KeyEvent, KeyType};
+use crate::rules::ForegroundContext;
+
+const EVIOCGRAB: u32 = ioctl_write(b'E', 0x90, size_of::<i32>());
+const EVIOCSCLOCKID: u32 = ioctl_write(b'E', 0xA0, size_of::<i32>());
+const KEY_BITS: usize = keycode::KEY_MAX as usize / 8 + 1;
+const EVIOCGKEY: u32 = ioctl_read(b'E', 0x18, KEY_BITS);
+
+/// Key events of a keyboard device, grabbed so that no other program receives
+/// them.
+pub struct EvdevInput {
+    device: File,
+    decoder: InputDecoder,
+    key_events: VecDeque<KeyEvent>,
+}
+
+impl EvdevInput {
+    /// Opens and grabs the keyboard device after all its keys were released.
+    pub fn open(path: &Path) -> Result<Self> {
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+            .with_context(|| format!("cannot open {}", path.display()))?;
+
+        // Same clock as `monotonic_ms()` for the event time stamps.
+        let clock: i32 = libc::CLOCK_MONOTONIC;
+        ioctl(&device, EVIOCSCLOCKID, &raw const clock as usize)
+            .with_context(|| format!("{} is not an input device", path.display()))?;
+
+        // The system would never see the release of keys held while grabbing,
+        // e.g. of the enter key which started kbremap.
+        loop {
+            let mut keys = [0u8; KEY_BITS];
+            ioctl(&device, EVIOCGKEY, keys.as_mut_ptr() as usize)
+                .context("cannot read the key state")?;
+            if keys.iter().all(|&bits| bits == 0) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        ioctl(&device, EVIOCGRAB, 1)
+            .with_context(|| format!("cannot grab {}, used by another program?", path.display()))?;
+
+        Ok(Self::new(device))
+    }
+
+    pub(super) fn new(device: File) -> Self {
+        Self {
+            device,
+            decoder: InputDecoder::default(),
+            key_events: VecDeque::new(),
+        }
+    }
+
+    /// The grabbed device, to set its indicator lights.
+    pub fn device(&self) -> &File {
+        &self.device
+    }
+
+    /// Reads all available events without blocking, fails e.g. when the
+    /// keyboard was unplugged.
+    fn read_events(&mut self) -> Result<()> {
+        let mut events = [input_event(0, 0, 0); 64];
+        loop {
+            let buffer = unsafe {
+                std::slice::from_raw_parts_mut(
+                    events.as_mut_ptr().cast::<u8>(),
+                    size_of_val(&events),
+                )
+            };
+            let len = match (&self.device).read(buffer) {
+                Ok(0) => return Ok(()),
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("cannot read key events"),
+            };
+            for event in &events[..len / size_of::<libc::input_event>()] {
+                self.decoder.decode(event, &mut self.key_events);
+            }
+        }
+    }
+}
+
+impl InputBackend for EvdevInput {
+    async fn next_key_event(&mut self, timeout_ms: u32) -> Result<Option<KeyEvent>> {
+        let start = monotonic_ms();
+        loop {
+            self.read_events()?;
+            if let Some(key_event) = self.key_events.pop_front() {
+                return Ok(Some(key_event));
+            }
+
+            let remaining = timeout_ms.saturating_sub(monotonic_ms().wrapping_sub(start));
+            if remaining == 0 {
+                return Ok(None);
+            }
+            let mut fd = libc::pollfd {
+                fd: self.device.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            unsafe { libc::poll(&mut fd, 1, remaining.min(i32::MAX as u32) as i32) };
+        }
+    }
+
+    fn time(&self) -> u32 {
+        monotonic_ms()
+    }
+
+    fn foreground_changed(&mut self) -> Option<ForegroundContext> {
+        // Rules for the foreground application only work on windows.
+        None
+    }
+}
+
+/// Translates evdev events to key events.
+#[derive(Debug, Default)]
+struct InputDecoder {
+    /// Keycodes of the pressed keys.
+    pressed: Vec<u16>,
+
+    /// Events are ignored until the next `SYN_REPORT` after the kernel
+    /// dropped events.
+    dropped: bool,
+}
+
+impl InputDecoder {
+    fn decode(&mut self, event: &libc::input_event, key_events: &mut VecDeque<KeyEvent>) {
+        let time = event_time(event);
+        match (event.type_, event.code) {
+            (EV_SYN, SYN_DROPPED) => {
+                // Releases might be lost, release all keys. Keys still held
+                // are pressed again by their auto repeat.
+                self.dropped = true;
+                while let Some(keycode) = self.pressed.pop() {
+                    key_events.extend(key_event(keycode, true, time));
+                }
+            }
+            (EV_SYN, SYN_REPORT) => self.dropped = false,
+            _ if self.dropped => {}
+            (EV_KEY, keycode) => {
+                // Auto repeat (2) is a press like on windows.
+                let up = event.value == 0;
+                let pressed = self.pressed.iter().position(|k| *k == keycode);
+                match (pressed, up) {
+                    (Some(idx), true) => _ = self.pressed.remove(idx),
+                    (None, false) => self.pressed.push(keycode),
+                    (None, true) => return, // released after dropped events
+                    (Some(_), false) => {}
+                }
+                key_events.extend(key_event(keycode, up, time));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn key_event(keycode: u16, up: bool, time: u32) -> Option<KeyEvent> {
+    let (scan_code, virtual_key) = keycode::from_keycode(keycode)?;
+    Some(KeyEvent {
+        key: KeyType::VirtualKey(virtual_key),
+        scan_code,
+        up,
+        time,
+    })
+}
@@ -0,0 +1,225 @@
+//! Translation between linux keycodes and the scan codes and virtual keys
+//! reported by the windows keyboard hook, so that configurations work on both
+//! systems unchanged.
+
+use crate::layout::ScanCode;
+
+/// Keys without windows scan code are passed through the layout with their
+/// linux keycode in this otherwise unused range of scan codes.
+const UNKNOWN_KEYS: ScanCode = 0xF000;
+
+/// Linux keycode (`KEY_*` from `linux/input-event-codes.h`), scan code as
+/// reported by the windows keyboard hook and virtual key on the US layout.
+///
+/// Keys with the same virtual key are listed in order of preference for
+/// sending the virtual key, e.g. the main enter key before the numpad one.
+#[rustfmt::skip]
+const KEYS: [(u16, ScanCode, u8); 125] = [
+    (1, 0x01, 0x1B),     // KEY_ESC
+    (2, 0x02, 0x31),     // KEY_1
+    (3, 0x03, 0x32),     // KEY_2
+    (4, 0x04, 0x33),     // KEY_3
+    (5, 0x05, 0x34),     // KEY_4
+    (6, 0x06, 0x35),     // KEY_5
+    (7, 0x07, 0x36),     // KEY_6
+    (8, 0x08, 0x37),     // KEY_7
+    (9, 0x09, 0x38),     // KEY_8
+    (10, 0x0A, 0x39),    // KEY_9
+    (11, 0x0B, 0x30),    // KEY_0
+    (12, 0x0C, 0xBD),    // KEY_MINUS
+    (13, 0x0D, 0xBB),    // KEY_EQUAL
+    (14, 0x0E, 0x08),    // KEY_BACKSPACE
+    (15, 0x0F, 0x09),    // KEY_TAB
+    (16, 0x10, 0x51),    // KEY_Q
+    (17, 0x11, 0x57),    // KEY_W
+    (18, 0x12, 0x45),    // KEY_E
+    (19, 0x13, 0x52),    // KEY_R
+    (20, 0x14, 0x54),    // KEY_T
+    (21, 0x15, 0x59),    // KEY_Y
+    (22, 0x16, 0x55),    // KEY_U
+    (23, 0x17, 0x49),    // KEY_I
+    (24, 0x18, 0x4F),    // KEY_O
+    (25, 0x19, 0x50),    // KEY_P
+    (26, 0x1A, 0xDB),    // KEY_LEFTBRACE
+    (27, 0x1B, 0xDD),    // KEY_RIGHTBRACE
+    (28, 0x1C, 0x0D),    // KEY_ENTER
+    (29, 0x1D, 0xA2),    // KEY_LEFTCTRL
+    (30, 0x1E, 0x41),    // KEY_A
+    (31, 0x1F, 0x53),    // KEY_S
+    (32, 0x20, 0x44),    // KEY_D
+    (33, 0x21, 0x46),    // KEY_F
+    (34, 0x22, 0x47),    // KEY_G
+    (35, 0x23, 0x48),    // KEY_H
+    (36, 0x24, 0x4A),    // KEY_J
+    (37, 0x25, 0x4B),    // KEY_K
+    (38, 0x26, 0x4C),    // KEY_L
+    (39, 0x27, 0xBA),    // KEY_SEMICOLON
+    (40, 0x28, 0xDE),    // KEY_APOSTROPHE
+    (41, 0x29, 0xC0),    // KEY_GRAVE
+    (42, 0x2A, 0xA0),    // KEY_LEFTSHIFT
+    (43, 0x2B, 0xDC),    // KEY_BACKSLASH
+    (44, 0x2C, 0x5A),    // KEY_Z
+    (45, 0x2D, 0x58),    // KEY_X
+    (46, 0x2E, 0x43),    // KEY_C
+    (47, 0x2F, 0x56),    // KEY_V
+    (48, 0x30, 0x42),    // KEY_B
+    (49, 0x31, 0x4E),    // KEY_N
+    (50, 0x32, 0x4D),    // KEY_M
+    (51, 0x33, 0xBC),    // KEY_COMMA
+    (52, 0x34, 0xBE),    // KEY_DOT
+    (53, 0x35, 0xBF),    // KEY_SLASH
+    (54, 0xE036, 0xA1),  // KEY_RIGHTSHIFT, extended for the keyboard hook
+    (55, 0x37, 0x6A),    // KEY_KPASTERISK
+    (56, 0x38, 0xA4),    // KEY_LEFTALT
+    (57, 0x39, 0x20),    // KEY_SPACE
+    (58, 0x3A, 0x14),    // KEY_CAPSLOCK
+    (59, 0x3B, 0x70),    // KEY_F1
+    (60, 0x3C, 0x71),    // KEY_F2
+    (61, 0x3D, 0x72),    // KEY_F3
+    (62, 0x3E, 0x73),    // KEY_F4
+    (63, 0x3F, 0x74),    // KEY_F5
+    (64, 0x40, 0x75),    // KEY_F6
+    (65, 0x41, 0x76),    // KEY_F7
+    (66, 0x42, 0x77),    // KEY_F8
+    (67, 0x43, 0x78),    // KEY_F9
+    (68, 0x44, 0x79),    // KEY_F10
+    (69, 0xE045, 0x90),  // KEY_NUMLOCK, extended for the keyboard hook
+    (70, 0x46, 0x91),    // KEY_SCROLLLOCK
+    (71, 0x47, 0x67),    // KEY_KP7
+    (72, 0x48, 0x68),    // KEY_KP8
+    (73, 0x49, 0x69),    // KEY_KP9
+    (74, 0x4A, 0x6D),    // KEY_KPMINUS
+    (75, 0x4B, 0x64),    // KEY_KP4
+    (76, 0x4C, 0x65),    // KEY_KP5
+    (77, 0x4D, 0x66),    // KEY_KP6
+    (78, 0x4E, 0x6B),    // KEY_KPPLUS
+    (79, 0x4F, 0x61),    // KEY_KP1
+    (80, 0x50, 0x62),    // KEY_KP2
+    (81, 0x51, 0x63),    // KEY_KP3
+    (82, 0x52, 0x60),    // KEY_KP0
+    (83, 0x53, 0x6E),    // KEY_KPDOT
+    (86, 0x56, 0xE2),    // KEY_102ND
+    (87, 0x57, 0x7A),    // KEY_F11
+    (88, 0x58, 0x7B),    // KEY_F12
+    (96, 0xE01C, 0x0D),  // KEY_KPENTER
+    (97, 0xE01D, 0xA3),  // KEY_RIGHTCTRL
+    (98, 0xE035, 0x6F),  // KEY_KPSLASH
+    (99, 0xE037, 0x2C),  // KEY_SYSRQ
+    (100, 0xE038, 0xA5), // KEY_RIGHTALT
+    (102, 0xE047, 0x24), // KEY_HOME
+    (103, 0xE048, 0x26), // KEY_UP
+    (104, 0xE049, 0x21), // KEY_PAGEUP
+    (105, 0xE04B, 0x25), // KEY_LEFT
+    (106, 0xE04D, 0x27), // KEY_RIGHT
+    (107, 0xE04F, 0x23), // KEY_END
+    (108, 0xE050, 0x28), // KEY_DOWN
+    (109, 0xE051, 0x22), // KEY_PAGEDOWN
+    (110, 0xE052, 0x2D), // KEY_INSERT
+    (111, 0xE053, 0x2E), // KEY_DELETE
+    (113, 0xE020, 0xAD), // KEY_MUTE
+    (114, 0xE02E, 0xAE), // KEY_VOLUMEDOWN
+    (115, 0xE030, 0xAF), // KEY_VOLUMEUP
+    (117, 0x59, 0x0C),   // KEY_KPEQUAL
+    (119, 0x45, 0x13),   // KEY_PAUSE
+    (125, 0xE05B, 0x5B), // KEY_LEFTMETA
+    (126, 0xE05C, 0x5C), // KEY_RIGHTMETA
+    (127, 0xE05D, 0x5D), // KEY_COMPOSE
+    (163, 0xE019, 0xB0), // KEY_NEXTSONG
+    (164, 0xE022, 0xB3), // KEY_PLAYPAUSE
+    (165, 0xE010, 0xB1), // KEY_PREVIOUSSONG
+    (166, 0xE024, 0xB2), // KEY_STOPCD
+    (183, 0x64, 0x7C),   // KEY_F13
+    (184, 0x65, 0x7D),   // KEY_F14
+    (185, 0x66, 0x7E),   // KEY_F15
+    (186, 0x67, 0x7F),   // KEY_F16
+    (187, 0x68, 0x80),   // KEY_F17
+    (188, 0x69, 0x81),   // KEY_F18
+    (189, 0x6A, 0x82),   // KEY_F19
+    (190, 0x6B, 0x83),   // KEY_F20
+    (191, 0x6C, 0x84),   // KEY_F21
+    (192, 0x6D, 0x85),   // KEY_F22
+    (193, 0x6E, 0x86),   // KEY_F23
+    (194, 0x76, 0x87),   // KEY_F24
+];
+
+/// Largest keycode passed through the layout.
+pub const KEY_MAX: u16 = 0x2FF;
+
+/// Scan code and virtual key of a linux keycode. Keys unknown to windows have
+/// no virtual key.
+pub fn from_keycode(keycode: u16) -> Option<(ScanCode, u8)> {
+    match KEYS.iter().find(|(code, ..)| *code == keycode) {
+        Some(&(_, scan_code, virtual_key)) => Some((scan_code, virtual_key)),
+        None if keycode <= KEY_MAX => Some((UNKNOWN_KEYS | keycode, 0)),
+        None => None,
+    }
+}
+
+/// Linux keycode to send for a virtual key event.
+///
+/// The scan code selects between keys with the same virtual key, e.g. the main
+/// and the numpad enter key. Keys without virtual key are found by scan code.
+pub fn to_keycode(virtual_key: u8, scan_code: ScanCode) -> Option<u16> {
+    // Modifiers without side, as in `virtual_key = 0x10` for shift.
+    let virtual_key = match virtual_key {
+        0x10 => 0xA0,
+        0x11 => 0xA2,
+        0x12 => 0xA4,
+        virtual_key => virtual_key,
+    };
+
+    let by_scan_code = KEYS.iter().find(|(_, sc, _)| *sc == scan_code);
+    if let Some(&(keycode, _, vk)) = by_scan_code
+        && vk == virtual_key
+    {
+        return Some(keycode);
+    }
+
+    if virtual_key == 0 {
+        return match by_scan_code {
+            Some(&(keycode, ..)) => Some(keycode),
+            None if scan_code & 0xF000 == UNKNOWN_KEYS => Some(scan_code & 0x0FFF),
+            None => None,
+        };
+    }
+
+    let by_virtual_key = KEYS.iter().find(|(.., vk)| *vk == virtual_key);
+    by_virtual_key.map(|&(keycode, ..)| keycode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keycodes() {
+        // Same numbering as the set 1 scan codes for most keys.
+        assert_eq!(from_keycode(30), Some((0x1E, 0x41)));
+        assert_eq!(from_keycode(54), Some((0xE036, 0xA1)));
+        assert_eq!(from_keycode(100), Some((0xE038, 0xA5)));
+        assert_eq!(from_keycode(224), Some((0xF0E0, 0)));
+        assert_eq!(from_keycode(0x300), None);
+
+        // Every key translates back to itself.
+        for keycode in 1..=KEY_MAX {
+            let (scan_code, virtual_key) = from_keycode(keycode).unwrap();
+            assert_eq!(to_keycode(virtual_key, scan_code), Some(keycode));
+        }
+    }
+
+    #[test]
+    fn virtual_keys() {
+        // Remapped to another virtual key, e.g. caps lock to escape.
+        assert_eq!(to_keycode(0x1B, 0x3A), Some(1));
+        // Synthetic events without scan code.
+        assert_eq!(to_keycode(0x41, 0), Some(30));
+        assert_eq!(to_keycode(0x0D, 0), Some(28));
+        assert_eq!(to_keycode(0x10, 0), Some(42));
+        assert_eq!(to_keycode(0x12, 0xE038), Some(56));
+        // The numpad enter key keeps its scan code.
+        assert_eq!(to_keycode(0x0D, 0xE01C), Some(96));
+
+        assert_eq!(to_keycode(0xFF, 0), None);
+        assert_eq!(to_keycode(0, 0x7F), None);
+    }
+}
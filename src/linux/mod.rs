@@ -0,0 +1,271 @@
+//! Linux backend: key events are read from a grabbed evdev device and the
+//! remapped key events are written to a virtual keyboard created with uinput.
+//!
+//! Linux keycodes are translated to the scan codes of the windows keyboard
+//! hook, so that the same configurations work on both systems. The keyboard
+//! layout of the system is expected to be US.
+
+mod evdev;
+mod keycode;
+mod uinput;
+mod unicode;
+
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+pub use evdev::EvdevInput;
+pub use uinput::UinputOutput;
+
+// Event types and codes from `linux/input-event-codes.h`.
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_LED: u16 = 0x11;
+const SYN_REPORT: u16 = 0;
+const SYN_DROPPED: u16 = 3;
+const LED_NUML: u16 = 0x00;
+const LED_CAPSL: u16 = 0x01;
+const LED_SCROLLL: u16 = 0x02;
+
+/// Layout of the system, see the module documentation.
+const US_LAYOUT: u16 = 0x0409;
+
+/// Runs the future to completion on the current thread. The backends block
+/// while waiting for key events, so the future never needs to be woken up.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+/// Milliseconds of the monotonic clock, the clock of the evdev event time
+/// stamps.
+fn monotonic_ms() -> u32 {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+    (time.tv_sec as u64 * 1000 + time.tv_nsec as u64 / 1_000_000) as u32
+}
+
+fn event_time(event: &libc::input_event) -> u32 {
+    (event.time.tv_sec as u64 * 1000 + event.time.tv_usec as u64 / 1000) as u32
+}
+
+fn input_event(type_: u16, code: u16, value: i32) -> libc::input_event {
+    libc::input_event {
+        time: libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+        type_,
+        code,
+        value,
+    }
+}
+
+/// Event buffer as read from or written to a device.
+fn as_bytes(events: &[libc::input_event]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(events.as_ptr().cast(), size_of_val(events)) }
+}
+
+/// Calls `ioctl()` with an integer or pointer argument.
+fn ioctl(file: &File, request: u32, arg: usize) -> io::Result<()> {
+    if unsafe { libc::ioctl(file.as_raw_fd(), request as _, arg) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Request number of an `ioctl()` which reads `len` bytes, like `_IOR()`.
+const fn ioctl_read(kind: u8, number: u8, len: usize) -> u32 {
+    2 << 30 | (len as u32) << 16 | (kind as u32) << 8 | number as u32
+}
+
+/// Request number of an `ioctl()` which writes `len` bytes, like `_IOW()`.
+const fn ioctl_write(kind: u8, number: u8, len: usize) -> u32 {
+    1 << 30 | (len as u32) << 16 | (kind as u32) << 8 | number as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    use super::evdev::EvdevInput;
+    use super::uinput::UinputEncoder;
+    use super::*;
+    use crate::backend::{InputBackend, OutputSink};
+    use crate::engine::Engine;
+    use crate::layout::Layouts;
+
+    /// Parses the output of `evtest`, lines like
+    /// `Event: time 1.000000, type 1 (EV_KEY), code 30 (KEY_A), value 1`.
+    fn parse_evtest(log: &str) -> Vec<libc::input_event> {
+        let mut events = Vec::new();
+        for line in log.lines().map(str::trim) {
+            let Some(line) = line.strip_prefix("Event: time ") else {
+                continue;
+            };
+            let (time, event) = line.split_once(", ").unwrap();
+            let (sec, usec) = time.split_once('.').unwrap();
+            let (type_, code, value) = if event.contains("SYN_REPORT") {
+                (EV_SYN, SYN_REPORT, 0)
+            } else if event.contains("SYN_DROPPED") {
+                (EV_SYN, SYN_DROPPED, 0)
+            } else {
+                let field = |name: &str| {
+                    let start = event.find(name).unwrap() + name.len();
+                    event[start..].split([' ', ',']).next().unwrap()
+                };
+                let type_ = field("type ").parse().unwrap();
+                // Scan codes (`MSC_SCAN`) are printed in hex.
+                let radix = if type_ == 0x04 { 16 } else { 10 };
+                let value = i32::from_str_radix(field("value "), radix).unwrap();
+                (type_, field("code ").parse().unwrap(), value)
+            };
+            let mut event = input_event(type_, code, value);
+            event.time.tv_sec = sec.parse().unwrap();
+            event.time.tv_usec = usec.parse().unwrap();
+            events.push(event);
+        }
+        events
+    }
+
+    /// Remaps the recorded events like the main loop, returns keycode and
+    /// value of the key events written to the virtual keyboard.
+    fn remap(config: &str, name: &str, log: &str) -> Vec<(u16, i32)> {
+        let layouts = Layouts::load_toml(Path::new(config)).unwrap();
+        let path = env::temp_dir().join(format!("kbremap-{}-{name}", std::process::id()));
+        fs::write(&path, as_bytes(&parse_evtest(log))).unwrap();
+        let mut input = EvdevInput::new(File::open(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let mut output = UinputEncoder::default();
+        let mut engine = Engine::new(layouts, None, None, &output);
+        let mut written = Vec::new();
+        while let Some(key_event) = block_on(input.next_key_event(0)).unwrap() {
+            let events = engine.process(key_event, &mut input, &output);
+            output.send(&events, key_event.time);
+            let key_events = output
+                .pending
+                .drain(..)
+                .filter(|event| event.type_ == EV_KEY);
+            written.extend(key_events.map(|event| (event.code, event.value)));
+        }
+        written
+    }
+
+    #[test]
+    fn recorded_events() {
+        // Typing `qS` with left shift for the `S`, including auto repeat of
+        // the shift key. `config.toml` remaps to Dvorak.
+        let log = "
+            Event: time 1700000000.100000, type 4 (EV_MSC), code 4 (MSC_SCAN), value 14
+            Event: time 1700000000.100000, type 1 (EV_KEY), code 16 (KEY_Q), value 1
+            Event: time 1700000000.100000, -------------- SYN_REPORT ------------
+            Event: time 1700000000.180000, type 4 (EV_MSC), code 4 (MSC_SCAN), value 14
+            Event: time 1700000000.180000, type 1 (EV_KEY), code 16 (KEY_Q), value 0
+            Event: time 1700000000.180000, -------------- SYN_REPORT ------------
+            Event: time 1700000000.300000, type 4 (EV_MSC), code 4 (MSC_SCAN), value e1
+            Event: time 1700000000.300000, type 1 (EV_KEY), code 42 (KEY_LEFTSHIFT), value 1
+            Event: time 1700000000.300000, -------------- SYN_REPORT ------------
+            Event: time 1700000000.800000, type 1 (EV_KEY), code 42 (KEY_LEFTSHIFT), value 2
+            Event: time 1700000000.800000, -------------- SYN_REPORT ------------
+            Event: time 1700000000.850000, type 4 (EV_MSC), code 4 (MSC_SCAN), value 16
+            Event: time 1700000000.850000, type 1 (EV_KEY), code 31 (KEY_S), value 1
+            Event: time 1700000000.850000, -------------- SYN_REPORT ------------
+            Event: time 1700000000.900000, type 4 (EV_MSC), code 4 (MSC_SCAN), value 16
+            Event: time 1700000000.900000, type 1 (EV_KEY), code 31 (KEY_S), value 0
+            Event: time 1700000000.900000, -------------- SYN_REPORT ------------
+            Event: time 1700000000.950000, type 4 (EV_MSC), code 4 (MSC_SCAN), value e1
+            Event: time 1700000000.950000, type 1 (EV_KEY), code 42 (KEY_LEFTSHIFT), value 0
+            Event: time 1700000000.950000, -------------- SYN_REPORT ------------
+        ";
+        assert_eq!(
+            remap("config.toml", "qwerty", log),
+            [
+                (40, 1), // KEY_APOSTROPHE
+                (40, 0),
+                (42, 1), // KEY_LEFTSHIFT
+                (42, 2),
+                (24, 1), // KEY_O
+                (24, 0),
+                (42, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn dropped_events() {
+        // The release of `KEY_A` was lost, the key is released after the
+        // events were dropped.
+        let log = "
+            Event: time 1.000000, type 1 (EV_KEY), code 57 (KEY_SPACE), value 1
+            Event: time 1.000000, -------------- SYN_REPORT ------------
+            Event: time 1.010000, type 1 (EV_KEY), code 30 (KEY_A), value 1
+            Event: time 1.010000, -------------- SYN_REPORT ------------
+            Event: time 1.020000, ++++++++++++++ SYN_DROPPED ++++++++++++
+            Event: time 1.030000, type 1 (EV_KEY), code 30 (KEY_A), value 0
+            Event: time 1.030000, -------------- SYN_REPORT ------------
+            Event: time 1.040000, type 1 (EV_KEY), code 57 (KEY_SPACE), value 0
+            Event: time 1.040000, -------------- SYN_REPORT ------------
+        ";
+        assert_eq!(
+            remap("config.toml", "dropped", log),
+            [
+                (57, 1), // KEY_SPACE
+                (30, 1), // KEY_A
+                (30, 0),
+                (57, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn neo() {
+        // `KEY_CAPSLOCK` is the mod 3 key of neo, `KEY_S` types `/` there.
+        // Keys unknown to windows pass through unchanged.
+        let log = "
+            Event: time 1.000000, type 1 (EV_KEY), code 58 (KEY_CAPSLOCK), value 1
+            Event: time 1.000000, -------------- SYN_REPORT ------------
+            Event: time 1.100000, type 1 (EV_KEY), code 31 (KEY_S), value 1
+            Event: time 1.100000, -------------- SYN_REPORT ------------
+            Event: time 1.200000, type 1 (EV_KEY), code 31 (KEY_S), value 0
+            Event: time 1.200000, -------------- SYN_REPORT ------------
+            Event: time 1.300000, type 1 (EV_KEY), code 58 (KEY_CAPSLOCK), value 0
+            Event: time 1.300000, -------------- SYN_REPORT ------------
+            Event: time 1.400000, type 1 (EV_KEY), code 224 (KEY_BRIGHTNESSDOWN), value 1
+            Event: time 1.400000, -------------- SYN_REPORT ------------
+            Event: time 1.500000, type 1 (EV_KEY), code 224 (KEY_BRIGHTNESSDOWN), value 0
+            Event: time 1.500000, -------------- SYN_REPORT ------------
+        ";
+        assert_eq!(
+            remap("neo.toml", "neo", log),
+            [
+                (53, 1), // KEY_SLASH
+                (53, 0),
+                (224, 1),
+                (224, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_error() {
+        // Like an unplugged keyboard, the device cannot be read anymore.
+        let path = env::temp_dir().join(format!("kbremap-{}-read-error", std::process::id()));
+        let mut input = EvdevInput::new(File::create(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+        let error = block_on(input.next_key_event(0)).unwrap_err();
+        assert_eq!(error.to_string(), "cannot read key events");
+    }
+}
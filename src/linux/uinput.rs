@@ -0,0 +1,533 @@
+//! Key events written to a virtual keyboard created with uinput.
+
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use super::{
+    EV_KEY, EV_LED, EV_SYN, LED_CAPSL, LED_NUML, LED_SCROLLL, SYN_REPORT, US_LAYOUT, as_bytes,
+    input_event, ioctl, ioctl_read, ioctl_write, keycode, unicode,
+};
+use crate::backend::{KeyType, OutputEvent, OutputSink};
+use crate::host_layout::{HostKey, HostLayout, Modifiers};
+use crate::layout::UnicodeInput;
+use crate::log::{debug, error};
+use crate::toggle_keys::ToggleKey;
+
+const UI_SET_EVBIT: u32 = ioctl_write(b'U', 100, size_of::<i32>());
+const UI_SET_KEYBIT: u32 = ioctl_write(b'U', 101, size_of::<i32>());
+const UI_SET_LEDBIT: u32 = ioctl_write(b'U', 105, size_of::<i32>());
+const UI_DEV_SETUP: u32 = ioctl_write(b'U', 3, size_of::<libc::uinput_setup>());
+const UI_DEV_CREATE: u32 = 0x5501; // _IO('U', 1)
+const EVIOCGLED: u32 = ioctl_read(b'E', 0x19, size_of::<u16>());
+const BUS_VIRTUAL: u16 = 0x06;
+
+/// Keycodes of the modifier keys, released while typing Unicode characters.
+const MODIFIERS: [u16; 8] = [29, 42, 54, 56, 97, 100, 125, 126];
+
+/// Time the helper program may take to type a character before it is killed,
+/// the remapping waits for it.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Keycodes of the toggle keys and their indicator lights.
+const TOGGLE_KEYS: [(u16, u16); 3] = [(58, LED_CAPSL), (69, LED_NUML), (70, LED_SCROLLL)];
+
+/// Mouse and joystick buttons, not declared so that the virtual keyboard is
+/// not mistaken for one.
+const BUTTONS: std::ops::Range<u16> = 0x100..0x160;
+
+/// Virtual keyboard which types the remapped key events.
+pub struct UinputOutput {
+    uinput: File,
+
+    /// Grabbed keyboard, shows the indicator lights of the virtual keyboard.
+    keyboard: File,
+
+    encoder: UinputEncoder,
+}
+
+impl UinputOutput {
+    /// Creates the virtual keyboard, the toggle key states are taken from the
+    /// indicator lights of the grabbed keyboard.
+    pub fn create(input: &super::EvdevInput, unicode_input: &UnicodeInput) -> Result<Self> {
+        let uinput = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/uinput")
+            .context("cannot open /dev/uinput")?;
+
+        let setup = || -> std::io::Result<()> {
+            ioctl(&uinput, UI_SET_EVBIT, EV_KEY.into())?;
+            for keycode in (1..=keycode::KEY_MAX).filter(|k| !BUTTONS.contains(k)) {
+                ioctl(&uinput, UI_SET_KEYBIT, keycode.into())?;
+            }
+            // The system sets the indicator lights of the virtual keyboard.
+            ioctl(&uinput, UI_SET_EVBIT, EV_LED.into())?;
+            for led in [LED_NUML, LED_CAPSL, LED_SCROLLL] {
+                ioctl(&uinput, UI_SET_LEDBIT, led.into())?;
+            }
+
+            let mut setup: libc::uinput_setup = unsafe { std::mem::zeroed() };
+            setup.id.bustype = BUS_VIRTUAL;
+            for (c, name) in setup.name.iter_mut().zip(c"kbremap".to_bytes()) {
+                *c = *name as _;
+            }
+            ioctl(&uinput, UI_DEV_SETUP, &raw const setup as usize)?;
+            ioctl(&uinput, UI_DEV_CREATE, 0)
+        };
+        setup().context("cannot create the virtual keyboard")?;
+
+        let keyboard = input.device().try_clone()?;
+        let mut leds = [0u8; 2];
+        ioctl(&keyboard, EVIOCGLED, leds.as_mut_ptr() as usize)
+            .context("cannot read the indicator lights")?;
+
+        Ok(Self {
+            uinput,
+            keyboard,
+            encoder: UinputEncoder {
+                leds: u16::from_le_bytes(leds),
+                unicode_input: unicode_input.clone(),
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Applies the indicator lights set by the system, they correct the
+    /// toggle key states tracked from the sent keys.
+    fn update_leds(&mut self) {
+        let mut events = [input_event(0, 0, 0); 16];
+        loop {
+            let buffer = unsafe {
+                std::slice::from_raw_parts_mut(
+                    events.as_mut_ptr().cast::<u8>(),
+                    size_of_val(&events),
+                )
+            };
+            let len = match (&self.uinput).read(buffer) {
+                Ok(0) => return,
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("cannot read indicator lights: {e}");
+                    return;
+                }
+            };
+            let events = &events[..len / size_of::<libc::input_event>()];
+            let leds: Vec<_> = events.iter().filter(|e| e.type_ == EV_LED).collect();
+            for led in &leds {
+                self.encoder.set_led(led.code, led.value != 0);
+            }
+
+            // Mirror them on the grabbed keyboard.
+            let mut mirror: Vec<_> = leds.iter().map(|led| **led).collect();
+            mirror.push(input_event(EV_SYN, SYN_REPORT, 0));
+            if let Err(e) = (&self.keyboard).write_all(as_bytes(&mirror)) {
+                error!("cannot set indicator lights: {e}");
+            }
+        }
+    }
+
+    /// Selects how [`OutputEvent::Text`] is typed, e.g. after the
+    /// configuration was reloaded.
+    pub fn set_unicode_input(&mut self, unicode_input: &UnicodeInput) {
+        self.encoder.set_unicode_input(unicode_input);
+    }
+
+    fn write_pending(&mut self) {
+        let events = std::mem::take(&mut self.encoder.pending);
+        // A single write, so that no other input is interleaved.
+        if !events.is_empty()
+            && let Err(e) = (&self.uinput).write_all(as_bytes(&events))
+        {
+            error!("cannot write key events: {e}");
+        }
+    }
+}
+
+impl OutputSink for UinputOutput {
+    fn send(&mut self, events: &[OutputEvent], time: u32) {
+        self.update_leds();
+        for event in events {
+            self.encoder.send(std::slice::from_ref(event), time);
+            // The helper program types after the preceding key events.
+            if let Some((command, held)) = self.encoder.command.take() {
+                self.write_pending();
+                run_command(&command);
+                for keycode in held {
+                    self.encoder.press(keycode);
+                }
+            }
+        }
+        self.write_pending();
+    }
+
+    fn host_key(&self, c: char) -> Option<HostKey> {
+        self.encoder.host_key(c)
+    }
+
+    fn modifiers(&self) -> Modifiers {
+        self.encoder.modifiers()
+    }
+
+    fn toggle_key_enabled(&self, key: ToggleKey) -> bool {
+        self.encoder.toggle_key_enabled(key)
+    }
+}
+
+/// Runs the helper program and waits until it typed the character, at most
+/// [`COMMAND_TIMEOUT`].
+fn run_command(command: &[String]) {
+    let Some((program, args)) = command.split_first() else {
+        return;
+    };
+    let mut child = match Command::new(program).args(args).spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            error!("cannot run `{program}`: {e}");
+            return;
+        }
+    };
+    let start = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => return,
+            Ok(None) if start.elapsed() < COMMAND_TIMEOUT => {
+                thread::sleep(Duration::from_millis(1));
+            }
+            Ok(None) => {
+                error!("`{program}` did not finish in time, killed");
+                _ = child.kill();
+                _ = child.wait();
+                return;
+            }
+            Err(e) => {
+                error!("cannot wait for `{program}`: {e}");
+                return;
+            }
+        }
+    }
+}
+
+/// State of the virtual keyboard, translates output events to evdev events.
+#[derive(Debug, Default)]
+pub(super) struct UinputEncoder {
+    /// Keycodes of the pressed keys.
+    pressed: Vec<u16>,
+
+    /// Bit mask of the indicator lights which are on. Sent toggle keys
+    /// switch them right away, the system sets them only some time later.
+    leds: u16,
+
+    /// Events to write to the virtual keyboard.
+    pub pending: Vec<libc::input_event>,
+
+    unicode_input: UnicodeInput,
+
+    /// Helper program to run after writing the pending events, and the
+    /// modifiers to press again afterwards.
+    pub command: Option<(Vec<String>, Vec<u16>)>,
+}
+
+impl UinputEncoder {
+    /// Selects how [`OutputEvent::Text`] is typed.
+    pub fn set_unicode_input(&mut self, unicode_input: &UnicodeInput) {
+        self.unicode_input = unicode_input.clone();
+    }
+
+    fn set_led(&mut self, led: u16, on: bool) {
+        if led < 16 {
+            self.leds = self.leds & !(1 << led) | u16::from(on) << led;
+        }
+    }
+
+    fn press(&mut self, keycode: u16) {
+        // Pressing a pressed key again is an auto repeat.
+        let value = if self.pressed.contains(&keycode) {
+            2
+        } else {
+            self.pressed.push(keycode);
+            if let Some((_, led)) = TOGGLE_KEYS.iter().find(|(k, _)| *k == keycode) {
+                self.leds ^= 1 << led;
+            }
+            1
+        };
+        self.pending.push(input_event(EV_KEY, keycode, value));
+        self.pending.push(input_event(EV_SYN, SYN_REPORT, 0));
+    }
+
+    fn release(&mut self, keycode: u16) {
+        self.pressed.retain(|k| *k != keycode);
+        self.pending.push(input_event(EV_KEY, keycode, 0));
+        self.pending.push(input_event(EV_SYN, SYN_REPORT, 0));
+    }
+
+    fn type_unicode(&mut self, c: char) {
+        let command = match &self.unicode_input {
+            UnicodeInput::Command(args) => Some(unicode::command(args, c)),
+            _ => None,
+        };
+        let caps_lock = self.toggle_key_enabled(ToggleKey::Caps);
+        let keys = unicode::keys(&self.unicode_input, c, caps_lock);
+        if command.is_none() && keys.is_none() {
+            debug!("cannot type `{c}`, no unicode input sequence");
+            return;
+        }
+
+        // Held modifiers would change the typed sequence.
+        let held: Vec<_> = (MODIFIERS.into_iter())
+            .filter(|k| self.pressed.contains(k))
+            .collect();
+        for keycode in held.iter().rev() {
+            self.release(*keycode);
+        }
+        if let Some(command) = command {
+            self.command = Some((command, held));
+            return;
+        }
+        for (keycode, up) in keys.into_iter().flatten() {
+            if up {
+                self.release(keycode);
+            } else {
+                self.press(keycode);
+            }
+        }
+        for keycode in held {
+            self.press(keycode);
+        }
+    }
+}
+
+impl OutputSink for UinputEncoder {
+    fn send(&mut self, events: &[OutputEvent], time: u32) {
+        for key in events.iter().filter_map(|event| event.key_event(time)) {
+            match key.key {
+                KeyType::VirtualKey(vk) => {
+                    let Some(keycode) = keycode::to_keycode(vk, key.scan_code) else {
+                        debug!("no keycode for {key}");
+                        continue;
+                    };
+                    if key.up {
+                        self.release(keycode);
+                    } else {
+                        self.press(keycode);
+                    }
+                }
+                KeyType::Unicode(c) => {
+                    if !key.up {
+                        self.type_unicode(c);
+                    }
+                }
+            }
+        }
+    }
+
+    fn host_key(&self, c: char) -> Option<HostKey> {
+        HostLayout::builtin(US_LAYOUT)?.key(c)
+    }
+
+    fn modifiers(&self) -> Modifiers {
+        let pressed = |keycodes: &[u16]| keycodes.iter().any(|k| self.pressed.contains(k));
+        Modifiers {
+            left_shift: pressed(&[42]),
+            right_shift: pressed(&[54]),
+            ctrl: pressed(&[29, 97]),
+            alt: pressed(&[56, 100]),
+            caps_lock: self.toggle_key_enabled(ToggleKey::Caps),
+        }
+    }
+
+    fn toggle_key_enabled(&self, key: ToggleKey) -> bool {
+        let led = match key {
+            ToggleKey::Caps => LED_CAPSL,
+            ToggleKey::Num => LED_NUML,
+            ToggleKey::Scroll => LED_SCROLLL,
+        };
+        self.leds & 1 << led != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::KeyEvent;
+    use crate::backend::fake::FakeInput;
+    use crate::engine::Engine;
+    use crate::layout::Layouts;
+
+    fn key_events(encoder: &mut UinputEncoder) -> Vec<(u16, i32)> {
+        let events = encoder.pending.drain(..);
+        let events: Vec<_> = events.collect();
+        for pair in events.chunks(2) {
+            assert_eq!((pair[1].type_, pair[1].code), (EV_SYN, SYN_REPORT));
+        }
+        (events.iter().step_by(2))
+            .map(|event| (event.code, event.value))
+            .collect()
+    }
+
+    #[test]
+    fn encoder() {
+        // No sequence to type `ä`.
+        let mut encoder = UinputEncoder {
+            unicode_input: UnicodeInput::Compose {
+                virtual_key: 0x5D,
+                sequences: Default::default(),
+            },
+            ..Default::default()
+        };
+        encoder.send(
+            &[
+                OutputEvent::virtual_key(0xA0, false),
+                OutputEvent::Press {
+                    virtual_key: 0x41,
+                    scan_code: 0x1E,
+                },
+                OutputEvent::Press {
+                    virtual_key: 0x41,
+                    scan_code: 0x1E,
+                },
+                OutputEvent::Text {
+                    character: 'ä',
+                    up: false,
+                },
+                OutputEvent::virtual_key(0xA0, true),
+            ],
+            0,
+        );
+        assert_eq!(
+            key_events(&mut encoder),
+            [(42, 1), (30, 1), (30, 2), (42, 0)]
+        );
+        assert_eq!(encoder.modifiers(), Modifiers::default());
+
+        // Modifiers are tracked from the sent keys, caps lock from the
+        // indicator light.
+        encoder.send(
+            &[
+                OutputEvent::virtual_key(0xA3, false),
+                OutputEvent::virtual_key(0xA5, false),
+            ],
+            0,
+        );
+        encoder.set_led(LED_CAPSL, true);
+        assert_eq!(
+            encoder.modifiers(),
+            Modifiers {
+                ctrl: true,
+                alt: true,
+                caps_lock: true,
+                ..Default::default()
+            }
+        );
+        assert!(!encoder.toggle_key_enabled(ToggleKey::Num));
+        encoder.set_led(LED_CAPSL, false);
+        assert!(!encoder.toggle_key_enabled(ToggleKey::Caps));
+
+        // Characters are typed with the US layout.
+        assert_eq!(encoder.host_key('z').unwrap().virtual_key, 0x5A);
+    }
+
+    #[test]
+    fn unicode_input() {
+        // Held modifiers are released while typing the character.
+        let mut encoder = UinputEncoder::default();
+        let text = OutputEvent::Text {
+            character: '→',
+            up: false,
+        };
+        encoder.send(&[OutputEvent::virtual_key(0xA1, false), text], 0);
+        assert_eq!(
+            key_events(&mut encoder),
+            [
+                (54, 1),
+                (54, 0),
+                (29, 1), // Ctrl+Shift+U
+                (42, 1),
+                (22, 1),
+                (22, 0),
+                (42, 0),
+                (29, 0),
+                (3, 1), // 2192
+                (3, 0),
+                (2, 1),
+                (2, 0),
+                (10, 1),
+                (10, 0),
+                (3, 1),
+                (3, 0),
+                (57, 1), // space
+                (57, 0),
+                (54, 1),
+            ]
+        );
+        assert!(encoder.modifiers().right_shift);
+
+        // The helper program types the character, without the held modifiers.
+        encoder.set_unicode_input(&UnicodeInput::Command(vec![
+            "wtype".to_string(),
+            "{}".to_string(),
+        ]));
+        encoder.send(&[text], 0);
+        assert_eq!(key_events(&mut encoder), [(54, 0)]);
+        let (command, held) = encoder.command.take().unwrap();
+        assert_eq!(command, ["wtype", "→"]);
+        assert_eq!(held, [54]);
+    }
+
+    #[test]
+    fn command_timeout() {
+        // A hanging helper program does not block the remapping.
+        let start = Instant::now();
+        run_command(&["sleep".to_string(), "10".to_string()]);
+        assert!(start.elapsed() < COMMAND_TIMEOUT * 2);
+    }
+
+    #[test]
+    fn toggle_keys() {
+        let layouts = Layouts::parse_toml(
+            r#"
+            base_layer = "base"
+            caps_lock_layer = "shift"
+
+            [layers]
+            base = [{ scan_code = 0x3A, lock = "shift" }]
+            shift = [{ scan_code = 0x1E, characters = "A" }]
+            "#,
+        )
+        .unwrap();
+        let mut encoder = UinputEncoder::default();
+        let mut engine = Engine::new(layouts, None, None, &encoder);
+        let mut input = FakeInput::default();
+        let mut key = |encoder: &mut UinputEncoder, scan_code, virtual_key, up| {
+            let key_event = KeyEvent {
+                key: KeyType::VirtualKey(virtual_key),
+                scan_code,
+                up,
+                time: 0,
+            };
+            let events = engine.process(key_event, &mut input, encoder);
+            encoder.send(&events, 0);
+            key_events(encoder)
+        };
+
+        // Locking the layer enables caps lock. The next batches see caps lock
+        // enabled before the system sets the indicator light.
+        assert_eq!(key(&mut encoder, 0x3A, 0x14, false), [(58, 1), (58, 0)]);
+        assert!(encoder.toggle_key_enabled(ToggleKey::Caps));
+        assert_eq!(key(&mut encoder, 0x3A, 0x14, true), []);
+        assert_eq!(key(&mut encoder, 0x1E, 0x41, false), [(30, 1)]);
+        assert!(encoder.toggle_key_enabled(ToggleKey::Caps));
+
+        // The indicator light set by the system wins.
+        encoder.set_led(LED_CAPSL, false);
+        assert!(!encoder.toggle_key_enabled(ToggleKey::Caps));
+    }
+}
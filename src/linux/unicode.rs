@@ -0,0 +1,151 @@
+//! Unicode input typed as key sequences for an input method, or by a helper
+//! program.
+
+use super::{US_LAYOUT, keycode};
+use crate::host_layout::{HostLayout, Modifiers};
+use crate::layout::UnicodeInput;
+
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_U: u16 = 22;
+const KEY_SPACE: u16 = 57;
+
+/// Keys pressed (`false`) and released (`true`) in order to type the
+/// character, `None` when the method cannot type it or needs a helper program.
+/// Expects that no modifier keys are held.
+pub fn keys(unicode_input: &UnicodeInput, c: char, caps_lock: bool) -> Option<Vec<(u16, bool)>> {
+    let mut keys = Vec::new();
+    match unicode_input {
+        UnicodeInput::CtrlShiftU => {
+            keys.extend([(KEY_LEFTCTRL, false), (KEY_LEFTSHIFT, false)]);
+            keys.extend([(KEY_U, false), (KEY_U, true)]);
+            keys.extend([(KEY_LEFTSHIFT, true), (KEY_LEFTCTRL, true)]);
+            for digit in format!("{:x}", u32::from(c)).chars() {
+                type_character(&mut keys, digit, caps_lock)?;
+            }
+            keys.extend([(KEY_SPACE, false), (KEY_SPACE, true)]);
+        }
+        UnicodeInput::Compose {
+            virtual_key,
+            sequences,
+        } => {
+            let sequence = sequences.get(&c)?;
+            let compose = keycode::to_keycode(*virtual_key, 0)?;
+            keys.extend([(compose, false), (compose, true)]);
+            for c in sequence.chars() {
+                type_character(&mut keys, c, caps_lock)?;
+            }
+        }
+        UnicodeInput::Command(_) => return None,
+    }
+    Some(keys)
+}
+
+/// Arguments of the helper program with `{}` replaced by the character.
+pub fn command(args: &[String], c: char) -> Vec<String> {
+    args.iter()
+        .map(|arg| arg.replace("{}", c.encode_utf8(&mut [0; 4])))
+        .collect()
+}
+
+/// Adds the keys to type the character on the US layout, with shift if needed.
+fn type_character(keys: &mut Vec<(u16, bool)>, c: char, caps_lock: bool) -> Option<()> {
+    let host_key = HostLayout::builtin(US_LAYOUT)?.key(c)?;
+    let modifiers = Modifiers {
+        caps_lock,
+        ..Default::default()
+    };
+    let wrapped = host_key.wrap(modifiers)?;
+    let key = keycode::to_keycode(wrapped.virtual_key, 0)?;
+    let modifier = |(virtual_key, up)| Some((keycode::to_keycode(virtual_key, 0)?, up));
+
+    let before: Option<Vec<_>> = wrapped.before.iter().copied().map(modifier).collect();
+    let after: Option<Vec<_>> = wrapped.after().map(modifier).collect();
+    keys.extend(before?);
+    keys.extend([(key, false), (key, true)]);
+    keys.extend(after?);
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tap(keycode: u16) -> [(u16, bool); 2] {
+        [(keycode, false), (keycode, true)]
+    }
+
+    #[test]
+    fn ctrl_shift_u() {
+        let ctrl_shift_u = [
+            (KEY_LEFTCTRL, false),
+            (KEY_LEFTSHIFT, false),
+            (KEY_U, false),
+            (KEY_U, true),
+            (KEY_LEFTSHIFT, true),
+            (KEY_LEFTCTRL, true),
+        ];
+        let keys = keys(&UnicodeInput::CtrlShiftU, 'ä', false).unwrap();
+        assert_eq!(
+            keys,
+            [&ctrl_shift_u[..], &tap(18), &tap(5), &tap(57)].concat()
+        );
+
+        // `1f600`, shift keeps the `f` lowercase while caps lock is on.
+        let keys = super::keys(&UnicodeInput::CtrlShiftU, '😀', true).unwrap();
+        let digits = [
+            &tap(2)[..],
+            &[(KEY_LEFTSHIFT, false)],
+            &tap(33),
+            &[(KEY_LEFTSHIFT, true)],
+            &tap(7),
+            &tap(11),
+            &tap(11),
+        ];
+        assert_eq!(
+            keys,
+            [&ctrl_shift_u[..], &digits.concat(), &tap(57)].concat()
+        );
+    }
+
+    #[test]
+    fn compose() {
+        let compose = UnicodeInput::Compose {
+            virtual_key: 0x5D,
+            sequences: [('é', "'e".to_string()), ('→', "->".to_string())].into(),
+        };
+        assert_eq!(
+            keys(&compose, 'é', false).unwrap(),
+            [tap(127), tap(40), tap(18)].concat()
+        );
+        assert_eq!(
+            keys(&compose, '→', false).unwrap(),
+            [
+                &tap(127)[..],
+                &tap(12),
+                &[(KEY_LEFTSHIFT, false)],
+                &tap(52),
+                &[(KEY_LEFTSHIFT, true)],
+            ]
+            .concat()
+        );
+
+        // No sequence for the character, or not typeable on the US layout.
+        assert_eq!(keys(&compose, 'ö', false), None);
+        let compose = UnicodeInput::Compose {
+            virtual_key: 0x5D,
+            sequences: [('ö', "¨o".to_string())].into(),
+        };
+        assert_eq!(keys(&compose, 'ö', false), None);
+    }
+
+    #[test]
+    fn command() {
+        let args = ["wtype".to_string(), "{}".to_string()];
+        assert_eq!(
+            keys(&UnicodeInput::Command(args.to_vec()), 'ä', false),
+            None
+        );
+        assert_eq!(super::command(&args, 'ä'), ["wtype", "ä"]);
+    }
+}
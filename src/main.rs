@@ -1,50 +1,63 @@
 #![cfg_attr(not(test), windows_subsystem = "windows")]
 #![cfg_attr(test, windows_subsystem = "console")]
 
+mod backend;
+mod cli;
 mod config;
+mod engine;
+mod host_layout;
 mod layout;
+#[cfg(all(target_os = "linux", feature = "evdev"))]
+mod linux;
+mod log;
+mod print;
+mod recording;
+#[cfg(any(windows, all(target_os = "linux", feature = "evdev"), test))]
+mod reload;
+mod remapper;
+#[cfg(windows)]
 mod resources;
+mod rules;
+mod self_test;
+mod simulate;
+mod toggle_keys;
 mod virtual_keyboard;
+#[cfg(windows)]
 mod winapi;
 
+#[cfg(any(windows, all(target_os = "linux", feature = "evdev")))]
 use std::cell::Cell;
-use std::path::Path;
-use std::{env, fs, process};
+use std::path::{Path, PathBuf};
+#[cfg(windows)]
+use std::process;
+use std::{env, fs, io};
 
-use anyhow::{Context, Result};
-use windows_sys::Win32::UI::Input::KeyboardAndMouse::VK_CAPITAL;
+use anyhow::{Context, Result, bail};
+#[cfg(windows)]
 use windows_sys::Win32::UI::WindowsAndMessaging::{MF_CHECKED, MF_DISABLED};
 
-use crate::layout::{KeyAction, Layout};
-use crate::virtual_keyboard::VirtualKeyboard;
-use crate::winapi::keyboard::{self, KeyEvent, KeyType};
+#[cfg(any(windows, all(target_os = "linux", feature = "evdev")))]
+use crate::backend::{InputBackend, OutputEvent, OutputSink};
+use crate::cli::{Cli, Command};
+use crate::config::SearchDirs;
+#[cfg(any(windows, all(target_os = "linux", feature = "evdev")))]
+use crate::engine::Engine;
+#[cfg(any(windows, all(target_os = "linux", feature = "evdev")))]
+use crate::layout::GlobalOptions;
+use crate::layout::Layouts;
+#[cfg(any(windows, all(target_os = "linux", feature = "evdev")))]
+use crate::log::{error, info};
+use crate::recording::Recorder;
+#[cfg(any(windows, all(target_os = "linux", feature = "evdev")))]
+use crate::reload::FileWatcher;
+#[cfg(windows)]
+use crate::winapi::keyboard::{self, HookInput, SendInputOutput};
+#[cfg(windows)]
 use crate::winapi::{AutoStartEntry, StaticIcon, TrayIcon, TrayIconEvent};
 
-fn load_config() -> Result<Layout> {
-    let config_file = env::args_os()
-        .nth(1)
-        .unwrap_or_else(|| "config.toml".into());
-
-    let mut config_file = Path::new(&config_file);
-
-    // Could not find the configuration file in current working directory.
-    // Check if a config file with same name exists next to our executable.
-    let mut path_buf;
-    if !config_file.exists() && config_file.is_relative() {
-        path_buf = env::current_exe()?;
-        path_buf.pop();
-        path_buf.push(config_file);
-        config_file = path_buf.as_path();
-    }
-
-    let config_file = config_file.canonicalize().context(format!(
-        "cannot load configuration file {}",
-        config_file.display()
-    ))?;
-
-    Layout::parse_toml(&fs::read_to_string(config_file)?)
-}
-
+// Only the tray icon disables remapping.
+#[cfg(any(windows, all(target_os = "linux", feature = "evdev")))]
+#[cfg_attr(not(windows), allow(dead_code))]
 #[derive(Debug, Clone, Copy)]
 enum EnableState {
     Enabled,
@@ -52,23 +65,31 @@ enum EnableState {
     ReEnabled,
 }
 
+#[cfg(any(windows, all(target_os = "linux", feature = "evdev")))]
 struct App {
+    #[cfg(windows)]
     running_in_terminal: bool,
+    #[cfg(windows)]
     autostart: AutoStartEntry<'static>,
-    tray_icon: TrayIcon,
+    #[cfg(windows)]
+    tray_icon: Option<TrayIcon>,
     enable_state: Cell<EnableState>,
+    reload_requested: Cell<bool>,
 }
 
+#[cfg(windows)]
 impl App {
-    fn new() -> Self {
+    fn new(tray: bool) -> Self {
         keyboard::hook_enable();
         Self {
             // Display debug and panic output when launched from a terminal.
             // Not only checks if we are running from a terminal but also attaches to it.
             running_in_terminal: winapi::console_check(),
             autostart: AutoStartEntry::new(c"kbremap"),
-            tray_icon: TrayIcon::new(StaticIcon::from_rc_numeric(resources::ICON_KEYBOARD)),
+            tray_icon: tray
+                .then(|| TrayIcon::new(StaticIcon::from_rc_numeric(resources::ICON_KEYBOARD))),
             enable_state: Cell::new(EnableState::Enabled),
+            reload_requested: Cell::new(false),
         }
     }
 
@@ -88,17 +109,21 @@ impl App {
         }
     }
 
+    fn set_icon(&self, icon: u16) {
+        if let Some(tray_icon) = &self.tray_icon {
+            tray_icon.set_icon(StaticIcon::from_rc_numeric(icon));
+        }
+    }
+
     fn toggle_enabled(&self) {
         match self.enable_state.get() {
             EnableState::Enabled | EnableState::ReEnabled => {
-                self.tray_icon
-                    .set_icon(StaticIcon::from_rc_numeric(resources::ICON_KEYBOARD_DELETE));
+                self.set_icon(resources::ICON_KEYBOARD_DELETE);
                 self.enable_state.set(EnableState::Disabled);
                 keyboard::hook_disable();
             }
             EnableState::Disabled => {
-                self.tray_icon
-                    .set_icon(StaticIcon::from_rc_numeric(resources::ICON_KEYBOARD));
+                self.set_icon(resources::ICON_KEYBOARD);
                 self.enable_state.set(EnableState::ReEnabled);
                 keyboard::hook_enable();
             }
@@ -106,123 +131,312 @@ impl App {
     }
 }
 
-async fn remap_keys(layout: Layout, enable_state: &Cell<EnableState>) {
-    let mut kb = VirtualKeyboard::new(layout);
+/// Loads the configuration again when requested or when one of its files
+/// changed. Keeps the current layouts when the configuration is invalid.
+///
+/// `reloaded` applies the options of the new configuration to the output.
+#[cfg(any(windows, all(target_os = "linux", feature = "evdev")))]
+fn reload_config<O: OutputSink>(
+    engine: &mut Engine,
+    watcher: &mut FileWatcher,
+    config_file: &Path,
+    output: &mut O,
+    reloaded: &impl Fn(&mut O, &GlobalOptions),
+    app: &App,
+) -> Vec<OutputEvent> {
+    let requested = app.reload_requested.take();
+    if !watcher.poll(reload::modified) && !requested {
+        return Vec::new();
+    }
+
+    match Layouts::load_toml(config_file) {
+        Ok(layouts) => {
+            *watcher = FileWatcher::new(layouts.files(), reload::modified);
+            reloaded(output, layouts.options());
+            let events = engine.reload(layouts, config_file, output);
+            info!("configuration reloaded");
+            events
+        }
+        Err(e) => {
+            error!("cannot reload configuration: {e:#}");
+            #[cfg(windows)]
+            if let Some(tray_icon) = &app.tray_icon {
+                tray_icon.show_error("Cannot reload configuration", &format!("{e:#}"));
+            }
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(any(windows, all(target_os = "linux", feature = "evdev")))]
+async fn remap_keys<O: OutputSink>(
+    layouts: Layouts,
+    config_file: PathBuf,
+    recorder: Option<Recorder>,
+    input: &mut impl InputBackend,
+    output: &mut O,
+    reloaded: impl Fn(&mut O, &GlobalOptions),
+    app: &App,
+) -> Result<()> {
+    let mut watcher = FileWatcher::new(layouts.files(), reload::modified);
+    let mut last_poll = input.time();
+    let mut engine = Engine::new(layouts, Some(&config_file), recorder, output);
+
     loop {
-        let mut key_event = keyboard::next_key_event().await;
+        // Wake up regularly to check for configuration changes.
+        let timeout_ms = engine.timeout_ms(input, reload::POLL_INTERVAL_MS);
+        let key_event = input.next_key_event(timeout_ms).await?;
 
-        if matches!(enable_state.get(), EnableState::ReEnabled) {
-            kb.reset();
-            enable_state.set(EnableState::Enabled);
+        // The events of each call are sent right away, so that the toggle
+        // keys read by the next call are up to date.
+        let now = input.time();
+        if key_event.is_none() {
+            let events = engine.expire_locks(output, now);
+            output.send(&events, now);
+        }
+        if now.wrapping_sub(last_poll) >= reload::POLL_INTERVAL_MS || app.reload_requested.get() {
+            last_poll = now;
+            let events = reload_config(
+                &mut engine,
+                &mut watcher,
+                &config_file,
+                output,
+                &reloaded,
+                app,
+            );
+            output.send(&events, now);
         }
 
-        let remap = if key_event.up {
-            kb.release_key(key_event.scan_code)
-        } else {
-            kb.press_key(key_event.scan_code)
-        };
-
-        // Make sure the caps lock state stays in sync with our layout.
-        // We can get out of sync when caps lock is pressed in elevated context
-        // but our program is not elevated. In which we turn want to toggle the
-        // caps lock state back to what we expect it to be.
-        if kb.caps_lock_enabled() != keyboard::caps_lock_enabled() {
-            keyboard::send_key(KeyEvent {
-                up: false,
-                key: KeyType::VirtualKey(VK_CAPITAL as _),
-                ..key_event
-            });
-            keyboard::send_key(KeyEvent {
-                up: true,
-                key: KeyType::VirtualKey(VK_CAPITAL as _),
-                ..key_event
-            });
-            println!("caps lock toggled");
-        }
-
-        match remap {
-            None => println!("{key_event} forwarded"),
-            Some(KeyAction::Ignore) => {
-                println!("{key_event} ignored");
-                continue;
+        if let Some(key_event) = key_event {
+            if matches!(app.enable_state.get(), EnableState::ReEnabled) {
+                engine.reset();
+                app.enable_state.set(EnableState::Enabled);
             }
-            Some(KeyAction::Character(c)) => {
-                if let Some(virtual_key) = keyboard::get_virtual_key(c) {
-                    println!("{key_event} remapped to `{c}` as virtual key");
-                    key_event.key = KeyType::VirtualKey(virtual_key);
-                } else {
-                    println!("{key_event} remapped to `{c}` as unicode input");
-                    key_event.key = KeyType::Unicode(c);
-                }
+
+            let events = engine.process(key_event, input, output);
+            output.send(&events, key_event.time);
+        }
+    }
+}
+
+/// Runs the commands which work without keyboard hook and tray icon.
+fn run_headless(cli: &Cli) -> Result<()> {
+    let search_dirs = SearchDirs::from_env()?;
+    let load_layouts = || {
+        let config_file = search_dirs
+            .find(&cli.config)
+            .context("cannot load configuration file")?;
+        Layouts::load_toml(&config_file)
+    };
+
+    match &cli.command {
+        Command::Run { .. } => unreachable!(),
+        Command::Help => print!("{}", cli::USAGE),
+        Command::ListProfiles => {
+            for profile in search_dirs.profiles() {
+                println!("{profile}");
             }
-            Some(KeyAction::VirtualKey(virtual_key)) => {
-                println!("{key_event} remapped to virtual key {virtual_key:#04X}");
-                key_event.key = KeyType::VirtualKey(virtual_key);
+        }
+        Command::Check => {
+            let layouts = load_layouts()?;
+            println!("configuration is valid");
+            for file in layouts.files() {
+                println!("  {}", file.display());
             }
         }
-
-        keyboard::send_key(key_event);
+        Command::Print => print!("{}", print::layouts(&load_layouts()?)),
+        Command::Simulate { script } => {
+            let layouts = load_layouts()?;
+            let script = match script {
+                Some(path) => fs::read_to_string(path)
+                    .with_context(|| format!("cannot read script {}", path.display()))?,
+                None => io::read_to_string(io::stdin()).context("cannot read script")?,
+            };
+            let events = simulate::parse_script(&script)?;
+            print!("{}", simulate::simulate(layouts, &events));
+        }
+        Command::Convert { output } => {
+            let config_file = search_dirs
+                .find(&cli.config)
+                .context("cannot load configuration file")?;
+            let config = config::convert_toml(&config_file)?;
+            match output {
+                Some(path) => fs::write(path, config)
+                    .with_context(|| format!("cannot write {}", path.display()))?,
+                None => print!("{config}"),
+            }
+        }
+        Command::Test => {
+            let layouts = load_layouts()?;
+            let (report, failures) = self_test::run(&layouts);
+            print!("{report}");
+            let tests = layouts.tests().len();
+            if failures > 0 {
+                bail!("{failures} of {tests} tests failed");
+            }
+            println!("{tests} tests passed");
+        }
+        Command::Replay { recording } => {
+            let entries = fs::read_to_string(recording)
+                .with_context(|| format!("cannot read recording {}", recording.display()))?;
+            let entries = recording::parse(&entries)?;
+            let (diff, key_events) = recording::replay(load_layouts()?, &entries)?;
+            print!("{diff}");
+            if !diff.is_empty() {
+                bail!("replay of {key_events} key events differs from the recording");
+            }
+            println!("replay of {key_events} key events matches the recording");
+        }
     }
+    Ok(())
 }
 
 fn main() -> Result<()> {
-    let layout = load_config()?;
-    let app = Box::leak(Box::new(App::new()));
-
-    const MENU_RUN_ADMIN: u32 = 1;
-    const MENU_STARTUP: u32 = 2;
-    const MENU_DEBUG: u32 = 3;
-    const MENU_DISABLE: u32 = 4;
-    const MENU_EXIT: u32 = 5;
-
-    app.tray_icon.on_menu(|menu| {
-        let flag_checked = |condition| if condition { MF_CHECKED } else { 0 };
-        let flag_disabled = |condition| if condition { MF_DISABLED } else { 0 };
-
-        let is_elevated = winapi::is_elevated();
-
-        menu.add_entry(
-            MENU_RUN_ADMIN,
-            flag_checked(is_elevated) | flag_disabled(is_elevated),
-            c"Run as Administrator",
-        );
-        menu.add_entry(
-            MENU_STARTUP,
-            flag_checked(app.autostart.is_registered()),
-            c"Run at system startup",
-        );
-        menu.add_entry(
-            MENU_DEBUG,
-            flag_checked(winapi::console_check()) | flag_disabled(app.running_in_terminal),
-            c"Show debug output",
-        );
-        menu.add_entry(
-            MENU_DISABLE,
-            flag_checked(matches!(app.enable_state.get(), EnableState::Disabled)),
-            c"Disable",
-        );
-        menu.add_entry(MENU_EXIT, 0, c"Exit");
-    });
-
-    app.tray_icon.on_event(|event| {
-        match event {
-            TrayIconEvent::Click => {} // ignore
-            TrayIconEvent::DoubleClick => app.toggle_enabled(),
-            TrayIconEvent::MenuItem(MENU_STARTUP) => app.toggle_autostart(),
-            TrayIconEvent::MenuItem(MENU_DEBUG) => app.toggle_debug_console(),
-            TrayIconEvent::MenuItem(MENU_RUN_ADMIN) => {
-                if winapi::elevate() {
-                    process::exit(0);
+    let cli = Cli::parse(env::args_os().skip(1));
+    let cli = match cli {
+        Ok(cli) if !cli.command.is_headless() => cli,
+        cli => {
+            // Output of headless commands and errors goes to the terminal.
+            #[cfg(windows)]
+            winapi::console_check();
+            return run_headless(&cli.context("invalid command line, see `kbremap --help`")?);
+        }
+    };
+    log::set_level(cli.log_level);
+
+    let config_file = SearchDirs::from_env()?
+        .find(&cli.config)
+        .context("cannot load configuration file")?;
+    let layouts = Layouts::load_toml(&config_file)?;
+    let Command::Run { recording, device } = &cli.command else {
+        unreachable!()
+    };
+    let recorder = match recording {
+        Some(path) => Some(Recorder::create(path, &config_file)?),
+        None => None,
+    };
+    run(&cli, device.as_deref(), layouts, config_file, recorder)
+}
+
+/// Remaps the keyboard with the low-level keyboard hook until the user exits
+/// from the tray icon menu.
+#[cfg(windows)]
+fn run(
+    cli: &Cli,
+    device: Option<&Path>,
+    layouts: Layouts,
+    config_file: PathBuf,
+    recorder: Option<Recorder>,
+) -> Result<()> {
+    if device.is_some() {
+        bail!("`--device` is only supported on Linux");
+    }
+    let app = Box::leak(Box::new(App::new(cli.tray)));
+
+    if let Some(tray_icon) = &app.tray_icon {
+        const MENU_RUN_ADMIN: u32 = 1;
+        const MENU_STARTUP: u32 = 2;
+        const MENU_DEBUG: u32 = 3;
+        const MENU_DISABLE: u32 = 4;
+        const MENU_RELOAD: u32 = 5;
+        const MENU_EXIT: u32 = 6;
+
+        tray_icon.on_menu(|menu| {
+            let flag_checked = |condition| if condition { MF_CHECKED } else { 0 };
+            let flag_disabled = |condition| if condition { MF_DISABLED } else { 0 };
+
+            let is_elevated = winapi::is_elevated();
+
+            menu.add_entry(
+                MENU_RUN_ADMIN,
+                flag_checked(is_elevated) | flag_disabled(is_elevated),
+                c"Run as Administrator",
+            );
+            menu.add_entry(
+                MENU_STARTUP,
+                flag_checked(app.autostart.is_registered()),
+                c"Run at system startup",
+            );
+            menu.add_entry(
+                MENU_DEBUG,
+                flag_checked(winapi::console_check()) | flag_disabled(app.running_in_terminal),
+                c"Show debug output",
+            );
+            menu.add_entry(
+                MENU_DISABLE,
+                flag_checked(matches!(app.enable_state.get(), EnableState::Disabled)),
+                c"Disable",
+            );
+            menu.add_entry(MENU_RELOAD, 0, c"Reload configuration");
+            menu.add_entry(MENU_EXIT, 0, c"Exit");
+        });
+
+        tray_icon.on_event(|event| {
+            match event {
+                TrayIconEvent::Click => {} // ignore
+                TrayIconEvent::DoubleClick => app.toggle_enabled(),
+                TrayIconEvent::MenuItem(MENU_STARTUP) => app.toggle_autostart(),
+                TrayIconEvent::MenuItem(MENU_DEBUG) => app.toggle_debug_console(),
+                TrayIconEvent::MenuItem(MENU_RUN_ADMIN) => {
+                    if winapi::elevate() {
+                        process::exit(0);
+                    }
                 }
+                TrayIconEvent::MenuItem(MENU_DISABLE) => app.toggle_enabled(),
+                TrayIconEvent::MenuItem(MENU_RELOAD) => app.reload_requested.set(true),
+                TrayIconEvent::MenuItem(MENU_EXIT) => process::exit(0),
+                TrayIconEvent::MenuItem(_) => unreachable!(),
             }
-            TrayIconEvent::MenuItem(MENU_DISABLE) => app.toggle_enabled(),
-            TrayIconEvent::MenuItem(MENU_EXIT) => process::exit(0),
-            TrayIconEvent::MenuItem(_) => unreachable!(),
-        }
-    });
+        });
+    }
 
     // The executor runs the windows message loop internally.
-    winmsg_executor::block_on(remap_keys(layout, &app.enable_state));
+    winmsg_executor::block_on(remap_keys(
+        layouts,
+        config_file,
+        recorder,
+        &mut HookInput::default(),
+        &mut SendInputOutput,
+        |_, _| {},
+        app,
+    ))
+}
 
-    Ok(())
+/// Remaps the keyboard device, grabbed so that only the remapped key events
+/// reach the system.
+#[cfg(all(target_os = "linux", feature = "evdev"))]
+fn run(
+    _cli: &Cli,
+    device: Option<&Path>,
+    layouts: Layouts,
+    config_file: PathBuf,
+    recorder: Option<Recorder>,
+) -> Result<()> {
+    let device = device.context("`run` requires `--device <path>` on Linux")?;
+    let mut input = linux::EvdevInput::open(device)?;
+    let mut output = linux::UinputOutput::create(&input, &layouts.options().unicode_input)?;
+    let app = App {
+        enable_state: Cell::new(EnableState::Enabled),
+        reload_requested: Cell::new(false),
+    };
+    linux::block_on(remap_keys(
+        layouts,
+        config_file,
+        recorder,
+        &mut input,
+        &mut output,
+        |output, options| output.set_unicode_input(&options.unicode_input),
+        &app,
+    ))
+}
+
+#[cfg(not(any(windows, all(target_os = "linux", feature = "evdev"))))]
+fn run(
+    _cli: &Cli,
+    _device: Option<&Path>,
+    _layouts: Layouts,
+    _config_file: PathBuf,
+    _recorder: Option<Recorder>,
+) -> Result<()> {
+    bail!("remapping requires windows, or linux with the `evdev` feature");
 }
@@ -0,0 +1,202 @@
+//! Human readable listing of the layouts for the `print` command.
+
+use std::fmt::{self, Write};
+
+use crate::layout::{
+    Fallthrough, KeyAction, LayerIdx, LayerLock, Layout, LayoutSwitch, Layouts, SendAs,
+};
+use crate::rules::RuleAction;
+use crate::toggle_keys::ToggleKey;
+
+pub fn layouts(layouts: &Layouts) -> String {
+    let mut out = String::new();
+    write_layouts(&mut out, layouts).unwrap();
+    out
+}
+
+fn write_layouts(out: &mut impl Write, layouts: &Layouts) -> fmt::Result {
+    for (idx, name) in layouts.names().enumerate() {
+        let idx = idx as u8;
+        write!(out, "layout `{name}`")?;
+        if idx == layouts.default_layout() {
+            write!(out, " (default)")?;
+        }
+        writeln!(out)?;
+        write_layout(out, layouts, layouts.layout(idx))?;
+    }
+
+    for rule in layouts.rules() {
+        let conditions = [
+            ("process", &rule.process_name),
+            ("window_class", &rule.window_class),
+            ("input_language", &rule.input_language),
+        ];
+        write!(out, "rule")?;
+        for (key, value) in conditions {
+            if let Some(value) = value {
+                write!(out, " {key} = `{value}`")?;
+            }
+        }
+        match rule.action {
+            RuleAction::Layout(layout) => writeln!(out, ": layout `{}`", layouts.name(layout))?,
+            RuleAction::Passthrough => writeln!(out, ": passthrough")?,
+        }
+    }
+    Ok(())
+}
+
+fn write_layout(out: &mut impl Write, layouts: &Layouts, layout: &Layout) -> fmt::Result {
+    for (idx, name) in layout.layer_names().enumerate() {
+        let layer = idx as LayerIdx;
+
+        let mut settings = Vec::new();
+        if idx == 0 {
+            settings.push(String::from("base layer"));
+        }
+        for key in ToggleKey::ALL {
+            if layout.toggle_layer(key) == Some(layer) {
+                settings.push(format!("{} layer", key.name()));
+            }
+        }
+        match layout.fallthrough(layer) {
+            Fallthrough::Transparent => {}
+            Fallthrough::Opaque => settings.push(String::from("opaque")),
+            Fallthrough::Passthrough => settings.push(String::from("passthrough")),
+        }
+        if let Some(timeout) = layout.lock_timeout(layer) {
+            settings.push(format!("lock timeout {timeout} ms"));
+        }
+
+        write!(out, "  layer `{name}`")?;
+        if !settings.is_empty() {
+            write!(out, " ({})", settings.join(", "))?;
+        }
+        writeln!(out)?;
+
+        for scan_code in layout.scan_codes(layer) {
+            let mut targets = Vec::new();
+            if let Some(target_layer) = layout.layer_modifier(layer, scan_code) {
+                targets.push(format!("layer `{}`", layout.layer_name(target_layer)));
+            }
+            if let Some(lock) = layout.layer_lock(layer, scan_code) {
+                targets.push(match lock {
+                    LayerLock::Toggle(l) => format!("lock `{}`", layout.layer_name(l)),
+                    LayerLock::Replace(l) => format!("to `{}`", layout.layer_name(l)),
+                    LayerLock::Clear => String::from("clear locks"),
+                    LayerLock::SetBase(l) => format!("set base `{}`", layout.layer_name(l)),
+                });
+            }
+            // Layer and lock keys without virtual key are ignored.
+            match layout.action(layer, scan_code) {
+                Some(KeyAction::Ignore) if !targets.is_empty() => {}
+                Some(key_action) => targets.push(action(layouts, key_action)),
+                None => {}
+            }
+            writeln!(out, "    {scan_code:#06X}: {}", targets.join(", "))?;
+        }
+    }
+
+    for (layers, target_layer) in layout.conditional_layers() {
+        let layers: Vec<_> = layers
+            .iter()
+            .map(|l| format!("`{}`", layout.layer_name(*l)))
+            .collect();
+        writeln!(
+            out,
+            "  conditional layer `{}` when {} are active",
+            layout.layer_name(*target_layer),
+            layers.join(" and ")
+        )?;
+    }
+    Ok(())
+}
+
+/// Describes a key action, e.g. `character 'a'`.
+pub fn action(layouts: &Layouts, action: KeyAction) -> String {
+    match action {
+        KeyAction::Ignore => String::from("ignore"),
+        KeyAction::Character(c, output) => {
+            let mut description = format!("character {c:?}");
+            match output.send_as {
+                SendAs::Auto => {}
+                SendAs::Unicode => description.push_str(" as unicode input"),
+                SendAs::VirtualKey => description.push_str(" as virtual key"),
+                SendAs::AltCode => description.push_str(" as alt code"),
+            }
+            if output.wrap_modifiers {
+                description.push_str(" with modifier wrapping");
+            }
+            description
+        }
+        KeyAction::VirtualKey(vk) => format!("virtual key {vk:#04X}"),
+        KeyAction::SwitchLayout(LayoutSwitch::To(layout)) => {
+            format!("switch to layout `{}`", layouts.name(layout))
+        }
+        KeyAction::SwitchLayout(LayoutSwitch::Cycle(cycle)) => {
+            let names: Vec<_> = layouts
+                .cycle(cycle)
+                .iter()
+                .map(|l| format!("`{}`", layouts.name(*l)))
+                .collect();
+            format!("cycle layouts {}", names.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn print_layouts() {
+        let layouts = Layouts::parse_toml(
+            r#"
+            base_layer = "base"
+            caps_lock_layer = "shift"
+
+            [layers]
+            base = [
+                { scan_code = 0x1E, characters = "ab" },
+                { scan_code = 0x2A, layer = "shift", virtual_key = 0xA0 },
+                { scan_code = 0x3A, lock = "shift" },
+                { scan_code = 0x01, switch_layout = "gaming" },
+            ]
+            shift = { fallthrough = "opaque", mappings = [
+                { scan_code = 0x1E, characters = "AB" },
+                { scan_code = 0x01, clear_locks = true },
+            ] }
+
+            [layouts.gaming]
+            base_layer = "game"
+            [layouts.gaming.layers]
+            game = [{ scan_code = 0x01, cycle_layouts = ["default", "gaming"] }]
+
+            [[rules]]
+            process = "game.exe"
+            layout = "gaming"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            super::layouts(&layouts),
+            "\
+layout `default` (default)
+  layer `base` (base layer)
+    0x0001: switch to layout `gaming`
+    0x001E: character 'a'
+    0x001F: character 'b'
+    0x002A: layer `shift`, virtual key 0xA0
+    0x003A: lock `shift`
+  layer `shift` (caps lock layer, opaque)
+    0x0001: clear locks
+    0x001E: character 'A'
+    0x001F: character 'B'
+layout `gaming`
+  layer `game` (base layer)
+    0x0001: cycle layouts `default`, `gaming`
+rule process = `game.exe`: layout `gaming`
+"
+        );
+    }
+}
@@ -0,0 +1,431 @@
+//! Recording of key event sessions and their replay to reproduce bugs.
+//!
+//! A recording is a JSON Lines file. The first line is the header
+//! `{"version":2,"config":"<path>"}`, every other line is one [`Entry`].
+//! Entries are tagged by their type, e.g.
+//! `{"key":{"scan_code":42,"virtual_key":160,"up":false,"time":1000,"output":[{"press":{"virtual_key":160,"scan_code":42}}]}}`.
+//! The version is incremented for incompatible changes of the format.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::backend::{InputBackend, InternalEvent, KeyEvent, KeyType, OutputEvent, OutputSink};
+use crate::engine::Engine;
+use crate::host_layout::{HostKey, HostLayout, Modifiers};
+use crate::layout::{Layouts, ScanCode};
+use crate::rules::ForegroundContext;
+use crate::toggle_keys::ToggleKey;
+
+pub const VERSION: u32 = 2;
+
+/// Keyboard layout assumed for characters when the recorded input language
+/// has no [`HostLayout`] model, like on linux.
+const US_LAYOUT: u16 = 0x0409;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    /// Configuration file used while recording.
+    config: PathBuf,
+}
+
+/// Event that influences the remapping, in the order of occurrence.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Entry {
+    /// State when remapping started, with the base layer restored by
+    /// `persist_base_layer` and the enabled toggle keys.
+    Start {
+        base_layer: String,
+        toggle_keys: Vec<ToggleKey>,
+    },
+
+    /// Key event of the keyboard hook and the events sent to the system.
+    Key {
+        scan_code: ScanCode,
+        /// Virtual key reported by the keyboard hook, for information only.
+        virtual_key: u8,
+        up: bool,
+        time: u32,
+        output: Vec<OutputEvent>,
+    },
+
+    /// Layer locks timed out at `time` without key event.
+    LockTimeout { time: u32 },
+
+    /// The rules were evaluated for a new foreground window.
+    Foreground(ForegroundContext),
+
+    /// Remapping was re-enabled, which releases all keys and layer locks.
+    Reset,
+
+    /// The configuration was reloaded. Recordings with reloads cannot be
+    /// replayed because the reloaded configuration is not recorded.
+    Reload,
+}
+
+/// Writes entries to a recording file. Each entry is written immediately so
+/// that nothing is lost when the process is terminated.
+pub struct Recorder {
+    file: LineWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, config_file: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("cannot create recording {}", path.display()))?;
+        let mut recorder = Self {
+            file: LineWriter::new(file),
+        };
+        let header = Header {
+            version: VERSION,
+            config: config_file.to_path_buf(),
+        };
+        recorder.write_line(&header)?;
+        Ok(recorder)
+    }
+
+    pub fn record(&mut self, entry: &Entry) -> Result<()> {
+        self.write_line(entry)
+    }
+
+    fn write_line(&mut self, value: &impl Serialize) -> Result<()> {
+        let mut line = serde_json::to_vec(value)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// Parses a recording, rejects unsupported versions.
+pub fn parse(recording: &str) -> Result<Vec<Entry>> {
+    let mut lines = recording.lines().enumerate();
+    let header = lines.next().map_or("", |(_, line)| line);
+    let header: Header = serde_json::from_str(header).context("invalid recording header")?;
+    if header.version != VERSION {
+        bail!(
+            "unsupported recording version {}, expected {VERSION}",
+            header.version
+        );
+    }
+
+    lines
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(line_idx, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("line {}: invalid entry", line_idx + 1))
+        })
+        .collect()
+}
+
+/// Foreground changes of the recording for the [`Engine`].
+#[derive(Debug, Default)]
+struct ReplayInput {
+    foreground: Option<ForegroundContext>,
+    time: u32,
+}
+
+impl InputBackend for ReplayInput {
+    async fn next_key_event(&mut self, _timeout_ms: u32) -> Result<Option<KeyEvent>> {
+        Ok(None)
+    }
+
+    fn time(&self) -> u32 {
+        self.time
+    }
+
+    fn foreground_changed(&mut self) -> Option<ForegroundContext> {
+        self.foreground.take()
+    }
+}
+
+/// System state as changed by the replayed events, without any other
+/// programs or keyboards.
+#[derive(Debug, Default)]
+struct ReplayOutput {
+    pressed: Vec<u8>,
+    toggle_keys: Vec<ToggleKey>,
+}
+
+impl OutputSink for ReplayOutput {
+    fn send(&mut self, events: &[OutputEvent], _time: u32) {
+        for event in events {
+            match *event {
+                OutputEvent::Press { virtual_key, .. } => {
+                    if !self.pressed.contains(&virtual_key) {
+                        self.pressed.push(virtual_key);
+                        if let Some(key) = (ToggleKey::ALL.into_iter())
+                            .find(|key| key.virtual_key() == virtual_key)
+                        {
+                            toggle(&mut self.toggle_keys, key);
+                        }
+                    }
+                }
+                OutputEvent::Release { virtual_key, .. } => {
+                    self.pressed.retain(|vk| *vk != virtual_key);
+                }
+                OutputEvent::Text { .. } | OutputEvent::Internal(_) => {}
+            }
+        }
+    }
+
+    fn host_key(&self, c: char) -> Option<HostKey> {
+        HostLayout::builtin(US_LAYOUT)?.key(c)
+    }
+
+    fn modifiers(&self) -> Modifiers {
+        let pressed = |vks: &[u8]| vks.iter().any(|vk| self.pressed.contains(vk));
+        Modifiers {
+            left_shift: pressed(&[0x10, 0xA0]),
+            right_shift: pressed(&[0xA1]),
+            ctrl: pressed(&[0x11, 0xA2, 0xA3]),
+            alt: pressed(&[0x12, 0xA4, 0xA5]),
+            caps_lock: self.toggle_key_enabled(ToggleKey::Caps),
+        }
+    }
+
+    fn toggle_key_enabled(&self, key: ToggleKey) -> bool {
+        self.toggle_keys.contains(&key)
+    }
+}
+
+fn toggle(toggle_keys: &mut Vec<ToggleKey>, key: ToggleKey) {
+    match toggle_keys.iter().position(|k| *k == key) {
+        Some(idx) => _ = toggle_keys.remove(idx),
+        None => toggle_keys.push(key),
+    }
+}
+
+/// Describes output events for the differences of a replay.
+fn describe(layouts: &Layouts, events: &[OutputEvent]) -> String {
+    let describe = |event: &OutputEvent| match *event {
+        OutputEvent::Press { virtual_key, .. } => format!("+vk:{virtual_key:#04X}"),
+        OutputEvent::Release { virtual_key, .. } => format!("-vk:{virtual_key:#04X}"),
+        OutputEvent::Text { character, up } => {
+            format!("{}{character:?}", if up { '-' } else { '+' })
+        }
+        // Recordings may refer to layouts which no longer exist.
+        OutputEvent::Internal(InternalEvent::LayoutChanged(layout)) => {
+            match layouts.names().nth(layout.into()) {
+                Some(name) => format!("layout:{name}"),
+                None => format!("layout:{layout}"),
+            }
+        }
+        OutputEvent::Internal(InternalEvent::BaseLayerChanged) => String::from("base layer"),
+    };
+    let events: Vec<_> = events.iter().map(describe).collect();
+    format!("[{}]", events.join(", "))
+}
+
+/// Feeds the recorded entries through the [`Engine`] and describes every key
+/// event whose output differs from the recorded output.
+///
+/// Returns the differences and the number of key events. Recordings with
+/// configuration reloads are rejected.
+pub fn replay(layouts: Layouts, entries: &[Entry]) -> Result<(String, usize)> {
+    if entries.contains(&Entry::Reload) {
+        bail!("the recording contains a configuration reload, which cannot be replayed");
+    }
+
+    let mut input = ReplayInput::default();
+    let mut output = ReplayOutput::default();
+    let mut engine = Engine::new(layouts.clone(), None, None, &output);
+    let mut diff = String::new();
+    let mut key_events = 0;
+    for entry in entries {
+        match entry {
+            Entry::Start {
+                base_layer,
+                toggle_keys,
+            } => {
+                output.toggle_keys.clone_from(toggle_keys);
+                if !engine.set_base_layer(base_layer) {
+                    writeln!(diff, "base layer `{base_layer}` not found").unwrap();
+                }
+            }
+            Entry::Key {
+                scan_code,
+                virtual_key,
+                up,
+                time,
+                output: recorded,
+            } => {
+                key_events += 1;
+                input.time = *time;
+                let key_event = KeyEvent {
+                    key: KeyType::VirtualKey(*virtual_key),
+                    scan_code: *scan_code,
+                    up: *up,
+                    time: *time,
+                };
+                let replayed = engine.process(key_event, &mut input, &output);
+                output.send(&replayed, *time);
+                if replayed != *recorded {
+                    writeln!(
+                        diff,
+                        "{}{scan_code:#06X} @{time}: recorded {}, replayed {}",
+                        if *up { '-' } else { '+' },
+                        describe(&layouts, recorded),
+                        describe(&layouts, &replayed)
+                    )
+                    .unwrap();
+                }
+            }
+            Entry::LockTimeout { time } => {
+                let events = engine.expire_locks(&output, *time);
+                output.send(&events, *time);
+            }
+            Entry::Foreground(context) => input.foreground = Some(context.clone()),
+            Entry::Reset => engine.reset(),
+            Entry::Reload => unreachable!(),
+        }
+    }
+    Ok((diff, key_events))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        base_layer = "base"
+
+        [layers]
+        base = [
+            { scan_code = 0x1E, characters = "a" },
+            { scan_code = 0x2A, layer = "shift", virtual_key = 0xA0 },
+            { scan_code = 0x3C, set_base = "qwerty" },
+        ]
+        shift = [{ scan_code = 0x1E, characters = "A" }]
+        qwerty = [
+            { scan_code = 0x1E, characters = "q" },
+            { scan_code = 0x2A, layer = "shift", virtual_key = 0xA0 },
+        ]
+
+        [[rules]]
+        process = "vmconnect.exe"
+        passthrough = true
+        "#;
+
+    fn key(scan_code: ScanCode, virtual_key: u8, up: bool, output: &[OutputEvent]) -> Entry {
+        Entry::Key {
+            scan_code,
+            virtual_key,
+            up,
+            time: 0,
+            output: output.to_vec(),
+        }
+    }
+
+    fn press(virtual_key: u8, scan_code: u16) -> OutputEvent {
+        OutputEvent::Press {
+            virtual_key,
+            scan_code,
+        }
+    }
+
+    fn release(virtual_key: u8, scan_code: u16) -> OutputEvent {
+        OutputEvent::Release {
+            virtual_key,
+            scan_code,
+        }
+    }
+
+    fn start(base_layer: &str) -> Entry {
+        Entry::Start {
+            base_layer: base_layer.to_string(),
+            toggle_keys: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn format() {
+        let dir = std::env::temp_dir().join(format!("kbremap-{}-recording", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("session.jsonl");
+
+        let mut recorder = Recorder::create(&path, Path::new("config.toml")).unwrap();
+        let entries = [
+            Entry::Start {
+                base_layer: String::from("base"),
+                toggle_keys: vec![ToggleKey::Caps],
+            },
+            Entry::Foreground(ForegroundContext {
+                process_name: String::from("notepad.exe"),
+                ..Default::default()
+            }),
+            key(0x1E, 0x41, false, &[press(0x41, 0x1E)]),
+            Entry::LockTimeout { time: 100 },
+            Entry::Reset,
+        ];
+        for entry in &entries {
+            recorder.record(entry).unwrap();
+        }
+        drop(recorder);
+
+        let recording = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let lines: Vec<_> = recording.lines().collect();
+        assert_eq!(lines[0], r#"{"version":2,"config":"config.toml"}"#);
+        assert_eq!(
+            lines[1],
+            r#"{"start":{"base_layer":"base","toggle_keys":["caps"]}}"#
+        );
+        assert_eq!(
+            lines[3],
+            r#"{"key":{"scan_code":30,"virtual_key":65,"up":false,"time":0,"output":[{"press":{"virtual_key":65,"scan_code":30}}]}}"#
+        );
+        assert_eq!(lines[5], r#""reset""#);
+        assert_eq!(parse(&recording).unwrap(), entries);
+
+        let error = |recording| format!("{:#}", parse(recording).unwrap_err());
+        assert_eq!(
+            error(r#"{"version":1,"config":""}"#),
+            "unsupported recording version 1, expected 2"
+        );
+        assert!(error("").starts_with("invalid recording header"));
+        assert!(error("{\"version\":2,\"config\":\"\"}\n{}").starts_with("line 2: invalid entry"));
+    }
+
+    #[test]
+    fn replay_differences() {
+        let layouts = || Layouts::parse_toml(CONFIG).unwrap();
+        let mut entries = vec![
+            start("qwerty"),
+            key(0x2A, 0xA0, false, &[press(0xA0, 0x2A)]),
+            // `A` is typed with the held shift key on the US layout.
+            key(0x1E, 0x41, false, &[press(0x41, 0x1E)]),
+            key(0x1E, 0x41, true, &[release(0x41, 0x1E)]),
+            key(0x2A, 0xA0, true, &[release(0xA0, 0x2A)]),
+            key(0x1E, 0x41, false, &[press(0x51, 0x1E)]),
+            Entry::Foreground(ForegroundContext {
+                process_name: String::from("vmconnect.exe"),
+                ..Default::default()
+            }),
+            key(0x1E, 0x41, true, &[release(0x51, 0x1E)]),
+            key(0x30, 0x42, false, &[press(0x42, 0x30)]),
+        ];
+        assert_eq!(replay(layouts(), &entries).unwrap(), (String::new(), 7));
+
+        // The recording started on another base layer.
+        entries[0] = start("base");
+        assert_eq!(
+            replay(layouts(), &entries).unwrap().0,
+            "\
++0x001E @0: recorded [+vk:0x51], replayed [+vk:0x41]
+-0x001E @0: recorded [-vk:0x51], replayed [-vk:0x41]
+"
+        );
+
+        entries.push(Entry::Reload);
+        assert_eq!(
+            replay(layouts(), &entries).unwrap_err().to_string(),
+            "the recording contains a configuration reload, which cannot be replayed"
+        );
+    }
+}
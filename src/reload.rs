@@ -0,0 +1,93 @@
+//! Detection of configuration file changes to reload the layouts.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Interval in milliseconds to check the configuration files for changes.
+#[cfg_attr(
+    not(any(windows, all(target_os = "linux", feature = "evdev"))),
+    allow(dead_code)
+)]
+pub const POLL_INTERVAL_MS: u32 = 1000;
+
+/// Returns the modification time of a file, `None` if it does not exist.
+#[cfg_attr(
+    not(any(windows, all(target_os = "linux", feature = "evdev"))),
+    allow(dead_code)
+)]
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Detects changes of a set of files by their modification times.
+#[derive(Debug, Default)]
+pub struct FileWatcher {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl FileWatcher {
+    pub fn new(files: &[PathBuf], modified: impl Fn(&Path) -> Option<SystemTime>) -> Self {
+        Self {
+            files: files
+                .iter()
+                .map(|path| (path.clone(), modified(path)))
+                .collect(),
+        }
+    }
+
+    /// Returns `true` if any of the files was modified, created or removed
+    /// since the last call.
+    pub fn poll(&mut self, modified: impl Fn(&Path) -> Option<SystemTime>) -> bool {
+        let mut changed = false;
+        for (path, last_modified) in &mut self.files {
+            let now_modified = modified(path);
+            if now_modified != *last_modified {
+                *last_modified = now_modified;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn file_watcher() {
+        let time = |secs| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        let files = RefCell::new(HashMap::from([
+            (PathBuf::from("config.toml"), time(1)),
+            (PathBuf::from("common.toml"), time(1)),
+        ]));
+        let modified = |path: &Path| files.borrow().get(path).copied().flatten();
+
+        let paths = [PathBuf::from("config.toml"), PathBuf::from("common.toml")];
+        let mut watcher = FileWatcher::new(&paths, modified);
+        assert!(!watcher.poll(modified));
+
+        // Changes are reported once.
+        files
+            .borrow_mut()
+            .insert(PathBuf::from("common.toml"), time(2));
+        assert!(watcher.poll(modified));
+        assert!(!watcher.poll(modified));
+
+        // Removed and created again, e.g. by editors saving to a new file.
+        files
+            .borrow_mut()
+            .insert(PathBuf::from("config.toml"), None);
+        assert!(watcher.poll(modified));
+        files
+            .borrow_mut()
+            .insert(PathBuf::from("config.toml"), time(3));
+        assert!(watcher.poll(modified));
+        assert!(!watcher.poll(modified));
+    }
+}
@@ -0,0 +1,322 @@
+//! Processing of key events with the layouts of a configuration, shared by the
+//! keyboard hook and the simulator.
+
+use std::fmt::Display;
+
+use crate::layout::{CharacterOutput, KeyAction, LayoutIdx, Layouts, ScanCode};
+use crate::rules::{ForegroundContext, LayoutSelection};
+use crate::virtual_keyboard::VirtualKeyboard;
+
+/// What to do with a key event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Remapping is disabled for the foreground application, send the key
+    /// unchanged.
+    Passthrough,
+
+    /// The key has no mapping, send it unchanged.
+    Forward,
+
+    /// Do not send anything.
+    Ignore,
+
+    /// Send a character, if possible as virtual key.
+    Character(char, CharacterOutput),
+
+    /// Send a virtual key.
+    VirtualKey(u8),
+
+    /// A layout switching key was pressed, nothing is sent.
+    SwitchLayout(LayoutIdx),
+}
+
+/// Layouts with the state of the active layout.
+#[derive(Debug)]
+pub struct Remapper {
+    layouts: Layouts,
+    selection: LayoutSelection,
+    layout_idx: LayoutIdx,
+    kb: VirtualKeyboard,
+
+    /// Base layer of the active layout, to detect changes by `set_base` keys.
+    base_layer: String,
+}
+
+impl Remapper {
+    pub fn new(layouts: Layouts) -> Self {
+        let layout_idx = layouts.default_layout();
+        let kb = VirtualKeyboard::new(layouts.layout(layout_idx).clone());
+        Self {
+            selection: LayoutSelection::new(layout_idx),
+            layout_idx,
+            base_layer: kb.base_layer().to_string(),
+            kb,
+            layouts,
+        }
+    }
+
+    pub fn layouts(&self) -> &Layouts {
+        &self.layouts
+    }
+
+    pub fn layout_idx(&self) -> LayoutIdx {
+        self.layout_idx
+    }
+
+    pub fn kb(&self) -> &VirtualKeyboard {
+        &self.kb
+    }
+
+    /// Releases all keys and unlocks all layers, e.g. after the keyboard hook
+    /// was disabled.
+    pub fn reset(&mut self) {
+        self.kb.reset();
+    }
+
+    /// Replaces the layouts after the configuration was reloaded.
+    ///
+    /// Stays on the layout and base layer with the same name if possible.
+    /// Keys held during the reload release with their previous action.
+    pub fn reload(&mut self, layouts: Layouts) {
+        let layout_name = self.layouts.name(self.layout_idx);
+        let same_layout = layouts.names().position(|name| name == layout_name);
+
+        self.layout_idx = same_layout.map_or(layouts.default_layout(), |idx| idx as LayoutIdx);
+        self.selection = LayoutSelection::new(self.layout_idx);
+        self.layouts = layouts;
+
+        // Loading the new layout into the existing virtual keyboard keeps the
+        // pressed keys.
+        self.kb
+            .set_layout(self.layouts.layout(self.layout_idx).clone());
+        if same_layout.is_some() {
+            self.kb.set_base_layer(&self.base_layer);
+        }
+        self.base_layer = self.kb.base_layer().to_string();
+    }
+
+    /// Changes the base layer of the active layout by name. Returns `false`
+    /// if there is no such layer.
+    pub fn set_base_layer(&mut self, name: &str) -> bool {
+        let found = self.kb.set_base_layer(name);
+        self.base_layer = self.kb.base_layer().to_string();
+        found
+    }
+
+    /// Returns `true` once after the base layer was changed by a `set_base` key.
+    pub fn base_layer_changed(&mut self) -> bool {
+        if self.kb.base_layer() == self.base_layer {
+            return false;
+        }
+        self.base_layer = self.kb.base_layer().to_string();
+        true
+    }
+
+    /// Loads the layout into the virtual keyboard unless it is active already.
+    ///
+    /// Returns `true` when the layout changed.
+    fn activate_layout(&mut self, layout_idx: LayoutIdx) -> bool {
+        if layout_idx == self.layout_idx {
+            return false;
+        }
+        self.layout_idx = layout_idx;
+        self.kb.set_layout(self.layouts.layout(layout_idx).clone());
+        self.base_layer = self.kb.base_layer().to_string();
+        true
+    }
+
+    /// Switches to a layout, like a `switch_layout` key.
+    pub fn switch_layout(&mut self, layout_idx: LayoutIdx) {
+        self.selection.switch(layout_idx);
+        self.activate_layout(layout_idx);
+    }
+
+    /// Evaluates the rules for a new foreground application.
+    ///
+    /// Returns `true` when the active layout changed.
+    pub fn update_foreground(&mut self, context: &ForegroundContext) -> bool {
+        self.selection
+            .update_foreground(self.layouts.rules(), context);
+        match self.selection.active() {
+            Some(layout_idx) => self.activate_layout(layout_idx),
+            None => false,
+        }
+    }
+
+    /// Returns the time at which locked layers time out, see
+    /// [`VirtualKeyboard::lock_deadline()`].
+    pub fn lock_deadline(&self) -> Option<u32> {
+        self.kb.lock_deadline()
+    }
+
+    /// Unlocks layers when their lock timeout expired at `time`.
+    pub fn expire_locks(&mut self, time: u32) -> bool {
+        self.kb.expire_locks(time)
+    }
+
+    /// Processes a key press or release at `time` in milliseconds.
+    pub fn process(&mut self, scan_code: ScanCode, up: bool, time: u32) -> Action {
+        // Keys pressed before passthrough was activated still need to release
+        // with their remapped action.
+        let remapped_release = up && self.kb.is_pressed(scan_code);
+        if self.selection.active().is_none() && !remapped_release {
+            return Action::Passthrough;
+        }
+
+        self.kb.update_time(time);
+        let repeated = !up && self.kb.is_pressed(scan_code);
+        let action = if up {
+            self.kb.release_key(scan_code)
+        } else {
+            self.kb.press_key(scan_code)
+        };
+
+        match action {
+            None => Action::Forward,
+            Some(KeyAction::Ignore) => Action::Ignore,
+            Some(KeyAction::Character(c, output)) => Action::Character(c, output),
+            Some(KeyAction::VirtualKey(virtual_key)) => Action::VirtualKey(virtual_key),
+            Some(KeyAction::SwitchLayout(switch)) => {
+                // Switch only once per key press, not on key repeat or release.
+                if up || repeated {
+                    return Action::Ignore;
+                }
+
+                let layout_idx = self.layouts.switch_target(self.layout_idx, switch);
+                self.switch_layout(layout_idx);
+                Action::SwitchLayout(layout_idx)
+            }
+        }
+    }
+
+    /// Describes the action for log output.
+    pub fn describe(&self, action: Action) -> impl Display + '_ {
+        struct Describe<'a>(&'a Layouts, Action);
+
+        impl Display for Describe<'_> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match self.1 {
+                    Action::Passthrough => f.write_str("passed through"),
+                    Action::Forward => f.write_str("forwarded"),
+                    Action::Ignore => f.write_str("ignored"),
+                    Action::Character(c, _) => write!(f, "remapped to `{c}`"),
+                    Action::VirtualKey(vk) => write!(f, "remapped to virtual key {vk:#04X}"),
+                    // Recordings may refer to layouts which no longer exist.
+                    Action::SwitchLayout(layout) => match self.0.names().nth(layout.into()) {
+                        Some(name) => write!(f, "switched to layout `{name}`"),
+                        None => write!(f, "switched to layout {layout}"),
+                    },
+                }
+            }
+        }
+
+        Describe(&self.layouts, action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        base_layer = "base"
+        persist_base_layer = true
+
+        [layers]
+        base = [
+            { scan_code = 0x1E, characters = "a" },
+            { scan_code = 0x2A, layer = "shift", virtual_key = 0xA0 },
+            { scan_code = 0x3B, switch_layout = "gaming" },
+            { scan_code = 0x3C, set_base = "shift" },
+        ]
+        shift = [{ scan_code = 0x1E, characters = "A" }]
+
+        [layouts.gaming]
+        base_layer = "game"
+        [layouts.gaming.layers]
+        game = [
+            { scan_code = 0x1E, virtual_key = 0x25 },
+            { scan_code = 0x3B, switch_layout = "default" },
+        ]
+
+        [[rules]]
+        process = "vmconnect.exe"
+        passthrough = true
+        "#;
+
+    fn context(process_name: &str) -> ForegroundContext {
+        ForegroundContext {
+            process_name: process_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn switch_layout() {
+        let mut remapper = Remapper::new(Layouts::parse_toml(CONFIG).unwrap());
+
+        assert_eq!(
+            remapper.process(0x1E, false, 0),
+            Action::Character('a', CharacterOutput::default())
+        );
+        assert_eq!(
+            remapper.process(0x1E, true, 0),
+            Action::Character('a', CharacterOutput::default())
+        );
+
+        assert_eq!(remapper.process(0x3B, false, 0), Action::SwitchLayout(1));
+        // Key repeat and release do not switch again.
+        assert_eq!(remapper.process(0x3B, false, 0), Action::Ignore);
+        assert_eq!(remapper.process(0x3B, true, 0), Action::Ignore);
+        assert_eq!(remapper.layout_idx(), 1);
+        assert_eq!(remapper.process(0x1E, false, 0), Action::VirtualKey(0x25));
+        assert_eq!(remapper.process(0x1E, true, 0), Action::VirtualKey(0x25));
+
+        assert_eq!(remapper.process(0x3B, false, 0), Action::SwitchLayout(0));
+        assert_eq!(remapper.process(0x3B, true, 0), Action::Ignore);
+        assert_eq!(remapper.process(0x30, false, 0), Action::Forward);
+    }
+
+    #[test]
+    fn passthrough() {
+        let mut remapper = Remapper::new(Layouts::parse_toml(CONFIG).unwrap());
+
+        assert_eq!(
+            remapper.process(0x1E, false, 0),
+            Action::Character('a', CharacterOutput::default())
+        );
+        assert!(!remapper.update_foreground(&context("vmconnect.exe")));
+        assert_eq!(remapper.process(0x2A, false, 0), Action::Passthrough);
+        // Keys pressed before passthrough release with their action.
+        assert_eq!(
+            remapper.process(0x1E, true, 0),
+            Action::Character('a', CharacterOutput::default())
+        );
+        assert_eq!(remapper.process(0x2A, true, 0), Action::Passthrough);
+
+        remapper.update_foreground(&context("notepad.exe"));
+        assert_eq!(
+            remapper.process(0x1E, false, 0),
+            Action::Character('a', CharacterOutput::default())
+        );
+    }
+
+    #[test]
+    fn base_layer() {
+        let mut remapper = Remapper::new(Layouts::parse_toml(CONFIG).unwrap());
+        assert!(!remapper.base_layer_changed());
+
+        remapper.process(0x3C, false, 0);
+        remapper.process(0x3C, true, 0);
+        assert!(remapper.base_layer_changed());
+        assert!(!remapper.base_layer_changed());
+        assert_eq!(remapper.kb().base_layer(), "shift");
+
+        // The base layer survives reloading the configuration.
+        remapper.reload(Layouts::parse_toml(CONFIG).unwrap());
+        assert_eq!(remapper.kb().base_layer(), "shift");
+        assert!(remapper.set_base_layer("base"));
+        assert!(!remapper.set_base_layer("missing"));
+        assert!(!remapper.base_layer_changed());
+    }
+}
@@ -0,0 +1,276 @@
+//! Selection of the layout depending on the application in the foreground.
+
+use serde::{Deserialize, Serialize};
+
+use crate::layout::LayoutIdx;
+
+/// Properties of the foreground window that rules can match on.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForegroundContext {
+    /// File name of the process executable, e.g. `vmconnect.exe`.
+    pub process_name: String,
+
+    /// Window class name of the foreground window.
+    pub window_class: String,
+
+    /// Language identifier (LANGID) of the active input language of the
+    /// foreground window, e.g. `0x0407`.
+    pub language_id: u16,
+
+    /// Language tag of the active input language, e.g. `de-DE`.
+    pub language_tag: String,
+
+    /// Keyboard layout of the active input language, the low word of the
+    /// keyboard layout identifier (KLID), e.g. `0x0407` for German.
+    #[serde(default)]
+    pub layout_id: u16,
+}
+
+/// What to do while a rule matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleAction {
+    /// Use the layout.
+    Layout(LayoutIdx),
+
+    /// Forward all keys without remapping.
+    Passthrough,
+}
+
+/// Rule matching the foreground application. Conditions are compared case
+/// insensitive and all conditions must match. A rule without conditions
+/// matches any application.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub process_name: Option<String>,
+    pub window_class: Option<String>,
+
+    /// Language tag (`de-DE`), primary language (`de`) or hexadecimal
+    /// language identifier (`0x0407`) of the input language.
+    pub input_language: Option<String>,
+
+    pub action: RuleAction,
+}
+
+impl Rule {
+    fn matches(&self, context: &ForegroundContext) -> bool {
+        let matches = |condition: &Option<String>, value: &str| {
+            condition
+                .as_ref()
+                .is_none_or(|condition| condition.eq_ignore_ascii_case(value))
+        };
+        matches(&self.process_name, &context.process_name)
+            && matches(&self.window_class, &context.window_class)
+            && self.input_language.as_ref().is_none_or(|language| {
+                language_matches(language, context.language_id, &context.language_tag)
+            })
+    }
+}
+
+/// Matches an input language condition against a language identifier and tag.
+fn language_matches(condition: &str, language_id: u16, language_tag: &str) -> bool {
+    if let Some(hex) = condition.strip_prefix("0x") {
+        return u16::from_str_radix(hex, 16) == Ok(language_id);
+    }
+
+    if condition.contains('-') {
+        condition.eq_ignore_ascii_case(language_tag)
+    } else {
+        let primary_language = language_tag.split('-').next().unwrap();
+        condition.eq_ignore_ascii_case(primary_language)
+    }
+}
+
+/// Returns the action of the first rule matching `context`.
+pub fn evaluate(rules: &[Rule], context: &ForegroundContext) -> Option<RuleAction> {
+    rules
+        .iter()
+        .find(|rule| rule.matches(context))
+        .map(|rule| rule.action)
+}
+
+/// Keeps track of the active layout chosen by layout switching keys and rules.
+#[derive(Debug)]
+pub struct LayoutSelection {
+    /// Layout chosen with layout switching keys, used when no rule matches.
+    selected: LayoutIdx,
+
+    /// Action of the rule matching the current foreground application.
+    rule: Option<RuleAction>,
+}
+
+impl LayoutSelection {
+    pub fn new(default_layout: LayoutIdx) -> Self {
+        Self {
+            selected: default_layout,
+            rule: None,
+        }
+    }
+
+    /// Returns the active layout, or `None` when keys are passed through.
+    pub fn active(&self) -> Option<LayoutIdx> {
+        match self.rule {
+            Some(RuleAction::Layout(layout)) => Some(layout),
+            Some(RuleAction::Passthrough) => None,
+            None => Some(self.selected),
+        }
+    }
+
+    /// Switches the layout with a key. Overrides the rule of the current
+    /// foreground application until the foreground changes.
+    pub fn switch(&mut self, layout: LayoutIdx) {
+        self.selected = layout;
+        self.rule = None;
+    }
+
+    /// Evaluates the rules for a new foreground application.
+    ///
+    /// Returns `true` when the active layout changed.
+    pub fn update_foreground(&mut self, rules: &[Rule], context: &ForegroundContext) -> bool {
+        let active = self.active();
+        self.rule = evaluate(rules, context);
+        self.active() != active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(process_name: &str, window_class: &str) -> ForegroundContext {
+        ForegroundContext {
+            process_name: process_name.to_string(),
+            window_class: window_class.to_string(),
+            language_id: 0x0409,
+            language_tag: String::from("en-US"),
+            layout_id: 0x0409,
+        }
+    }
+
+    fn language(language_id: u16, language_tag: &str) -> ForegroundContext {
+        ForegroundContext {
+            language_id,
+            language_tag: language_tag.to_string(),
+            ..context("notepad.exe", "Notepad")
+        }
+    }
+
+    fn rule(
+        process_name: Option<&str>,
+        window_class: Option<&str>,
+        input_language: Option<&str>,
+        action: RuleAction,
+    ) -> Rule {
+        Rule {
+            process_name: process_name.map(String::from),
+            window_class: window_class.map(String::from),
+            input_language: input_language.map(String::from),
+            action,
+        }
+    }
+
+    fn rules() -> Vec<Rule> {
+        vec![
+            rule(Some("vmconnect.exe"), None, None, RuleAction::Passthrough),
+            rule(
+                Some("game.exe"),
+                Some("GameWindow"),
+                None,
+                RuleAction::Layout(1),
+            ),
+            rule(
+                None,
+                Some("ConsoleWindowClass"),
+                None,
+                RuleAction::Layout(2),
+            ),
+        ]
+    }
+
+    #[test]
+    fn evaluate_rules() {
+        let rules = rules();
+
+        assert_eq!(evaluate(&rules, &context("notepad.exe", "Notepad")), None);
+        assert_eq!(
+            evaluate(&rules, &context("VMConnect.EXE", "")),
+            Some(RuleAction::Passthrough)
+        );
+
+        // All conditions must match.
+        assert_eq!(
+            evaluate(&rules, &context("game.exe", "GameWindow")),
+            Some(RuleAction::Layout(1))
+        );
+        assert_eq!(evaluate(&rules, &context("game.exe", "Launcher")), None);
+
+        // First matching rule wins.
+        assert_eq!(
+            evaluate(&rules, &context("vmconnect.exe", "ConsoleWindowClass")),
+            Some(RuleAction::Passthrough)
+        );
+
+        // Rules without conditions act as fallback.
+        let mut rules = rules;
+        rules.push(rule(None, None, None, RuleAction::Layout(3)));
+        assert_eq!(
+            evaluate(&rules, &context("notepad.exe", "Notepad")),
+            Some(RuleAction::Layout(3))
+        );
+    }
+
+    #[test]
+    fn input_language() {
+        let rules = vec![
+            rule(None, None, Some("de-CH"), RuleAction::Layout(1)),
+            rule(None, None, Some("DE"), RuleAction::Layout(2)),
+            rule(None, None, Some("0x0409"), RuleAction::Layout(3)),
+        ];
+
+        assert_eq!(
+            evaluate(&rules, &language(0x0807, "de-CH")),
+            Some(RuleAction::Layout(1))
+        );
+        assert_eq!(
+            evaluate(&rules, &language(0x0407, "de-DE")),
+            Some(RuleAction::Layout(2))
+        );
+        assert_eq!(
+            evaluate(&rules, &language(0x0409, "en-US")),
+            Some(RuleAction::Layout(3))
+        );
+        assert_eq!(evaluate(&rules, &language(0x0809, "en-GB")), None);
+
+        // Combined with other conditions.
+        let rules = vec![rule(
+            Some("code.exe"),
+            None,
+            Some("fr"),
+            RuleAction::Layout(1),
+        )];
+        let mut context = language(0x040C, "fr-FR");
+        assert_eq!(evaluate(&rules, &context), None);
+        context.process_name = String::from("Code.exe");
+        assert_eq!(evaluate(&rules, &context), Some(RuleAction::Layout(1)));
+    }
+
+    #[test]
+    fn layout_selection() {
+        let rules = rules();
+        let mut selection = LayoutSelection::new(0);
+        assert_eq!(selection.active(), Some(0));
+
+        assert!(!selection.update_foreground(&rules, &context("notepad.exe", "Notepad")));
+        assert!(selection.update_foreground(&rules, &context("game.exe", "GameWindow")));
+        assert_eq!(selection.active(), Some(1));
+        assert!(selection.update_foreground(&rules, &context("vmconnect.exe", "")));
+        assert_eq!(selection.active(), None);
+
+        // Switching keys override the rule until the foreground changes.
+        selection.switch(2);
+        assert_eq!(selection.active(), Some(2));
+        assert!(!selection.update_foreground(&rules, &context("notepad.exe", "Notepad")));
+        assert_eq!(selection.active(), Some(2));
+        assert!(selection.update_foreground(&rules, &context("vmconnect.exe", "")));
+        assert_eq!(selection.active(), None);
+    }
+}
@@ -0,0 +1,393 @@
+//! Layout tests declared in the `[[tests]]` section of a configuration, run
+//! with the `test` command.
+
+use std::fmt::{Display, Write};
+
+use anyhow::{Context, Result, bail};
+
+use crate::layout::{CharacterOutput, LayoutIdx, Layouts, ScanCode};
+use crate::remapper::{Action, Remapper};
+
+/// Key sequence with the expected results.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutTest {
+    pub name: String,
+
+    /// Layout to start with, the default layout if not specified.
+    pub layout: Option<LayoutIdx>,
+
+    /// Key presses (`false`) and releases (`true`) in order.
+    pub keys: Vec<(ScanCode, bool)>,
+
+    /// Characters typed by all key presses.
+    pub types: Option<String>,
+
+    /// Action of each key press.
+    pub actions: Option<Vec<Action>>,
+
+    /// Active layer after the key sequence.
+    pub layer: Option<String>,
+}
+
+/// Scan codes of the keys on a US keyboard, usable as key names in tests.
+const KEY_NAMES: &[(&str, ScanCode)] = &[
+    ("esc", 0x01),
+    ("1", 0x02),
+    ("2", 0x03),
+    ("3", 0x04),
+    ("4", 0x05),
+    ("5", 0x06),
+    ("6", 0x07),
+    ("7", 0x08),
+    ("8", 0x09),
+    ("9", 0x0A),
+    ("0", 0x0B),
+    ("minus", 0x0C),
+    ("equal", 0x0D),
+    ("backspace", 0x0E),
+    ("tab", 0x0F),
+    ("q", 0x10),
+    ("w", 0x11),
+    ("e", 0x12),
+    ("r", 0x13),
+    ("t", 0x14),
+    ("y", 0x15),
+    ("u", 0x16),
+    ("i", 0x17),
+    ("o", 0x18),
+    ("p", 0x19),
+    ("bracketleft", 0x1A),
+    ("bracketright", 0x1B),
+    ("enter", 0x1C),
+    ("ctrl", 0x1D),
+    ("lctrl", 0x1D),
+    ("a", 0x1E),
+    ("s", 0x1F),
+    ("d", 0x20),
+    ("f", 0x21),
+    ("g", 0x22),
+    ("h", 0x23),
+    ("j", 0x24),
+    ("k", 0x25),
+    ("l", 0x26),
+    ("semicolon", 0x27),
+    ("quote", 0x28),
+    ("grave", 0x29),
+    ("shift", 0x2A),
+    ("lshift", 0x2A),
+    ("backslash", 0x2B),
+    ("z", 0x2C),
+    ("x", 0x2D),
+    ("c", 0x2E),
+    ("v", 0x2F),
+    ("b", 0x30),
+    ("n", 0x31),
+    ("m", 0x32),
+    ("comma", 0x33),
+    ("period", 0x34),
+    ("slash", 0x35),
+    ("rshift", 0xE036),
+    ("alt", 0x38),
+    ("lalt", 0x38),
+    ("space", 0x39),
+    ("capslock", 0x3A),
+    ("f1", 0x3B),
+    ("f2", 0x3C),
+    ("f3", 0x3D),
+    ("f4", 0x3E),
+    ("f5", 0x3F),
+    ("f6", 0x40),
+    ("f7", 0x41),
+    ("f8", 0x42),
+    ("f9", 0x43),
+    ("f10", 0x44),
+    ("numlock", 0xE045),
+    ("scrolllock", 0x46),
+    ("iso", 0x56),
+    ("f11", 0x57),
+    ("f12", 0x58),
+    ("rctrl", 0xE01D),
+    ("ralt", 0xE038),
+    ("home", 0xE047),
+    ("up", 0xE048),
+    ("pageup", 0xE049),
+    ("left", 0xE04B),
+    ("right", 0xE04D),
+    ("end", 0xE04F),
+    ("down", 0xE050),
+    ("pagedown", 0xE051),
+    ("insert", 0xE052),
+    ("delete", 0xE053),
+    ("lwin", 0xE05B),
+    ("rwin", 0xE05C),
+    ("menu", 0xE05D),
+];
+
+/// Parses a key sequence like `shift+a capslock a`.
+///
+/// Keys joined with `+` are pressed in order and released in reverse order.
+/// Keys are hexadecimal scan codes, names resolved by `key` or the names of
+/// [`KEY_NAMES`] in any case.
+pub fn parse_keys(
+    keys: &str,
+    key: impl Fn(&str) -> Option<ScanCode>,
+) -> Result<Vec<(ScanCode, bool)>> {
+    let mut events = Vec::new();
+    for chord in keys.split([' ', '\t', '\n', ',']).filter(|s| !s.is_empty()) {
+        let scan_codes = chord
+            .split('+')
+            .map(|name| {
+                if let Some(hex) = name.strip_prefix("0x") {
+                    return ScanCode::from_str_radix(hex, 16)
+                        .with_context(|| format!("invalid scan code `{name}`"));
+                }
+                key(name)
+                    .or_else(|| {
+                        KEY_NAMES
+                            .iter()
+                            .find(|(key_name, _)| key_name.eq_ignore_ascii_case(name))
+                            .map(|(_, scan_code)| *scan_code)
+                    })
+                    .with_context(|| format!("key `{name}` is not defined"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        events.extend(scan_codes.iter().map(|scan_code| (*scan_code, false)));
+        events.extend(scan_codes.iter().rev().map(|scan_code| (*scan_code, true)));
+    }
+    if events.is_empty() {
+        bail!("no keys to press");
+    }
+    Ok(events)
+}
+
+/// Parses an expected action: the character itself, `vk:<virtual key>`,
+/// `layout:<name>`, `forward` or `ignore`.
+pub fn parse_action(action: &str, layout: impl Fn(&str) -> Option<LayoutIdx>) -> Result<Action> {
+    let mut chars = action.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(Action::Character(c, CharacterOutput::default()));
+    }
+
+    match action.split_once(':') {
+        None if action == "forward" => Ok(Action::Forward),
+        None if action == "ignore" => Ok(Action::Ignore),
+        Some(("vk", virtual_key)) => {
+            let hex = virtual_key.strip_prefix("0x").unwrap_or(virtual_key);
+            let virtual_key = u8::from_str_radix(hex, 16)
+                .with_context(|| format!("invalid virtual key `{virtual_key}`"))?;
+            Ok(Action::VirtualKey(virtual_key))
+        }
+        Some(("layout", name)) => match layout(name) {
+            Some(layout_idx) => Ok(Action::SwitchLayout(layout_idx)),
+            None => bail!("layout `{name}` not found"),
+        },
+        _ => bail!(
+            "invalid action `{action}`, expected a character, `vk:<virtual key>`, \
+             `layout:<name>`, `forward` or `ignore`"
+        ),
+    }
+}
+
+/// Formats actions in the notation of [`parse_action()`].
+fn format_actions<'a>(layouts: &'a Layouts, actions: &'a [Action]) -> impl Display + 'a {
+    struct Actions<'a>(&'a Layouts, &'a [Action]);
+
+    impl Display for Actions<'_> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("[")?;
+            for (i, action) in self.1.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                match action {
+                    Action::Passthrough => f.write_str("passthrough")?,
+                    Action::Forward => f.write_str("forward")?,
+                    Action::Ignore => f.write_str("ignore")?,
+                    Action::Character(c, _) => write!(f, "{c:?}")?,
+                    Action::VirtualKey(vk) => write!(f, "vk:{vk:#04X}")?,
+                    Action::SwitchLayout(layout) => {
+                        write!(f, "layout:{}", self.0.name(*layout))?;
+                    }
+                }
+            }
+            f.write_str("]")
+        }
+    }
+
+    Actions(layouts, actions)
+}
+
+/// Runs all tests of the configuration with a fresh [`Remapper`] each.
+///
+/// Returns the report and the number of failed tests.
+pub fn run(layouts: &Layouts) -> (String, usize) {
+    let mut report = String::new();
+    let mut failures = 0;
+    for test in layouts.tests() {
+        let mut remapper = Remapper::new(layouts.clone());
+        if let Some(layout_idx) = test.layout {
+            remapper.switch_layout(layout_idx);
+        }
+
+        let mut typed = String::new();
+        let mut actions = Vec::new();
+        for (time, &(scan_code, up)) in (0..).step_by(10).zip(&test.keys) {
+            let mut action = remapper.process(scan_code, up, time);
+            if up {
+                continue;
+            }
+            if let Action::Character(c, _) = action {
+                // Tests expect characters regardless of how they are sent.
+                action = Action::Character(c, CharacterOutput::default());
+                typed.push(c);
+            }
+            actions.push(action);
+        }
+
+        let mut errors = Vec::new();
+        if let Some(types) = &test.types
+            && typed != *types
+        {
+            errors.push(format!("typed {typed:?}, expected {types:?}"));
+        }
+        if let Some(expected) = &test.actions
+            && actions != *expected
+        {
+            errors.push(format!(
+                "actions {}, expected {}",
+                format_actions(layouts, &actions),
+                format_actions(layouts, expected)
+            ));
+        }
+        let active_layer = remapper.kb().active_layer();
+        if let Some(layer) = &test.layer
+            && active_layer != layer
+        {
+            errors.push(format!("layer `{active_layer}`, expected `{layer}`"));
+        }
+
+        if errors.is_empty() {
+            writeln!(report, "ok: {}", test.name).unwrap();
+        } else {
+            failures += 1;
+            writeln!(report, "FAILED: {}", test.name).unwrap();
+            for error in errors {
+                writeln!(report, "  {error}").unwrap();
+            }
+        }
+    }
+    (report, failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        base_layer = "base"
+
+        [define.keys]
+        nav = 0x56
+
+        [layers]
+        base = [
+            { scan_code = 0x1E, characters = "a" },
+            { scan_code = 0x2A, layer = "shift", virtual_key = 0xA0 },
+            { scan_code = 0x3A, lock = "shift" },
+            { scan_code = 0x3B, switch_layout = "gaming" },
+        ]
+        shift = [{ scan_code = 0x1E, characters = "A" }]
+
+        [layouts.gaming]
+        base_layer = "game"
+        [layouts.gaming.layers]
+        game = [{ scan_code = 0x1E, virtual_key = 0x25 }]
+
+        [[tests]]
+        name = "Shift+a types A"
+        keys = "shift+a"
+        types = "A"
+
+        [[tests]]
+        name = "caps lock"
+        keys = "CapsLock, a, CapsLock, a"
+        types = "Aa"
+        actions = ["ignore", "A", "ignore", "a"]
+        layer = "base"
+
+        [[tests]]
+        name = "gaming"
+        layout = "gaming"
+        keys = "a nav"
+        actions = ["vk:0x25", "forward"]
+
+        [[tests]]
+        name = "wrong"
+        keys = "f1 a"
+        types = "b"
+        actions = ["layout:default", "a"]
+        layer = "shift"
+        "#;
+
+    #[test]
+    fn keys() {
+        let key = |name: &str| (name == "nav").then_some(0x56);
+        assert_eq!(
+            parse_keys("Shift+A, 0x56\tnav", key).unwrap(),
+            [
+                (0x2A, false),
+                (0x1E, false),
+                (0x1E, true),
+                (0x2A, true),
+                (0x56, false),
+                (0x56, true),
+                (0x56, false),
+                (0x56, true),
+            ]
+        );
+
+        let error = |keys| parse_keys(keys, key).unwrap_err().to_string();
+        assert_eq!(error("shift+hyper"), "key `hyper` is not defined");
+        assert_eq!(error("0xZZ"), "invalid scan code `0xZZ`");
+        assert_eq!(error(" "), "no keys to press");
+    }
+
+    #[test]
+    fn actions() {
+        let layout = |name: &str| (name == "gaming").then_some(1);
+        let action = |action| parse_action(action, layout);
+        assert_eq!(
+            action("ä").unwrap(),
+            Action::Character('ä', CharacterOutput::default())
+        );
+        assert_eq!(action("vk:0x25").unwrap(), Action::VirtualKey(0x25));
+        assert_eq!(action("layout:gaming").unwrap(), Action::SwitchLayout(1));
+        assert_eq!(action("forward").unwrap(), Action::Forward);
+        assert_eq!(
+            action("layout:qwerty").unwrap_err().to_string(),
+            "layout `qwerty` not found"
+        );
+        assert!(action("ab").is_err());
+    }
+
+    #[test]
+    fn run_tests() {
+        let layouts = Layouts::parse_toml(CONFIG).unwrap();
+        assert_eq!(
+            run(&layouts),
+            (
+                String::from(
+                    r#"ok: Shift+a types A
+ok: caps lock
+ok: gaming
+FAILED: wrong
+  typed "", expected "b"
+  actions [layout:gaming, vk:0x25], expected [layout:default, 'a']
+  layer `game`, expected `shift`
+"#
+                ),
+                1
+            )
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Headless simulation of key events for the `simulate` command.
+
+use std::fmt::Write;
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::layout::{Layouts, ScanCode};
+use crate::remapper::Remapper;
+
+/// Step of a simulation script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptEvent {
+    /// Key press or release at `time` in milliseconds.
+    Key {
+        scan_code: ScanCode,
+        up: bool,
+        time: u32,
+    },
+
+    /// No key event until `time`, e.g. to let layer locks time out.
+    Idle { time: u32 },
+}
+
+/// Key event as JSON object, compatible with the fields of the key events of
+/// the keyboard hook.
+#[derive(Debug, Deserialize)]
+struct JsonKeyEvent {
+    scan_code: ScanCode,
+    up: bool,
+    time: u32,
+}
+
+/// Parses a simulation script, either as text or as JSON Lines.
+///
+/// The text format consists of hexadecimal scan codes prefixed with `+` for a
+/// key press or `-` for a key release, e.g. `+2A +1E -1E -2A`. `<n>ms` waits
+/// before the next event. Everything after `#` up to the end of the line is a
+/// comment.
+///
+/// JSON Lines contain one key event per line, e.g.
+/// `{ "scan_code": 42, "up": false, "time": 1000 }`.
+pub fn parse_script(script: &str) -> Result<Vec<ScriptEvent>> {
+    if script.trim_start().starts_with('{') {
+        parse_json_lines(script)
+    } else {
+        parse_text(script)
+    }
+}
+
+fn parse_text(script: &str) -> Result<Vec<ScriptEvent>> {
+    let mut events = Vec::new();
+    let mut time: u32 = 0;
+    for (line_idx, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap();
+        for token in line.split_whitespace() {
+            let context = || format!("line {}: invalid key event `{token}`", line_idx + 1);
+
+            if let Some(wait_ms) = token.strip_suffix("ms") {
+                let wait_ms: u32 = wait_ms.parse().with_context(context)?;
+                time = time.wrapping_add(wait_ms);
+                events.push(ScriptEvent::Idle { time });
+                continue;
+            }
+
+            let (up, scan_code) = match token.split_at_checked(1) {
+                Some(("+", scan_code)) => (false, scan_code),
+                Some(("-", scan_code)) => (true, scan_code),
+                _ => bail!(
+                    "{}, expected `+<scan code>`, `-<scan code>` or `<n>ms`",
+                    context()
+                ),
+            };
+            let scan_code = scan_code.strip_prefix("0x").unwrap_or(scan_code);
+            let scan_code = ScanCode::from_str_radix(scan_code, 16).with_context(context)?;
+            events.push(ScriptEvent::Key {
+                scan_code,
+                up,
+                time,
+            });
+        }
+    }
+    Ok(events)
+}
+
+fn parse_json_lines(script: &str) -> Result<Vec<ScriptEvent>> {
+    let mut events = Vec::new();
+    for (line_idx, line) in script.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: JsonKeyEvent = serde_json::from_str(line)
+            .with_context(|| format!("line {}: invalid key event", line_idx + 1))?;
+        events.push(ScriptEvent::Key {
+            scan_code: event.scan_code,
+            up: event.up,
+            time: event.time,
+        });
+    }
+    Ok(events)
+}
+
+/// Runs the events through the same [`Remapper`] as the keyboard hook and
+/// describes the action and the active layer after each event.
+pub fn simulate(layouts: Layouts, events: &[ScriptEvent]) -> String {
+    let mut remapper = Remapper::new(layouts);
+    let mut out = String::new();
+    for event in events {
+        let time = match *event {
+            ScriptEvent::Key { time, .. } | ScriptEvent::Idle { time } => time,
+        };
+
+        // The keyboard hook unlocks layers on a timer, before the next event.
+        if let Some(deadline) = remapper.lock_deadline()
+            && remapper.expire_locks(time)
+        {
+            writeln!(
+                out,
+                "@{deadline}: layer lock timed out, layer `{}`",
+                remapper.kb().active_layer()
+            )
+            .unwrap();
+        }
+
+        let ScriptEvent::Key {
+            scan_code,
+            up,
+            time,
+        } = *event
+        else {
+            continue;
+        };
+
+        let action = remapper.process(scan_code, up, time);
+        writeln!(
+            out,
+            "{}{scan_code:#06X} @{time}: {}, layer `{}`",
+            if up { '-' } else { '+' },
+            remapper.describe(action),
+            remapper.kb().active_layer()
+        )
+        .unwrap();
+
+        if remapper.base_layer_changed() {
+            let base_layer = remapper.kb().base_layer();
+            writeln!(out, "base layer changed to `{base_layer}`").unwrap();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(scan_code: ScanCode, up: bool, time: u32) -> ScriptEvent {
+        ScriptEvent::Key {
+            scan_code,
+            up,
+            time,
+        }
+    }
+
+    #[test]
+    fn script() {
+        let events = parse_script("+2A +0x1E # shift + a\n-1E 50ms\t-2A\n").unwrap();
+        assert_eq!(
+            events,
+            [
+                key(0x2A, false, 0),
+                key(0x1E, false, 0),
+                key(0x1E, true, 0),
+                ScriptEvent::Idle { time: 50 },
+                key(0x2A, true, 50),
+            ]
+        );
+
+        let events = parse_script(
+            r#"{ "scan_code": 42, "up": false, "time": 1000 }
+
+            { "scan_code": 42, "up": true, "time": 1100, "key": { "VirtualKey": 160 } }"#,
+        )
+        .unwrap();
+        assert_eq!(events, [key(0x2A, false, 1000), key(0x2A, true, 1100)]);
+
+        let error = |script| format!("{:#}", parse_script(script).unwrap_err());
+        assert_eq!(
+            error("+2A\n2A"),
+            "line 2: invalid key event `2A`, expected `+<scan code>`, `-<scan code>` or `<n>ms`"
+        );
+        assert_eq!(
+            error("+XY"),
+            "line 1: invalid key event `+XY`: invalid digit found in string"
+        );
+        assert!(error("{ \"scan_code\": 42 }").starts_with("line 1: invalid key event: missing"));
+    }
+
+    #[test]
+    fn simulate_script() {
+        let layouts = Layouts::parse_toml(
+            r#"
+            base_layer = "base"
+
+            [layers]
+            base = [
+                { scan_code = 0x1E, characters = "a" },
+                { scan_code = 0x2A, layer = "shift", virtual_key = 0xA0 },
+                { scan_code = 0x3A, lock = "shift" },
+                { scan_code = 0x3B, switch_layout = "gaming" },
+            ]
+            shift = { lock_timeout_ms = 1000, mappings = [{ scan_code = 0x1E, characters = "A" }] }
+
+            [layouts.gaming]
+            base_layer = "game"
+            [layouts.gaming.layers]
+            game = [{ scan_code = 0x1E, virtual_key = 0x25 }]
+            "#,
+        )
+        .unwrap();
+
+        let events =
+            parse_script("+2A +1E -1E -2A +3A -3A 500ms +1E -1E 1500ms +3B -3B +1E").unwrap();
+        assert_eq!(
+            simulate(layouts, &events),
+            "\
++0x002A @0: remapped to virtual key 0xA0, layer `shift`
++0x001E @0: remapped to `A`, layer `shift`
+-0x001E @0: remapped to `A`, layer `shift`
+-0x002A @0: remapped to virtual key 0xA0, layer `base`
++0x003A @0: ignored, layer `shift`
+-0x003A @0: ignored, layer `shift`
++0x001E @500: remapped to `A`, layer `shift`
+-0x001E @500: remapped to `A`, layer `shift`
+@1500: layer lock timed out, layer `base`
++0x003B @2000: switched to layout `gaming`, layer `game`
+-0x003B @2000: ignored, layer `game`
++0x001E @2000: remapped to virtual key 0x25, layer `game`
+"
+        );
+    }
+}
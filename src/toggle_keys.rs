@@ -0,0 +1,120 @@
+//! Synchronization of the host toggle keys (caps lock, num lock, scroll lock)
+//! with the locked layers of the virtual keyboard.
+
+use serde::{Deserialize, Serialize};
+
+/// Keys with a toggle state and indicator light.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToggleKey {
+    /// Caps lock
+    Caps,
+    /// Num lock
+    Num,
+    /// Scroll lock
+    Scroll,
+}
+
+impl ToggleKey {
+    pub const ALL: [ToggleKey; 3] = [Self::Caps, Self::Num, Self::Scroll];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Caps => "caps lock",
+            Self::Num => "num lock",
+            Self::Scroll => "scroll lock",
+        }
+    }
+
+    /// Reference: <https://docs.microsoft.com/en-us/windows/win32/inputdev/virtual-key-codes>
+    pub fn virtual_key(self) -> u8 {
+        match self {
+            Self::Caps => 0x14,
+            Self::Num => 0x90,
+            Self::Scroll => 0x91,
+        }
+    }
+}
+
+/// Access to the toggle key states of the host system.
+pub trait ToggleKeyHost {
+    fn is_enabled(&self, key: ToggleKey) -> bool;
+
+    /// Toggles the state of `key`, e.g. by injecting a key press and release.
+    fn toggle(&mut self, key: ToggleKey);
+}
+
+/// Toggles all keys whose host state differs from the `desired` state.
+/// Keys without a desired state are left as they are.
+///
+/// We can get out of sync when a toggle key is pressed in elevated context but
+/// our program is not elevated. In which case we want to toggle the state back
+/// to what we expect it to be.
+///
+/// Returns the keys which were toggled.
+pub fn sync(
+    host: &mut impl ToggleKeyHost,
+    desired: impl Fn(ToggleKey) -> Option<bool>,
+) -> Vec<ToggleKey> {
+    let mut toggled = Vec::new();
+    for key in ToggleKey::ALL {
+        if desired(key).is_some_and(|enabled| enabled != host.is_enabled(key)) {
+            host.toggle(key);
+            toggled.push(key);
+        }
+    }
+    toggled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeHost {
+        enabled: Vec<ToggleKey>,
+        toggles: usize,
+    }
+
+    impl ToggleKeyHost for FakeHost {
+        fn is_enabled(&self, key: ToggleKey) -> bool {
+            self.enabled.contains(&key)
+        }
+
+        fn toggle(&mut self, key: ToggleKey) {
+            self.toggles += 1;
+            if let Some(idx) = self.enabled.iter().position(|k| *k == key) {
+                self.enabled.remove(idx);
+            } else {
+                self.enabled.push(key);
+            }
+        }
+    }
+
+    #[test]
+    fn sync_toggle_keys() {
+        let mut host = FakeHost {
+            enabled: vec![ToggleKey::Num],
+            ..Default::default()
+        };
+
+        let all = |key| Some(key == ToggleKey::Caps);
+
+        // Keys without desired state are not touched.
+        assert_eq!(sync(&mut host, |_| None), []);
+        assert_eq!(host.toggles, 0);
+
+        // Caps lock enabled, num lock disabled.
+        assert_eq!(sync(&mut host, all), [ToggleKey::Caps, ToggleKey::Num]);
+        assert_eq!(host.enabled, [ToggleKey::Caps]);
+
+        // Already in sync.
+        assert_eq!(sync(&mut host, all), []);
+        assert_eq!(host.toggles, 2);
+
+        // Externally toggled scroll lock is reverted.
+        host.toggle(ToggleKey::Scroll);
+        assert_eq!(sync(&mut host, all), [ToggleKey::Scroll]);
+        assert_eq!(host.enabled, [ToggleKey::Caps]);
+    }
+}
@@ -1,15 +1,21 @@
 //! Remapping and layer switching logic.
 
-use crate::layout::{KeyAction, LayerIdx, Layout, ScanCode};
+use crate::layout::{Fallthrough, KeyAction, LayerIdx, LayerLock, Layout, ScanCode};
+use crate::toggle_keys::ToggleKey;
 
+/// Base layer of the configuration, until changed by a `set_base` key.
 const BASE_LAYER: LayerIdx = 0;
 
 /// Collection of virtual keyboard layers and logic to switch between them
 /// depending on which modifier keys are pressed.
 #[derive(Debug)]
 pub struct VirtualKeyboard {
-    /// Layer used when no modifier keys are pressed.
-    locked_layer: LayerIdx,
+    /// Stack of locked layers.
+    ///
+    /// The first entry is always the base layer, which is changed with
+    /// [`LayerLock::SetBase`] and survives [`VirtualKeyboard::reset()`].
+    /// The last element is the layer used when no modifier keys are pressed.
+    locks: Vec<LayerIdx>,
 
     /// Keeps track of layer activations over time.
     ///
@@ -23,6 +29,10 @@ pub struct VirtualKeyboard {
     /// a modifier or lock key is pressed or released.
     pressed_keys: Vec<(ScanCode, Option<KeyAction>)>,
 
+    /// Time of the last key event in milliseconds, to unlock layers with a
+    /// lock timeout after inactivity.
+    last_key_time: u32,
+
     /// Immutable information about the layout.
     layout: Layout,
 }
@@ -31,50 +41,143 @@ impl VirtualKeyboard {
     /// Create a new virtual keyboard with `layout`.
     pub fn new(layout: Layout) -> Self {
         Self {
-            locked_layer: BASE_LAYER,
+            locks: vec![BASE_LAYER],
             layer_history: vec![BASE_LAYER],
             pressed_keys: Vec::new(),
+            last_key_time: 0,
             layout,
         }
     }
 
     pub fn reset(&mut self) {
-        self.locked_layer = BASE_LAYER;
-        self.layer_history = vec![BASE_LAYER];
+        self.locks.truncate(1);
+        self.layer_history = self.locks.clone();
         self.pressed_keys.clear();
     }
 
+    /// Replaces the layout and starts over on its base layer.
+    ///
+    /// Keys pressed before the switch keep their action until released.
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+        self.locks = vec![BASE_LAYER];
+        self.layer_history = vec![BASE_LAYER];
+        self.update_layer_history();
+    }
+
+    /// Returns `true` if the key is held down, i.e. the next press is a repeat.
+    pub fn is_pressed(&self, scan_code: ScanCode) -> bool {
+        self.pressed_keys.iter().any(|(sc, _)| *sc == scan_code)
+    }
+
     fn active_layer_idx(&self) -> LayerIdx {
         *self.layer_history.last().unwrap()
     }
 
-    #[allow(unused)]
+    fn locked_layer_idx(&self) -> LayerIdx {
+        *self.locks.last().unwrap()
+    }
+
     pub fn active_layer(&self) -> &str {
         self.layout.layer_name(self.active_layer_idx())
     }
 
     #[allow(unused)]
     pub fn locked_layer(&self) -> &str {
-        self.layout.layer_name(self.locked_layer)
+        self.layout.layer_name(self.locked_layer_idx())
+    }
+
+    pub fn base_layer(&self) -> &str {
+        self.layout.layer_name(self.locks[0])
+    }
+
+    /// Changes the base layer by name. Returns `false` if there is no such layer.
+    pub fn set_base_layer(&mut self, name: &str) -> bool {
+        let Some(layer) = self.layout.layer_idx(name) else {
+            return false;
+        };
+        self.set_base_layer_idx(layer);
+        true
     }
 
-    pub fn caps_lock_enabled(&self) -> bool {
-        matches!(self.layout.caps_lock_layer(), Some(layer) if self.locked_layer == layer)
+    fn set_base_layer_idx(&mut self, layer: LayerIdx) {
+        // Layers locked above the base layer stay locked, unless it is the
+        // new base layer itself.
+        self.locks.remove(0);
+        self.locks.retain(|l| *l != layer);
+        self.locks.insert(0, layer);
+        self.layer_history = self.locks.clone();
+    }
+
+    /// Returns the expected state of a toggle key, enabled when its layer is
+    /// locked. Returns `None` when the key is not tied to any layer.
+    ///
+    /// Caps lock is always disabled when not tied to a layer.
+    pub fn toggle_key_enabled(&self, key: ToggleKey) -> Option<bool> {
+        match self.layout.toggle_layer(key) {
+            Some(layer) => Some(self.locks.contains(&layer)),
+            None if key == ToggleKey::Caps => Some(false),
+            None => None,
+        }
+    }
+
+    /// Returns the time at which the locked layers time out, or `None` when no
+    /// locked layer has a lock timeout. The base layer never times out.
+    pub fn lock_deadline(&self) -> Option<u32> {
+        self.locks[1..]
+            .iter()
+            .filter_map(|layer| self.layout.lock_timeout(*layer))
+            .min()
+            .map(|timeout_ms| self.last_key_time.wrapping_add(timeout_ms))
+    }
+
+    /// Unlocks all layers when the lock timeout has expired at `time`.
+    ///
+    /// Returns `true` when layers were unlocked.
+    pub fn expire_locks(&mut self, time: u32) -> bool {
+        let expired = self.lock_deadline().is_some_and(|deadline| {
+            // Wrapping arithmetic because the tick count overflows after 49 days.
+            time.wrapping_sub(deadline) as i32 >= 0
+        });
+        if expired {
+            self.locks.truncate(1);
+            self.rewind_layer_history();
+        }
+        expired
+    }
+
+    /// Records the time of a key event. Call before pressing or releasing the
+    /// key so that an expired lock timeout is applied first.
+    pub fn update_time(&mut self, time: u32) {
+        self.expire_locks(time);
+        self.last_key_time = time;
     }
 
     /// Returns the layer activated by the currently pressed modifier keys.
     fn find_layer_activation(&self, starting_layer: LayerIdx) -> LayerIdx {
         let mut layer = starting_layer;
+        let mut active_layers = vec![starting_layer];
         for (scan_code, _) in &self.pressed_keys {
             if let Some(target_layer) = self.layout.layer_modifier(layer, *scan_code) {
                 layer = target_layer;
+                active_layers.push(target_layer);
+            }
+
+            // Modifiers of the starting layer count as active independent of
+            // the press order so that conditional layers can be resolved.
+            if let Some(target_layer) = self.layout.layer_modifier(starting_layer, *scan_code) {
+                active_layers.push(target_layer);
             }
         }
-        layer
+
+        self.layout
+            .conditional_layer(|layer| active_layers.contains(&layer))
+            .unwrap_or(layer)
     }
 
     fn update_layer_history(&mut self) {
-        let new_active_layer = self.find_layer_activation(self.locked_layer);
+        let locked_layer = self.locked_layer_idx();
+        let new_active_layer = self.find_layer_activation(locked_layer);
 
         // Check if the active layer is in the history already.
         // This usually happens when a modifier key is released and we go back
@@ -86,7 +189,7 @@ impl VirtualKeyboard {
                 break;
             }
 
-            if self.layer_history[idx] == self.locked_layer {
+            if self.layer_history[idx] == locked_layer {
                 break;
             }
         }
@@ -102,13 +205,79 @@ impl VirtualKeyboard {
         }
     }
 
+    /// Updates the stack of locked layers when a lock key is pressed.
+    ///
+    /// Transitions:
+    /// * `Toggle` locks a layer that is not locked yet on top of the stack.
+    ///   The top most locked layer is unlocked when the same lock key is
+    ///   pressed from the layer below it, e.g. caps lock on the base layer.
+    /// * `Replace` replaces the top most locked layer, or locks the layer if
+    ///   only the base layer is active.
+    /// * `Clear` unlocks all layers.
+    /// * `SetBase` replaces the base layer at the bottom of the stack.
+    fn update_locks(&mut self, scan_code: ScanCode) {
+        if let [.., below, top] = self.locks[..] {
+            let layer_from_below = self.find_layer_activation(below);
+            if self.layout.layer_lock(layer_from_below, scan_code) == Some(LayerLock::Toggle(top)) {
+                self.locks.pop();
+                self.rewind_layer_history();
+                return;
+            }
+        }
+
+        match self.layout.layer_lock(self.active_layer_idx(), scan_code) {
+            Some(LayerLock::Toggle(layer)) => self.lock_layer(layer),
+            Some(LayerLock::Replace(layer)) => {
+                if self.locks.len() > 1 {
+                    self.locks.pop();
+                }
+                self.lock_layer(layer);
+            }
+            Some(LayerLock::Clear) => {
+                self.locks.truncate(1);
+                self.rewind_layer_history();
+            }
+            Some(LayerLock::SetBase(layer)) => self.set_base_layer_idx(layer),
+            None => {}
+        }
+    }
+
+    /// Locks `layer` on top of the stack unless it is already locked.
     pub fn lock_layer(&mut self, layer: LayerIdx) {
-        // Jump back in history if this layer was locked before.
-        if let Some(idx) = self.layer_history.iter().position(|l| *l == layer) {
-            self.layer_history.drain(idx + 1..);
+        if !self.locks.contains(&layer) {
+            self.locks.push(layer);
+            self.rewind_layer_history();
         }
+    }
 
-        self.locked_layer = layer;
+    /// Jumps back in the layer history to the top most locked layer after
+    /// the locks have changed.
+    fn rewind_layer_history(&mut self) {
+        for (lock_idx, lock) in self.locks.iter().enumerate().rev() {
+            if let Some(idx) = self.layer_history.iter().rposition(|l| l == lock) {
+                self.layer_history.truncate(idx + 1);
+                self.layer_history.extend(&self.locks[lock_idx + 1..]);
+                return;
+            }
+        }
+    }
+
+    /// Get the key action from the current layer. If the key is not available on
+    /// the current layer, check the previous layer for transparent layers.
+    /// Repeat until a action was found or we run out of layers.
+    fn find_action(&self, scan_code: ScanCode) -> Option<KeyAction> {
+        for layer in self.layer_history.iter().rev() {
+            if let Some(action) = self.layout.action(*layer, scan_code) {
+                return Some(action);
+            }
+
+            match self.layout.fallthrough(*layer) {
+                Fallthrough::Transparent => {}
+                Fallthrough::Opaque => return Some(KeyAction::Ignore),
+                Fallthrough::Passthrough => return None,
+            }
+        }
+        None
     }
 
     fn take_pressed(&mut self, scan_code: ScanCode) -> Option<Option<KeyAction>> {
@@ -132,27 +301,9 @@ impl VirtualKeyboard {
             return action;
         }
 
-        // Get the key action from the current layer. If the key is not available on
-        // the current layer, check the previous layer. Repeat until a action was
-        // found or we run out of layers.
-        let action = self
-            .layer_history
-            .iter()
-            .rev()
-            .find_map(|layer| self.layout.action(*layer, scan_code));
+        let action = self.find_action(scan_code);
 
-        if self.locked_layer == BASE_LAYER {
-            if let Some(target_layer) = self.layout.layer_lock(self.active_layer_idx(), scan_code) {
-                self.lock_layer(target_layer);
-            }
-        } else {
-            // Try to unlock a previously locked layer
-            let active_layer_from_base = self.find_layer_activation(BASE_LAYER);
-            let layer_to_lock = self.layout.layer_lock(active_layer_from_base, scan_code);
-            if layer_to_lock == Some(self.locked_layer) {
-                self.lock_layer(BASE_LAYER);
-            }
-        }
+        self.update_locks(scan_code);
 
         self.pressed_keys.push((scan_code, action));
         self.update_layer_history();
@@ -182,10 +333,14 @@ impl VirtualKeyboard {
 
 #[cfg(test)]
 mod tests {
-    use crate::layout::{KeyAction::*, LayoutBuilder};
+    use crate::layout::{CharacterOutput, KeyAction::*, LayoutBuilder};
 
     use super::*;
 
+    fn chr(c: char) -> KeyAction {
+        Character(c, CharacterOutput::default())
+    }
+
     #[test]
     fn layer_activation() {
         let mut layout = LayoutBuilder::new();
@@ -197,70 +352,70 @@ mod tests {
         layout.add_key(0x11, base, Ignore);
         layout.add_modifier(0x12, base, b);
         layout.add_key(0x12, base, Ignore);
-        layout.add_key(0x20, base, Character('0'));
+        layout.add_key(0x20, base, chr('0'));
         layout.add_modifier(0x12, a, c);
         layout.add_key(0x12, a, Ignore);
-        layout.add_key(0x20, a, Character('1'));
-        layout.add_key(0x20, b, Character('2'));
-        layout.add_key(0x20, c, Character('3'));
+        layout.add_key(0x20, a, chr('1'));
+        layout.add_key(0x20, b, chr('2'));
+        layout.add_key(0x20, c, chr('3'));
 
         let mut kb = VirtualKeyboard::new(layout.build());
 
         // L0
-        assert_eq!(kb.press_key(0x20), Some(Character('0')));
-        assert_eq!(kb.release_key(0x20), Some(Character('0')));
+        assert_eq!(kb.press_key(0x20), Some(chr('0')));
+        assert_eq!(kb.release_key(0x20), Some(chr('0')));
 
         // L1
         assert_eq!(kb.press_key(0x11), Some(Ignore));
-        assert_eq!(kb.press_key(0x20), Some(Character('1')));
-        assert_eq!(kb.release_key(0x20), Some(Character('1')));
+        assert_eq!(kb.press_key(0x20), Some(chr('1')));
+        assert_eq!(kb.release_key(0x20), Some(chr('1')));
         assert_eq!(kb.release_key(0x11), Some(Ignore));
-        assert_eq!(kb.press_key(0x20), Some(Character('0')));
-        assert_eq!(kb.release_key(0x20), Some(Character('0')));
+        assert_eq!(kb.press_key(0x20), Some(chr('0')));
+        assert_eq!(kb.release_key(0x20), Some(chr('0')));
 
         // L2
         assert_eq!(kb.press_key(0x12), Some(Ignore));
-        assert_eq!(kb.press_key(0x20), Some(Character('2')));
-        assert_eq!(kb.release_key(0x20), Some(Character('2')));
+        assert_eq!(kb.press_key(0x20), Some(chr('2')));
+        assert_eq!(kb.release_key(0x20), Some(chr('2')));
         assert_eq!(kb.release_key(0x12), Some(Ignore));
-        assert_eq!(kb.press_key(0x20), Some(Character('0')));
-        assert_eq!(kb.release_key(0x20), Some(Character('0')));
+        assert_eq!(kb.press_key(0x20), Some(chr('0')));
+        assert_eq!(kb.release_key(0x20), Some(chr('0')));
 
         // L1 -> L3 -> L2
         assert_eq!(kb.press_key(0x11), Some(Ignore));
-        assert_eq!(kb.press_key(0x20), Some(Character('1')));
-        assert_eq!(kb.release_key(0x20), Some(Character('1')));
+        assert_eq!(kb.press_key(0x20), Some(chr('1')));
+        assert_eq!(kb.release_key(0x20), Some(chr('1')));
         assert_eq!(kb.press_key(0x12), Some(Ignore));
-        assert_eq!(kb.press_key(0x20), Some(Character('3')));
-        assert_eq!(kb.release_key(0x20), Some(Character('3')));
+        assert_eq!(kb.press_key(0x20), Some(chr('3')));
+        assert_eq!(kb.release_key(0x20), Some(chr('3')));
         assert_eq!(kb.release_key(0x11), Some(Ignore));
-        assert_eq!(kb.press_key(0x20), Some(Character('2')));
-        assert_eq!(kb.release_key(0x20), Some(Character('2')));
+        assert_eq!(kb.press_key(0x20), Some(chr('2')));
+        assert_eq!(kb.release_key(0x20), Some(chr('2')));
         assert_eq!(kb.release_key(0x12), Some(Ignore));
-        assert_eq!(kb.press_key(0x20), Some(Character('0')));
-        assert_eq!(kb.release_key(0x20), Some(Character('0')));
+        assert_eq!(kb.press_key(0x20), Some(chr('0')));
+        assert_eq!(kb.release_key(0x20), Some(chr('0')));
 
         // L2 -> XX (L2 still active) -> L1
         assert_eq!(kb.press_key(0x12), Some(Ignore));
-        assert_eq!(kb.press_key(0x20), Some(Character('2')));
-        assert_eq!(kb.release_key(0x20), Some(Character('2')));
+        assert_eq!(kb.press_key(0x20), Some(chr('2')));
+        assert_eq!(kb.release_key(0x20), Some(chr('2')));
         assert_eq!(kb.press_key(0x11), Some(Ignore));
-        assert_eq!(kb.press_key(0x20), Some(Character('2')));
-        assert_eq!(kb.release_key(0x20), Some(Character('2')));
+        assert_eq!(kb.press_key(0x20), Some(chr('2')));
+        assert_eq!(kb.release_key(0x20), Some(chr('2')));
         assert_eq!(kb.release_key(0x12), Some(Ignore));
-        assert_eq!(kb.press_key(0x20), Some(Character('1')));
-        assert_eq!(kb.release_key(0x20), Some(Character('1')));
+        assert_eq!(kb.press_key(0x20), Some(chr('1')));
+        assert_eq!(kb.release_key(0x20), Some(chr('1')));
         assert_eq!(kb.release_key(0x11), Some(Ignore));
-        assert_eq!(kb.press_key(0x20), Some(Character('0')));
-        assert_eq!(kb.release_key(0x20), Some(Character('0')));
+        assert_eq!(kb.press_key(0x20), Some(chr('0')));
+        assert_eq!(kb.release_key(0x20), Some(chr('0')));
 
         // Change layer during key press
         assert_eq!(kb.press_key(0x11), Some(Ignore));
-        assert_eq!(kb.press_key(0x20), Some(Character('1')));
+        assert_eq!(kb.press_key(0x20), Some(chr('1')));
         assert_eq!(kb.release_key(0x11), Some(Ignore));
-        assert_eq!(kb.release_key(0x20), Some(Character('1')));
-        assert_eq!(kb.press_key(0x20), Some(Character('0')));
-        assert_eq!(kb.release_key(0x20), Some(Character('0')));
+        assert_eq!(kb.release_key(0x20), Some(chr('1')));
+        assert_eq!(kb.press_key(0x20), Some(chr('0')));
+        assert_eq!(kb.release_key(0x20), Some(chr('0')));
     }
 
     #[test]
@@ -294,8 +449,8 @@ mod tests {
         layout.add_key(0x0B, base, Ignore);
         layout.add_modifier(0x0C, a, c);
         layout.add_key(0x0C, a, Ignore);
-        layout.add_key(0xBB, b, Character('B'));
-        layout.add_key(0xCC, c, Character('C')); // not reachable from base
+        layout.add_key(0xBB, b, chr('B'));
+        layout.add_key(0xCC, c, chr('C')); // not reachable from base
 
         let mut kb = VirtualKeyboard::new(layout.build());
 
@@ -310,13 +465,13 @@ mod tests {
 
         // But Layer b should be activated even when modifier for layer c pressed.
         assert_eq!(kb.press_key(0x0B), Some(Ignore));
-        assert_eq!(kb.press_key(0xBB), Some(Character('B')));
-        assert_eq!(kb.release_key(0xBB), Some(Character('B')));
+        assert_eq!(kb.press_key(0xBB), Some(chr('B')));
+        assert_eq!(kb.release_key(0xBB), Some(chr('B')));
 
         // Release layer c key (it was never activated) and make sure we are still on layer b.
         assert_eq!(kb.release_key(0x0C), None);
-        assert_eq!(kb.press_key(0xBB), Some(Character('B')));
-        assert_eq!(kb.release_key(0xBB), Some(Character('B')));
+        assert_eq!(kb.press_key(0xBB), Some(chr('B')));
+        assert_eq!(kb.release_key(0xBB), Some(chr('B')));
 
         // Release layer b key
         assert_eq!(kb.release_key(0x0B), Some(Ignore));
@@ -326,6 +481,47 @@ mod tests {
         assert_eq!(kb.release_key(0xBB), None);
     }
 
+    #[test]
+    fn conditional_layer() {
+        let mut layout = LayoutBuilder::new();
+        let base = layout.add_layer(String::from("base"));
+        let symbols = layout.add_layer(String::from("symbols"));
+        let navigation = layout.add_layer(String::from("navigation"));
+        let greek = layout.add_layer(String::from("greek"));
+        layout.add_modifier(0x3A, base, symbols);
+        layout.add_key(0x3A, base, Ignore);
+        layout.add_modifier(0x56, base, navigation);
+        layout.add_key(0x56, base, Ignore);
+        layout.add_conditional_layer(vec![symbols, navigation], greek);
+        layout.add_key(0xFF, base, chr('x'));
+        layout.add_key(0xFF, symbols, chr('3'));
+        layout.add_key(0xFF, navigation, chr('4'));
+        layout.add_key(0xFF, greek, chr('ξ'));
+
+        let mut kb = VirtualKeyboard::new(layout.build());
+
+        // Both press orders activate the conditional layer.
+        for (first, second) in [(0x3A, 0x56), (0x56, 0x3A)] {
+            let first_char = if first == 0x3A { '3' } else { '4' };
+            let second_char = if second == 0x3A { '3' } else { '4' };
+
+            assert_eq!(kb.press_key(first), Some(Ignore));
+            assert_eq!(kb.press_key(0xFF), Some(chr(first_char)));
+            assert_eq!(kb.release_key(0xFF), Some(chr(first_char)));
+            assert_eq!(kb.press_key(second), Some(Ignore));
+            assert_eq!(kb.press_key(0xFF), Some(chr('ξ')));
+            assert_eq!(kb.release_key(0xFF), Some(chr('ξ')));
+
+            // Releasing one modifier leaves the other layer active.
+            assert_eq!(kb.release_key(first), Some(Ignore));
+            assert_eq!(kb.press_key(0xFF), Some(chr(second_char)));
+            assert_eq!(kb.release_key(0xFF), Some(chr(second_char)));
+            assert_eq!(kb.release_key(second), Some(Ignore));
+            assert_eq!(kb.press_key(0xFF), Some(chr('x')));
+            assert_eq!(kb.release_key(0xFF), Some(chr('x')));
+        }
+    }
+
     #[test]
     fn layer_lock() {
         let mut layout = LayoutBuilder::new();
@@ -343,7 +539,7 @@ mod tests {
         layout.add_modifier(0xB0, base, b);
         layout.add_key(0xB0, base, Ignore);
 
-        layout.add_key(0xFF, base, Character('X'));
+        layout.add_key(0xFF, base, chr('X'));
 
         layout.add_modifier(0x0B, a, c);
         layout.add_key(0x0B, a, Ignore);
@@ -355,7 +551,7 @@ mod tests {
         layout.add_layer_lock(0xA0, a, a);
         layout.add_modifier(0xA0, a, base);
 
-        layout.add_key(0xFF, a, Character('A'));
+        layout.add_key(0xFF, a, chr('A'));
 
         layout.add_modifier(0x0A, b, c);
         layout.add_key(0x0A, b, Ignore);
@@ -364,14 +560,14 @@ mod tests {
         layout.add_layer_lock(0x0B, b, b);
         layout.add_layer_lock(0xB0, b, b);
 
-        layout.add_key(0xFF, b, Character('B'));
+        layout.add_key(0xFF, b, chr('B'));
 
         layout.add_layer_lock(0x0A, c, c);
         layout.add_layer_lock(0xA0, c, c);
         layout.add_layer_lock(0x0B, c, c);
         layout.add_layer_lock(0xB0, c, c);
 
-        layout.add_key(0xFF, c, Character('C'));
+        layout.add_key(0xFF, c, chr('C'));
 
         let mut kb = VirtualKeyboard::new(layout.build());
 
@@ -382,32 +578,38 @@ mod tests {
         assert_eq!(kb.release_key(0xA0), Some(Ignore));
 
         // Test if locked
-        assert_eq!(kb.press_key(0xFF), Some(Character('A')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('A')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('A')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('A')));
 
         // Temp switch back to layer base
         assert_eq!(kb.press_key(0x0A), Some(Ignore));
-        assert_eq!(kb.press_key(0xFF), Some(Character('X')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('X')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('X')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('X')));
         assert_eq!(kb.release_key(0x0A), Some(Ignore));
 
         // Temp switch to layer c
         assert_eq!(kb.press_key(0x0B), Some(Ignore));
-        assert_eq!(kb.press_key(0xFF), Some(Character('C')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('C')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('C')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('C')));
 
-        // Try to lock layer c
+        // Lock layer c on top of layer a
         assert_eq!(kb.press_key(0xB0), Some(Ignore));
         assert_eq!(kb.release_key(0xB0), Some(Ignore));
+        assert_eq!(kb.press_key(0xFF), Some(chr('C')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('C')));
 
-        // Locks failed, on layer c because mod still pressed
-        assert_eq!(kb.press_key(0xFF), Some(Character('C')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('C')));
+        // Still on layer c after mod released
+        assert_eq!(kb.release_key(0x0B), Some(Ignore));
+        assert_eq!(kb.press_key(0xFF), Some(chr('C')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('C')));
 
-        // Lock failed, on layer a still after mod released
+        // Unlock layer c with the same keys, back on locked layer a
+        assert_eq!(kb.press_key(0x0B), Some(Ignore));
+        assert_eq!(kb.press_key(0xB0), Some(Ignore));
+        assert_eq!(kb.release_key(0xB0), Some(Ignore));
         assert_eq!(kb.release_key(0x0B), Some(Ignore));
-        assert_eq!(kb.press_key(0xFF), Some(Character('A')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('A')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('A')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('A')));
 
         // Unlock layer a
         assert_eq!(kb.press_key(0xA0), Some(Ignore));
@@ -416,8 +618,72 @@ mod tests {
         assert_eq!(kb.release_key(0xA0), Some(Ignore));
 
         // Check if locked to layer base
-        assert_eq!(kb.press_key(0xFF), Some(Character('X')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('X')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('X')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('X')));
+    }
+
+    #[test]
+    fn layer_lock_stack() {
+        let mut layout = LayoutBuilder::new();
+        let base = layout.add_layer(String::from("base"));
+        let symbols = layout.add_layer(String::from("symbols"));
+        let navigation = layout.add_layer(String::from("navigation"));
+        let numpad = layout.add_layer(String::from("numpad"));
+
+        layout.add_layer_lock(0x01, base, symbols);
+        layout.add_layer_lock(0x02, symbols, navigation);
+        layout.add_lock_action(0x03, navigation, LayerLock::Replace(numpad));
+        layout.add_lock_action(0x04, numpad, LayerLock::Clear);
+        layout.add_lock_action(0x03, base, LayerLock::Replace(numpad));
+
+        layout.add_key(0xFF, base, chr('x'));
+        layout.add_key(0xFF, symbols, chr('s'));
+        layout.add_key(0xFF, navigation, chr('n'));
+        layout.add_key(0xFE, symbols, chr('S'));
+        layout.add_key(0xFF, numpad, chr('1'));
+
+        let mut kb = VirtualKeyboard::new(layout.build());
+        let tap = |kb: &mut VirtualKeyboard, scan_code| {
+            kb.press_key(scan_code);
+            kb.release_key(scan_code);
+        };
+
+        // Stack navigation on top of symbols, transparent to the locked symbols layer.
+        tap(&mut kb, 0x01);
+        assert_eq!(kb.locked_layer(), "symbols");
+        tap(&mut kb, 0x02);
+        assert_eq!(kb.locked_layer(), "navigation");
+        assert_eq!(kb.press_key(0xFF), Some(chr('n')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('n')));
+        assert_eq!(kb.press_key(0xFE), Some(chr('S')));
+        assert_eq!(kb.release_key(0xFE), Some(chr('S')));
+
+        // Unlock navigation from the layer below.
+        tap(&mut kb, 0x02);
+        assert_eq!(kb.locked_layer(), "symbols");
+        assert_eq!(kb.press_key(0xFF), Some(chr('s')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('s')));
+
+        // Replace the top most lock, numpad is not transparent to navigation.
+        tap(&mut kb, 0x02);
+        tap(&mut kb, 0x03);
+        assert_eq!(kb.locked_layer(), "numpad");
+        assert_eq!(kb.press_key(0xFF), Some(chr('1')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('1')));
+        assert_eq!(kb.press_key(0xFE), Some(chr('S')));
+        assert_eq!(kb.release_key(0xFE), Some(chr('S')));
+
+        // Clear all locks.
+        tap(&mut kb, 0x04);
+        assert_eq!(kb.locked_layer(), "base");
+        assert_eq!(kb.press_key(0xFF), Some(chr('x')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('x')));
+
+        // Replace locks when only the base layer is active.
+        tap(&mut kb, 0x03);
+        assert_eq!(kb.locked_layer(), "numpad");
+        assert_eq!(kb.press_key(0xFE), None);
+        assert_eq!(kb.release_key(0xFE), None);
     }
 
     #[test]
@@ -429,53 +695,53 @@ mod tests {
 
         layout.add_modifier(0xAB, a, b);
         layout.add_key(0xAB, a, Ignore);
-        layout.add_key(0x01, a, Character('A'));
-        layout.add_key(0x02, a, Character('A'));
-        layout.add_key(0x03, a, Character('A'));
+        layout.add_key(0x01, a, chr('A'));
+        layout.add_key(0x02, a, chr('A'));
+        layout.add_key(0x03, a, chr('A'));
         layout.add_modifier(0xBC, b, c);
         layout.add_key(0xBC, b, Ignore);
-        layout.add_key(0x01, b, Character('B'));
-        layout.add_key(0x02, b, Character('B'));
+        layout.add_key(0x01, b, chr('B'));
+        layout.add_key(0x02, b, chr('B'));
         layout.add_layer_lock(0xCC, c, c);
         layout.add_key(0xCC, c, Ignore);
-        layout.add_key(0x01, c, Character('C'));
-        layout.add_key(0x04, c, Character('C'));
+        layout.add_key(0x01, c, chr('C'));
+        layout.add_key(0x04, c, chr('C'));
 
         let mut kb = VirtualKeyboard::new(layout.build());
 
         // Layer a
-        assert_eq!(kb.press_key(0x01), Some(Character('A')));
-        assert_eq!(kb.release_key(0x01), Some(Character('A')));
-        assert_eq!(kb.press_key(0x02), Some(Character('A')));
-        assert_eq!(kb.release_key(0x02), Some(Character('A')));
-        assert_eq!(kb.press_key(0x03), Some(Character('A')));
-        assert_eq!(kb.release_key(0x03), Some(Character('A')));
+        assert_eq!(kb.press_key(0x01), Some(chr('A')));
+        assert_eq!(kb.release_key(0x01), Some(chr('A')));
+        assert_eq!(kb.press_key(0x02), Some(chr('A')));
+        assert_eq!(kb.release_key(0x02), Some(chr('A')));
+        assert_eq!(kb.press_key(0x03), Some(chr('A')));
+        assert_eq!(kb.release_key(0x03), Some(chr('A')));
         assert_eq!(kb.press_key(0x04), None);
         assert_eq!(kb.release_key(0x04), None);
 
         assert_eq!(kb.press_key(0xAB), Some(Ignore));
 
         // Layer b
-        assert_eq!(kb.press_key(0x01), Some(Character('B')));
-        assert_eq!(kb.release_key(0x01), Some(Character('B')));
-        assert_eq!(kb.press_key(0x02), Some(Character('B')));
-        assert_eq!(kb.release_key(0x02), Some(Character('B')));
-        assert_eq!(kb.press_key(0x03), Some(Character('A')));
-        assert_eq!(kb.release_key(0x03), Some(Character('A')));
+        assert_eq!(kb.press_key(0x01), Some(chr('B')));
+        assert_eq!(kb.release_key(0x01), Some(chr('B')));
+        assert_eq!(kb.press_key(0x02), Some(chr('B')));
+        assert_eq!(kb.release_key(0x02), Some(chr('B')));
+        assert_eq!(kb.press_key(0x03), Some(chr('A')));
+        assert_eq!(kb.release_key(0x03), Some(chr('A')));
         assert_eq!(kb.press_key(0x04), None);
         assert_eq!(kb.release_key(0x04), None);
 
         assert_eq!(kb.press_key(0xBC), Some(Ignore));
 
         // Layer c
-        assert_eq!(kb.press_key(0x01), Some(Character('C')));
-        assert_eq!(kb.release_key(0x01), Some(Character('C')));
-        assert_eq!(kb.press_key(0x02), Some(Character('B')));
-        assert_eq!(kb.release_key(0x02), Some(Character('B')));
-        assert_eq!(kb.press_key(0x03), Some(Character('A')));
-        assert_eq!(kb.release_key(0x03), Some(Character('A')));
-        assert_eq!(kb.press_key(0x04), Some(Character('C')));
-        assert_eq!(kb.release_key(0x04), Some(Character('C')));
+        assert_eq!(kb.press_key(0x01), Some(chr('C')));
+        assert_eq!(kb.release_key(0x01), Some(chr('C')));
+        assert_eq!(kb.press_key(0x02), Some(chr('B')));
+        assert_eq!(kb.release_key(0x02), Some(chr('B')));
+        assert_eq!(kb.press_key(0x03), Some(chr('A')));
+        assert_eq!(kb.release_key(0x03), Some(chr('A')));
+        assert_eq!(kb.press_key(0x04), Some(chr('C')));
+        assert_eq!(kb.release_key(0x04), Some(chr('C')));
 
         // Lock layer c
         assert_eq!(kb.press_key(0xCC), Some(Ignore));
@@ -484,15 +750,15 @@ mod tests {
         assert_eq!(kb.release_key(0xAB), Some(Ignore));
 
         // Layer c
-        assert_eq!(kb.press_key(0x01), Some(Character('C')));
-        assert_eq!(kb.release_key(0x01), Some(Character('C')));
-        assert_eq!(kb.press_key(0x02), Some(Character('B')));
-        assert_eq!(kb.release_key(0x02), Some(Character('B')));
-        assert_eq!(kb.press_key(0x03), Some(Character('A')));
-        assert_eq!(kb.release_key(0x03), Some(Character('A')));
+        assert_eq!(kb.press_key(0x01), Some(chr('C')));
+        assert_eq!(kb.release_key(0x01), Some(chr('C')));
+        assert_eq!(kb.press_key(0x02), Some(chr('B')));
+        assert_eq!(kb.release_key(0x02), Some(chr('B')));
+        assert_eq!(kb.press_key(0x03), Some(chr('A')));
+        assert_eq!(kb.release_key(0x03), Some(chr('A')));
         // Should be transparent to layer c now
-        assert_eq!(kb.press_key(0x04), Some(Character('C')));
-        assert_eq!(kb.release_key(0x04), Some(Character('C')));
+        assert_eq!(kb.press_key(0x04), Some(chr('C')));
+        assert_eq!(kb.release_key(0x04), Some(chr('C')));
 
         // Unlock layer c
         assert_eq!(kb.press_key(0xAB), Some(Ignore));
@@ -502,16 +768,59 @@ mod tests {
         assert_eq!(kb.release_key(0xAB), Some(Ignore));
         assert_eq!(kb.release_key(0xBC), Some(Ignore));
 
-        assert_eq!(kb.press_key(0x01), Some(Character('A')));
-        assert_eq!(kb.release_key(0x01), Some(Character('A')));
-        assert_eq!(kb.press_key(0x02), Some(Character('A')));
-        assert_eq!(kb.release_key(0x02), Some(Character('A')));
-        assert_eq!(kb.press_key(0x03), Some(Character('A')));
-        assert_eq!(kb.release_key(0x03), Some(Character('A')));
+        assert_eq!(kb.press_key(0x01), Some(chr('A')));
+        assert_eq!(kb.release_key(0x01), Some(chr('A')));
+        assert_eq!(kb.press_key(0x02), Some(chr('A')));
+        assert_eq!(kb.release_key(0x02), Some(chr('A')));
+        assert_eq!(kb.press_key(0x03), Some(chr('A')));
+        assert_eq!(kb.release_key(0x03), Some(chr('A')));
         assert_eq!(kb.press_key(0x04), None);
         assert_eq!(kb.release_key(0x04), None);
     }
 
+    #[test]
+    fn fallthrough() {
+        let mut layout = LayoutBuilder::new();
+        let base = layout.add_layer(String::from("base"));
+        let navigation = layout.add_layer(String::from("navigation"));
+        let numpad = layout.add_layer(String::from("numpad"));
+        layout.set_fallthrough(navigation, Fallthrough::Opaque);
+        layout.set_fallthrough(numpad, Fallthrough::Passthrough);
+
+        layout.add_modifier(0x3A, base, navigation);
+        layout.add_key(0x3A, base, Ignore);
+        layout.add_modifier(0x56, base, numpad);
+        layout.add_key(0x56, base, Ignore);
+        layout.add_key(0x10, base, chr('a'));
+        layout.add_key(0x11, base, chr('b'));
+        layout.add_key(0x10, navigation, VirtualKey(0x26));
+        layout.add_key(0x10, numpad, chr('1'));
+
+        let mut kb = VirtualKeyboard::new(layout.build());
+
+        // Opaque: unmapped keys are ignored.
+        assert_eq!(kb.press_key(0x3A), Some(Ignore));
+        assert_eq!(kb.press_key(0x10), Some(VirtualKey(0x26)));
+        assert_eq!(kb.release_key(0x10), Some(VirtualKey(0x26)));
+        assert_eq!(kb.press_key(0x11), Some(Ignore));
+        assert_eq!(kb.release_key(0x11), Some(Ignore));
+        assert_eq!(kb.press_key(0x12), Some(Ignore));
+        assert_eq!(kb.release_key(0x12), Some(Ignore));
+        assert_eq!(kb.release_key(0x3A), Some(Ignore));
+
+        // Passthrough: unmapped keys are forwarded.
+        assert_eq!(kb.press_key(0x56), Some(Ignore));
+        assert_eq!(kb.press_key(0x10), Some(chr('1')));
+        assert_eq!(kb.release_key(0x10), Some(chr('1')));
+        assert_eq!(kb.press_key(0x11), None);
+        assert_eq!(kb.release_key(0x11), None);
+        assert_eq!(kb.release_key(0x56), Some(Ignore));
+
+        // Back on the base layer.
+        assert_eq!(kb.press_key(0x11), Some(chr('b')));
+        assert_eq!(kb.release_key(0x11), Some(chr('b')));
+    }
+
     #[test]
     fn layer_lock_shared_path() {
         let mut layout = LayoutBuilder::new();
@@ -535,7 +844,7 @@ mod tests {
         layout.add_key(0xCD, c, Ignore);
         layout.add_layer_lock(0xBD, d, d);
         layout.add_layer_lock(0xCD, d, d);
-        layout.add_key(0xFF, d, Character('X'));
+        layout.add_key(0xFF, d, chr('X'));
 
         let mut kb = VirtualKeyboard::new(layout.build());
 
@@ -554,8 +863,8 @@ mod tests {
         kb.release_key(0xCD);
 
         // Check if locked
-        assert_eq!(kb.press_key(0xFF), Some(Character('X')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('X')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('X')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('X')));
     }
 
     #[test]
@@ -567,35 +876,231 @@ mod tests {
         // base layer
         layout.add_layer_lock(0x3A, base, shift); // caps lock
         layout.add_key(0x3A, base, VirtualKey(0x14)); // forward caps vk
-        layout.add_key(0xFF, base, Character('x'));
+        layout.add_key(0xFF, base, chr('x'));
 
         // shift layer
-        layout.add_key(0xFF, shift, Character('X'));
+        layout.add_key(0xFF, shift, chr('X'));
 
         let mut kb = VirtualKeyboard::new(layout.build());
 
-        assert_eq!(kb.press_key(0xFF), Some(Character('x')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('x')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('x')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('x')));
 
         // Activate caps lock (but do not release yet)
         assert_eq!(kb.press_key(0x3A), Some(VirtualKey(0x14)));
-        assert_eq!(kb.press_key(0xFF), Some(Character('X')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('X')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('X')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('X')));
 
         // Release caps lock key, shift layer stays activated
         assert_eq!(kb.release_key(0x3A), Some(VirtualKey(0x14)));
-        assert_eq!(kb.press_key(0xFF), Some(Character('X')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('X')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('X')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('X')));
 
         // Deativate caps lock (but do not release yet)
         assert_eq!(kb.press_key(0x3A), Some(VirtualKey(0x14)));
-        assert_eq!(kb.press_key(0xFF), Some(Character('x')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('x')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('x')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('x')));
 
         // Release caps lock key
         assert_eq!(kb.release_key(0x3A), Some(VirtualKey(0x14)));
-        assert_eq!(kb.press_key(0xFF), Some(Character('x')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('x')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('x')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('x')));
+    }
+
+    #[test]
+    fn toggle_layers() {
+        let mut layout = LayoutBuilder::new();
+        let base = layout.add_layer(String::from("base"));
+        let numpad = layout.add_layer(String::from("numpad"));
+        let navigation = layout.add_layer(String::from("navigation"));
+        layout.add_layer_lock(0x45, base, numpad);
+        layout.add_layer_lock(0x46, numpad, navigation);
+        layout.set_toggle_layer(ToggleKey::Num, numpad);
+        layout.set_toggle_layer(ToggleKey::Scroll, navigation);
+
+        let mut kb = VirtualKeyboard::new(layout.build());
+        let enabled = |kb: &VirtualKeyboard| ToggleKey::ALL.map(|key| kb.toggle_key_enabled(key));
+
+        assert_eq!(enabled(&kb), [Some(false), Some(false), Some(false)]);
+        kb.press_key(0x45);
+        kb.release_key(0x45);
+        assert_eq!(enabled(&kb), [Some(false), Some(true), Some(false)]);
+        kb.press_key(0x46);
+        kb.release_key(0x46);
+        assert_eq!(enabled(&kb), [Some(false), Some(true), Some(true)]);
+        kb.reset();
+        assert_eq!(enabled(&kb), [Some(false), Some(false), Some(false)]);
+
+        // Caps lock is always disabled, other keys are not managed.
+        let mut layout = LayoutBuilder::new();
+        layout.add_layer(String::from("base"));
+        let kb = VirtualKeyboard::new(layout.build());
+        assert_eq!(enabled(&kb), [Some(false), None, None]);
+    }
+
+    #[test]
+    fn set_base_layer() {
+        let mut layout = LayoutBuilder::new();
+        let qwerty = layout.add_layer(String::from("qwerty"));
+        let dvorak = layout.add_layer(String::from("dvorak"));
+        let shift = layout.add_layer(String::from("shift"));
+        let fn_layer = layout.add_layer(String::from("fn"));
+        layout.add_modifier(0x2A, qwerty, shift);
+        layout.add_modifier(0x2A, dvorak, shift);
+        layout.add_layer_lock(0x3A, qwerty, shift);
+        layout.add_layer_lock(0x3A, dvorak, shift);
+        layout.add_modifier(0x5D, qwerty, fn_layer);
+        layout.add_modifier(0x5D, dvorak, fn_layer);
+        layout.add_lock_action(0x02, fn_layer, LayerLock::SetBase(qwerty));
+        layout.add_lock_action(0x03, fn_layer, LayerLock::SetBase(dvorak));
+        layout.add_key(0x10, qwerty, chr('q'));
+        layout.add_key(0x10, dvorak, chr('\''));
+        layout.add_key(0x10, shift, chr('Q'));
+
+        let mut kb = VirtualKeyboard::new(layout.build());
+        assert_eq!(kb.base_layer(), "qwerty");
+
+        // Switch to dvorak while holding the fn modifier.
+        kb.press_key(0x5D);
+        kb.press_key(0x03);
+        kb.release_key(0x03);
+        assert_eq!(kb.active_layer(), "fn");
+        kb.release_key(0x5D);
+        assert_eq!(kb.base_layer(), "dvorak");
+        assert_eq!(kb.press_key(0x10), Some(chr('\'')));
+        assert_eq!(kb.release_key(0x10), Some(chr('\'')));
+
+        // Modifiers and locks work on top of the new base layer.
+        kb.press_key(0x3A);
+        kb.release_key(0x3A);
+        assert_eq!(kb.press_key(0x10), Some(chr('Q')));
+        assert_eq!(kb.release_key(0x10), Some(chr('Q')));
+        kb.press_key(0x3A);
+        kb.release_key(0x3A);
+        assert_eq!(kb.locked_layer(), "dvorak");
+
+        // Survives reset.
+        kb.reset();
+        assert_eq!(kb.base_layer(), "dvorak");
+        assert_eq!(kb.press_key(0x10), Some(chr('\'')));
+        assert_eq!(kb.release_key(0x10), Some(chr('\'')));
+
+        // Changed by name, e.g. when restoring the persisted base layer.
+        assert!(!kb.set_base_layer("colemak"));
+        assert!(kb.set_base_layer("qwerty"));
+        assert_eq!(kb.press_key(0x10), Some(chr('q')));
+        assert_eq!(kb.release_key(0x10), Some(chr('q')));
+    }
+
+    #[test]
+    fn switch_layout() {
+        let mut layout = LayoutBuilder::new();
+        let base = layout.add_layer(String::from("base"));
+        let shift = layout.add_layer(String::from("shift"));
+        layout.add_modifier(0x2A, base, shift);
+        layout.add_layer_lock(0x3A, base, shift);
+        layout.add_key(0x10, base, chr('q'));
+        layout.add_key(0x10, shift, chr('Q'));
+        let qwerty = layout.build();
+
+        let mut layout = LayoutBuilder::new();
+        let base = layout.add_layer(String::from("base"));
+        layout.add_key(0x10, base, chr('\''));
+        let dvorak = layout.build();
+
+        let mut kb = VirtualKeyboard::new(qwerty);
+        kb.press_key(0x3A);
+        kb.release_key(0x3A);
+        assert_eq!(kb.locked_layer(), "shift");
+        assert_eq!(kb.press_key(0x10), Some(chr('Q')));
+        assert!(kb.is_pressed(0x10));
+
+        // Held keys release with the action of the previous layout.
+        kb.set_layout(dvorak);
+        assert_eq!(kb.locked_layer(), "base");
+        assert_eq!(kb.press_key(0x10), Some(chr('Q')));
+        assert_eq!(kb.release_key(0x10), Some(chr('Q')));
+        assert!(!kb.is_pressed(0x10));
+
+        assert_eq!(kb.press_key(0x10), Some(chr('\'')));
+        assert_eq!(kb.release_key(0x10), Some(chr('\'')));
+
+        // Shift is not a modifier on the new layout.
+        assert_eq!(kb.press_key(0x2A), None);
+        assert_eq!(kb.active_layer(), "base");
+        assert_eq!(kb.release_key(0x2A), None);
+    }
+
+    #[test]
+    fn lock_timeout() {
+        let mut layout = LayoutBuilder::new();
+        let base = layout.add_layer(String::from("base"));
+        let numpad = layout.add_layer(String::from("numpad"));
+        layout.add_layer_lock(0x45, base, numpad);
+        layout.add_key(0x10, base, chr('q'));
+        layout.add_key(0x10, numpad, chr('7'));
+        layout.set_lock_timeout(numpad, 1000);
+        layout.set_toggle_layer(ToggleKey::Num, numpad);
+
+        let mut kb = VirtualKeyboard::new(layout.build());
+        let tap = |kb: &mut VirtualKeyboard, scan_code, time| {
+            kb.update_time(time);
+            let action = kb.press_key(scan_code);
+            kb.update_time(time + 10);
+            kb.release_key(scan_code);
+            action
+        };
+
+        // No deadline without a locked layer.
+        assert_eq!(kb.lock_deadline(), None);
+        assert!(!kb.expire_locks(100_000));
+
+        // Lock the numpad, each key press restarts the timeout.
+        tap(&mut kb, 0x45, 5000);
+        assert_eq!(kb.lock_deadline(), Some(6010));
+        assert_eq!(tap(&mut kb, 0x10, 5900), Some(chr('7')));
+        assert_eq!(tap(&mut kb, 0x10, 6800), Some(chr('7')));
+        assert_eq!(kb.lock_deadline(), Some(7810));
+
+        // Timer based expiry.
+        assert!(!kb.expire_locks(7809));
+        assert_eq!(kb.toggle_key_enabled(ToggleKey::Num), Some(true));
+        assert!(kb.expire_locks(7810));
+        assert_eq!(kb.locked_layer(), "base");
+        assert_eq!(kb.toggle_key_enabled(ToggleKey::Num), Some(false));
+        assert_eq!(kb.lock_deadline(), None);
+
+        // Expiry with the next key event when the timer did not fire.
+        tap(&mut kb, 0x45, 10_000);
+        assert_eq!(tap(&mut kb, 0x10, 20_000), Some(chr('q')));
+        assert_eq!(kb.locked_layer(), "base");
+
+        // Tick count overflow.
+        tap(&mut kb, 0x45, u32::MAX - 500);
+        assert_eq!(tap(&mut kb, 0x10, 200), Some(chr('7')));
+        assert!(!kb.expire_locks(1000));
+        assert!(kb.expire_locks(1210));
+        assert_eq!(kb.locked_layer(), "base");
+    }
+
+    #[test]
+    fn lock_timeout_base_layer() {
+        let mut layout = LayoutBuilder::new();
+        let base = layout.add_layer(String::from("base"));
+        let numpad = layout.add_layer(String::from("numpad"));
+        layout.add_lock_action(0x45, base, LayerLock::SetBase(numpad));
+        layout.set_lock_timeout(numpad, 1000);
+
+        let mut kb = VirtualKeyboard::new(layout.build());
+
+        // A base layer with lock timeout does not time out.
+        kb.update_time(5000);
+        kb.press_key(0x45);
+        kb.release_key(0x45);
+        assert_eq!(kb.base_layer(), "numpad");
+        assert_eq!(kb.lock_deadline(), None);
+        assert!(!kb.expire_locks(100_000));
+        assert_eq!(kb.locked_layer(), "numpad");
     }
 
     #[test]
@@ -609,7 +1114,7 @@ mod tests {
         layout.add_modifier(0xE036, base, shift);
         layout.add_key(0xE036, base, VirtualKey(0xA1)); // forward shift vk
 
-        layout.add_key(0xFF, base, Character('x'));
+        layout.add_key(0xFF, base, chr('x'));
 
         layout.add_key(0x2A, shift, VirtualKey(0x14)); // caps lock vk
         layout.add_modifier(0x2A, shift, base); // temp base layer
@@ -618,29 +1123,29 @@ mod tests {
         layout.add_modifier(0xE036, shift, base); // temp base layer
         layout.add_layer_lock(0xE036, shift, shift);
 
-        layout.add_key(0xFF, shift, Character('X'));
+        layout.add_key(0xFF, shift, chr('X'));
 
         let mut kb = VirtualKeyboard::new(layout.build());
 
         // base layer
-        assert_eq!(kb.press_key(0xFF), Some(Character('x')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('x')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('x')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('x')));
 
         // activate caps lock
         assert_eq!(kb.press_key(0x2A), Some(VirtualKey(0xA0)));
         assert_eq!(kb.press_key(0xE036), Some(VirtualKey(0x14)));
-        assert_eq!(kb.press_key(0xFF), Some(Character('X')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('X')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('X')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('X')));
 
         // temp base layer
         assert_eq!(kb.release_key(0x2A), Some(VirtualKey(0xA0)));
-        assert_eq!(kb.press_key(0xFF), Some(Character('x')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('x')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('x')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('x')));
         assert_eq!(kb.release_key(0xE036), Some(VirtualKey(0x14)));
 
         // locked shift layer
-        assert_eq!(kb.press_key(0xFF), Some(Character('X')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('X')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('X')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('X')));
 
         // deactivate caps lock
         assert_eq!(kb.press_key(0xE036), Some(VirtualKey(0x14)));
@@ -649,7 +1154,7 @@ mod tests {
         assert_eq!(kb.release_key(0xE036), Some(VirtualKey(0x14)));
 
         // base layer
-        assert_eq!(kb.press_key(0xFF), Some(Character('x')));
-        assert_eq!(kb.release_key(0xFF), Some(Character('x')));
+        assert_eq!(kb.press_key(0xFF), Some(chr('x')));
+        assert_eq!(kb.release_key(0xFF), Some(chr('x')));
     }
 }
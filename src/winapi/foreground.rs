@@ -0,0 +1,114 @@
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use windows_sys::Win32::Foundation::*;
+use windows_sys::Win32::Globalization::LCIDToLocaleName;
+use windows_sys::Win32::System::SystemServices::LOCALE_NAME_MAX_LENGTH;
+use windows_sys::Win32::System::Threading::*;
+use windows_sys::Win32::UI::Input::KeyboardAndMouse::{GetKeyboardLayout, HKL};
+use windows_sys::Win32::UI::WindowsAndMessaging::*;
+
+use crate::rules::ForegroundContext;
+
+/// Handle of the foreground window and its input language, cheap to query
+/// and compare to detect when the rules need to be evaluated again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForegroundWindow(HWND, HKL);
+
+impl ForegroundWindow {
+    pub fn get() -> Self {
+        unsafe {
+            let window = GetForegroundWindow();
+            let mut layout = GetKeyboardLayout(GetWindowThreadProcessId(window, ptr::null_mut()));
+            // Console applications report no layout, same fallback as in
+            // `keyboard::get_virtual_key()`.
+            if layout.is_null() {
+                layout = GetKeyboardLayout(0);
+            }
+            Self(window, layout)
+        }
+    }
+
+    /// Queries the process and window class of the window.
+    ///
+    /// Returns `None` when there is no foreground window (e.g. while switching
+    /// windows) or when the window is gone already.
+    pub fn context(self) -> Option<ForegroundContext> {
+        if self.0.is_null() {
+            return None;
+        }
+
+        let mut class_buf = [0; 256];
+        let len = unsafe { GetClassNameW(self.0, class_buf.as_mut_ptr(), class_buf.len() as i32) };
+        if len == 0 {
+            return None;
+        }
+        let window_class = String::from_utf16_lossy(&class_buf[..len as usize]);
+
+        let mut process_id = 0;
+        unsafe { GetWindowThreadProcessId(self.0, &mut process_id) };
+
+        // Elevated processes cannot be queried from a non-elevated process,
+        // match with an empty process name in that case.
+        let process_name = process_image_path(process_id)
+            .as_deref()
+            .and_then(Path::file_name)
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        // The low word of the keyboard layout handle is the language identifier,
+        // the high word identifies the keyboard layout.
+        let language_id = self.1 as usize as u16;
+        let layout_id = (self.1 as usize >> 16) as u16;
+
+        Some(ForegroundContext {
+            process_name,
+            window_class,
+            language_id,
+            language_tag: language_tag(language_id).unwrap_or_default(),
+            layout_id,
+        })
+    }
+}
+
+fn language_tag(language_id: u16) -> Option<String> {
+    let mut tag_buf = [0; LOCALE_NAME_MAX_LENGTH as usize];
+    // A language identifier is a locale identifier with default sort order.
+    let len = unsafe {
+        LCIDToLocaleName(
+            language_id.into(),
+            tag_buf.as_mut_ptr(),
+            tag_buf.len() as i32,
+            0,
+        )
+    };
+    if len == 0 {
+        return None;
+    }
+    // Length includes the null terminator.
+    Some(String::from_utf16_lossy(&tag_buf[..len as usize - 1]))
+}
+
+fn process_image_path(process_id: u32) -> Option<PathBuf> {
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, process_id);
+        if process.is_null() {
+            return None;
+        }
+
+        let mut path_buf = [0; MAX_PATH as usize];
+        let mut len = path_buf.len() as u32;
+        let res = QueryFullProcessImageNameW(
+            process,
+            PROCESS_NAME_WIN32,
+            path_buf.as_mut_ptr(),
+            &mut len,
+        );
+        CloseHandle(process);
+
+        if res == 0 {
+            return None;
+        }
+        Some(String::from_utf16_lossy(&path_buf[..len as usize]).into())
+    }
+}
@@ -2,16 +2,38 @@
 
 use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
-use std::fmt::Display;
 use std::future::poll_fn;
 use std::task::{Poll, Waker};
 use std::{mem, ptr};
 
+use anyhow::Result;
 use encode_unicode::CharExt;
 use windows_sys::Win32::Foundation::*;
 use windows_sys::Win32::UI::Input::KeyboardAndMouse::*;
 use windows_sys::Win32::UI::WindowsAndMessaging::*;
 
+use super::{ForegroundWindow, tick_count, timeout};
+use crate::backend::{InputBackend, KeyEvent, KeyType, OutputEvent, OutputSink};
+use crate::host_layout::{HostKey, Modifiers};
+use crate::log::error;
+use crate::rules::ForegroundContext;
+use crate::toggle_keys::ToggleKey;
+
+/// Modifier keys, pressed again when their release was undone.
+const MODIFIERS: [VIRTUAL_KEY; 11] = [
+    VK_SHIFT,
+    VK_CONTROL,
+    VK_MENU,
+    VK_LSHIFT,
+    VK_RSHIFT,
+    VK_LCONTROL,
+    VK_RCONTROL,
+    VK_LMENU,
+    VK_RMENU,
+    VK_LWIN,
+    VK_RWIN,
+];
+
 thread_local! {
     /// Buffer key events to prevent blocking the low-level keyboard hook.
     static KEY_QUEUE: RefCell<KeyQueue> = const { RefCell::new(KeyQueue::new()) };
@@ -45,7 +67,7 @@ pub fn hook_disable() {
 }
 
 /// Asynchronously waits for the next key event captured by the low-level keyboard hook.
-pub async fn next_key_event() -> KeyEvent {
+async fn next_key_event() -> KeyEvent {
     poll_fn(|cx| {
         KEY_QUEUE.with_borrow_mut(|queue| {
             if let Some(key) = queue.key_events.pop_front() {
@@ -59,66 +81,17 @@ pub async fn next_key_event() -> KeyEvent {
     .await
 }
 
-/// Key event type.
-#[derive(Debug, Clone, Copy)]
-pub enum KeyType {
-    /// Virtual key as defined by the layout set by Windows.
-    ///
-    /// <https://docs.microsoft.com/en-us/windows/win32/inputdev/virtual-key-codes>
-    VirtualKey(u8),
-
-    /// Unicode character.
-    Unicode(char),
-}
-
-/// Key event received by the low-level keyboard hook.
-#[derive(Debug, Clone, Copy)]
-pub struct KeyEvent {
-    /// Virtual key or Unicode character of this event.
-    pub key: KeyType,
-
-    /// Scan code as defined by the keyboard.
-    /// Extended keycodes have the three most significant bits set (0xExxx).
-    pub scan_code: u16,
-
-    /// Key was released.
-    pub up: bool,
-
-    /// Time in milliseconds since boot.
-    pub time: u32,
-}
-
-impl Display for KeyEvent {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{{ sc: {:#06X}, ", self.scan_code))?;
-
-        match self.key {
-            KeyType::VirtualKey(vk) => f.write_fmt(format_args!("vk: {vk:#04X}"))?,
-            KeyType::Unicode(c) => f.write_fmt(format_args!("char: {c}"))?,
-        }
-
-        f.write_fmt(format_args!(
-            ", {} }}",
-            if self.up { "up  " } else { "down" }
-        ))?;
-
-        Ok(())
+fn key_event_from_hook_lparam(lparam: &KBDLLHOOKSTRUCT) -> KeyEvent {
+    let mut scan_code = lparam.scanCode as u16;
+    if lparam.flags & LLKHF_EXTENDED != 0 {
+        scan_code |= 0xE000;
     }
-}
 
-impl KeyEvent {
-    fn from_hook_lparam(lparam: &KBDLLHOOKSTRUCT) -> Self {
-        let mut scan_code = lparam.scanCode as u16;
-        if lparam.flags & LLKHF_EXTENDED != 0 {
-            scan_code |= 0xE000;
-        }
-
-        Self {
-            key: KeyType::VirtualKey(lparam.vkCode as _),
-            scan_code,
-            up: lparam.flags & LLKHF_UP != 0,
-            time: lparam.time,
-        }
+    KeyEvent {
+        key: KeyType::VirtualKey(lparam.vkCode as _),
+        scan_code,
+        up: lparam.flags & LLKHF_UP != 0,
+        time: lparam.time,
     }
 }
 
@@ -152,7 +125,7 @@ unsafe extern "system" fn hook_proc(code: i32, wparam: WPARAM, lparam: LPARAM) -
     // keyboards hooks only ever see injected events. So far this did not cause
     // any issues in practice.
 
-    let key = KeyEvent::from_hook_lparam(hook_lparam);
+    let key = key_event_from_hook_lparam(hook_lparam);
     KEY_QUEUE.with(|queue| queue.borrow_mut().enqueue(key));
     -1
 }
@@ -178,54 +151,123 @@ impl KeyQueue {
     }
 }
 
-/// Sends a virtual key event.
-pub fn send_key(key: KeyEvent) {
-    unsafe {
-        let mut inputs: [INPUT; 2] = mem::zeroed();
-
-        let n_inputs = match key.key {
-            KeyType::VirtualKey(vk) => {
-                inputs[0].r#type = INPUT_KEYBOARD;
-                inputs[0].Anonymous.ki = KEYBDINPUT {
-                    wVk: vk.into(),
-                    wScan: key.scan_code,
-                    dwFlags: if key.up { KEYEVENTF_KEYUP } else { 0 },
-                    time: key.time,
-                    dwExtraInfo: 0,
-                };
-                1
-            }
+/// Sends the input buffer with a single `SendInput()` call, so that no other
+/// input is interleaved.
+fn send_inputs(inputs: &[INPUT]) {
+    if inputs.is_empty() {
+        return;
+    }
+    let inserted = unsafe {
+        SendInput(
+            inputs.len() as _,
+            inputs.as_ptr(),
+            mem::size_of::<INPUT>() as _,
+        )
+    } as usize;
+    if inserted < inputs.len() {
+        error!(
+            "SendInput() inserted {inserted} of {} events (error {})",
+            inputs.len(),
+            unsafe { GetLastError() }
+        );
+        // Sending the remaining events later could interleave them with other
+        // input. Drop them, but undo the inserted ones.
+        let undo = inverse_inputs(&inputs[..inserted]);
+        if undo.is_empty() {
+            return;
+        }
+        let undone =
+            unsafe { SendInput(undo.len() as _, undo.as_ptr(), mem::size_of::<INPUT>() as _) }
+                as usize;
+        if undone < undo.len() {
+            error!(
+                "SendInput() inserted {undone} of {} events to undo (error {})",
+                undo.len(),
+                unsafe { GetLastError() }
+            );
+        }
+    }
+}
+
+/// Builds the input buffer for `SendInput()` from the output events.
+/// Internal events are skipped.
+fn input_buffer(events: &[OutputEvent], time: u32) -> Vec<INPUT> {
+    let mut inputs = Vec::with_capacity(events.len());
+    for key in events.iter().filter_map(|event| event.key_event(time)) {
+        let up = if key.up { KEYEVENTF_KEYUP } else { 0 };
+        match key.key {
+            KeyType::VirtualKey(vk) => inputs.push(keyboard_input(KEYBDINPUT {
+                wVk: vk.into(),
+                wScan: key.scan_code,
+                dwFlags: up,
+                time: key.time,
+                dwExtraInfo: 0,
+            })),
             KeyType::Unicode(c) => {
                 // Sends a unicode character, knows as `VK_PACKET`.
                 // Interestingly this is faster than sending a regular virtual key event.
-                inputs
-                    .iter_mut()
-                    .zip(c.to_utf16())
-                    .map(|(input, c)| {
-                        input.r#type = INPUT_KEYBOARD;
-                        input.Anonymous.ki = KEYBDINPUT {
-                            wVk: 0,
-                            wScan: c,
-                            dwFlags: KEYEVENTF_UNICODE | if key.up { KEYEVENTF_KEYUP } else { 0 },
-                            time: key.time,
-                            dwExtraInfo: 0,
-                        };
-                    })
-                    .count()
+                // Characters outside the BMP are sent as UTF-16 surrogate pair.
+                for c in c.to_utf16() {
+                    inputs.push(keyboard_input(KEYBDINPUT {
+                        wVk: 0,
+                        wScan: c,
+                        dwFlags: KEYEVENTF_UNICODE | up,
+                        time: key.time,
+                        dwExtraInfo: 0,
+                    }));
+                }
             }
+        }
+    }
+    inputs
+}
+
+/// Returns the events which undo the key state changes of the inputs, in
+/// reverse order: pressed keys are released and released modifiers are
+/// pressed again. Releases of other keys are kept, pressing them again would
+/// type them.
+fn inverse_inputs(inputs: &[INPUT]) -> Vec<INPUT> {
+    let mut changed: Vec<KEYBDINPUT> = Vec::new();
+    for input in inputs {
+        let ki = unsafe { input.Anonymous.ki };
+        let same_key = |other: &KEYBDINPUT| {
+            other.wVk == ki.wVk
+                && other.wScan == ki.wScan
+                && (other.dwFlags & KEYEVENTF_UNICODE) == (ki.dwFlags & KEYEVENTF_UNICODE)
         };
+        let up = ki.dwFlags & KEYEVENTF_KEYUP;
+        match changed.iter().position(same_key) {
+            // A press after a release or a release after a press restores the
+            // state before the inputs.
+            Some(idx) if changed[idx].dwFlags & KEYEVENTF_KEYUP != up => _ = changed.remove(idx),
+            // Auto repeat.
+            Some(_) => {}
+            None if up == 0 || MODIFIERS.contains(&ki.wVk) => changed.push(ki),
+            None => {}
+        }
+    }
+    changed
+        .into_iter()
+        .rev()
+        .map(|ki| {
+            keyboard_input(KEYBDINPUT {
+                dwFlags: ki.dwFlags ^ KEYEVENTF_KEYUP,
+                ..ki
+            })
+        })
+        .collect()
+}
 
-        SendInput(
-            n_inputs as _,
-            inputs.as_mut_ptr(),
-            mem::size_of::<INPUT>() as _,
-        );
+fn keyboard_input(ki: KEYBDINPUT) -> INPUT {
+    INPUT {
+        r#type: INPUT_KEYBOARD,
+        Anonymous: INPUT_0 { ki },
     }
 }
 
-/// Returns a virtual key code if the requested character can be typed with a
-/// single key press/release.
-pub fn get_virtual_key(c: char) -> Option<u8> {
+/// Returns the virtual key and modifiers to type the character on the
+/// keyboard layout of the foreground window.
+fn get_host_key(c: char) -> Option<HostKey> {
     unsafe {
         let mut layout = GetKeyboardLayout(GetWindowThreadProcessId(
             GetForegroundWindow(),
@@ -245,37 +287,213 @@ pub fn get_virtual_key(c: char) -> Option<u8> {
             return None;
         }
 
-        let dead_key =
+        // Dead-keys, e.g.: `^` or `~` on international layouts.
+        let dead =
             MapVirtualKeyExW((vk_state & 0xFF) as u32, MAPVK_VK_TO_CHAR, layout) & 0x80000000 != 0;
-        if dead_key {
-            // We have a virtual key but it is a dead-key, e.g.: `^` or `~` on international layouts.
+
+        let ctrl = vk_state & 0x200 != 0;
+        let alt = vk_state & 0x400 != 0;
+        if ctrl != alt {
+            // Control characters or keys which need Alt only.
             return None;
         }
 
-        // Check if the modifier keys, which are required to type the character, are pressed.
-        let modifier_pressed = |vk: u16| (GetAsyncKeyState(vk.into()) as u16) & 0x8000 != 0;
+        // Windows does not tell which keys caps lock affects, assume it
+        // affects all letters with upper and lower case.
+        let caps_lock = !ctrl && (c.is_lowercase() || c.is_uppercase());
 
-        let shift = vk_state & 0x100 != 0;
-        if shift && (modifier_pressed(VK_SHIFT) == caps_lock_enabled()) {
-            // Shift required but not pressed and caps lock disabled OR
-            // Shift required and pressed but caps lock enabled (which cancels the shift press).
-            return None;
-        }
+        Some(HostKey {
+            virtual_key: vk_state as u8,
+            shift: vk_state & 0x100 != 0,
+            ctrl_alt: ctrl && alt,
+            caps_lock,
+            dead,
+        })
+    }
+}
 
-        let ctrl = vk_state & 0x200 != 0;
-        if ctrl && !modifier_pressed(VK_CONTROL) {
+fn modifier_pressed(vk: u16) -> bool {
+    unsafe { (GetAsyncKeyState(vk.into()) as u16) & 0x8000 != 0 }
+}
+
+fn caps_lock_enabled() -> bool {
+    toggle_key_enabled(ToggleKey::Caps)
+}
+
+fn toggle_key_enabled(key: ToggleKey) -> bool {
+    unsafe { (GetKeyState(key.virtual_key().into()) as u16) & 0x0001 != 0 }
+}
+
+/// Key events captured by the low-level keyboard hook.
+#[derive(Debug, Default)]
+pub struct HookInput {
+    foreground: Option<ForegroundWindow>,
+}
+
+impl InputBackend for HookInput {
+    async fn next_key_event(&mut self, timeout_ms: u32) -> Result<Option<KeyEvent>> {
+        Ok(timeout(timeout_ms, next_key_event()).await)
+    }
+
+    fn time(&self) -> u32 {
+        tick_count()
+    }
+
+    fn foreground_changed(&mut self) -> Option<ForegroundContext> {
+        let window = ForegroundWindow::get();
+        if self.foreground == Some(window) {
             return None;
         }
+        self.foreground = Some(window);
+        window.context()
+    }
+}
 
-        let alt = vk_state & 0x400 != 0;
-        if alt && !modifier_pressed(VK_CONTROL) {
-            return None;
+/// Key events injected with `SendInput()`.
+#[derive(Debug, Default)]
+pub struct SendInputOutput;
+
+impl OutputSink for SendInputOutput {
+    fn send(&mut self, events: &[OutputEvent], time: u32) {
+        send_inputs(&input_buffer(events, time));
+    }
+
+    fn host_key(&self, c: char) -> Option<HostKey> {
+        get_host_key(c)
+    }
+
+    fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            left_shift: modifier_pressed(VK_LSHIFT),
+            right_shift: modifier_pressed(VK_RSHIFT),
+            ctrl: modifier_pressed(VK_CONTROL),
+            alt: modifier_pressed(VK_MENU),
+            caps_lock: caps_lock_enabled(),
         }
+    }
 
-        Some(vk_state as u8)
+    fn toggle_key_enabled(&self, key: ToggleKey) -> bool {
+        toggle_key_enabled(key)
     }
 }
 
-pub fn caps_lock_enabled() -> bool {
-    unsafe { (GetKeyState(VK_CAPITAL.into()) as u16) & 0x0001 != 0 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Virtual key (or UTF-16 code unit for Unicode input), scan code and flags.
+    fn keys(inputs: &[INPUT]) -> Vec<(u16, u16, u32)> {
+        inputs
+            .iter()
+            .map(|input| {
+                assert_eq!(input.r#type, INPUT_KEYBOARD);
+                let ki = unsafe { input.Anonymous.ki };
+                assert_eq!(ki.dwExtraInfo, 0);
+                (ki.wVk, ki.wScan, ki.dwFlags)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn input_buffer() {
+        let events = [
+            OutputEvent::Internal(crate::backend::InternalEvent::BaseLayerChanged),
+            OutputEvent::virtual_key(0xA0, false),
+            OutputEvent::Press {
+                virtual_key: 0x41,
+                scan_code: 0x1E,
+            },
+            OutputEvent::virtual_key(0xA0, true),
+            OutputEvent::Release {
+                virtual_key: 0x41,
+                scan_code: 0x1E,
+            },
+            OutputEvent::Text {
+                character: 'ä',
+                up: false,
+            },
+            OutputEvent::Text {
+                character: '😀',
+                up: true,
+            },
+        ];
+        let inputs = super::input_buffer(&events, 1234);
+        assert!(
+            inputs
+                .iter()
+                .all(|input| unsafe { input.Anonymous.ki.time } == 1234)
+        );
+        assert_eq!(
+            keys(&inputs),
+            [
+                (0xA0, 0, 0),
+                (0x41, 0x1E, 0),
+                (0xA0, 0, KEYEVENTF_KEYUP),
+                (0x41, 0x1E, KEYEVENTF_KEYUP),
+                (0, 0xE4, KEYEVENTF_UNICODE),
+                (0, 0xD83D, KEYEVENTF_UNICODE | KEYEVENTF_KEYUP),
+                (0, 0xDE00, KEYEVENTF_UNICODE | KEYEVENTF_KEYUP),
+            ]
+        );
+
+        assert!(super::input_buffer(&[], 0).is_empty());
+    }
+
+    #[test]
+    fn inverse_inputs() {
+        let events = [
+            OutputEvent::virtual_key(0xA2, false),
+            OutputEvent::virtual_key(0xA4, false),
+            OutputEvent::Press {
+                virtual_key: 0x51,
+                scan_code: 0x10,
+            },
+            OutputEvent::virtual_key(0xA4, true),
+            OutputEvent::Text {
+                character: 'ä',
+                up: false,
+            },
+        ];
+        let inputs = super::input_buffer(&events, 0);
+        assert_eq!(
+            keys(&super::inverse_inputs(&inputs)),
+            [
+                (0, 0xE4, KEYEVENTF_UNICODE | KEYEVENTF_KEYUP),
+                (0x51, 0x10, KEYEVENTF_KEYUP),
+                (0xA2, 0, KEYEVENTF_KEYUP),
+            ]
+        );
+
+        // Modifiers of a partially inserted wrap are released.
+        assert_eq!(keys(&super::inverse_inputs(&inputs[..0])), []);
+        assert_eq!(
+            keys(&super::inverse_inputs(&inputs[..2])),
+            [(0xA4, 0, KEYEVENTF_KEYUP), (0xA2, 0, KEYEVENTF_KEYUP)]
+        );
+
+        // Held modifiers released by a partially inserted wrap are pressed
+        // again, other released keys are not.
+        let events = [
+            OutputEvent::Release {
+                virtual_key: 0x41,
+                scan_code: 0x1E,
+            },
+            OutputEvent::virtual_key(0xA0, true),
+            OutputEvent::Press {
+                virtual_key: 0x31,
+                scan_code: 0x02,
+            },
+            OutputEvent::virtual_key(0xA0, false),
+        ];
+        let inputs = super::input_buffer(&events, 0);
+        assert_eq!(keys(&super::inverse_inputs(&inputs[..2])), [(0xA0, 0, 0)]);
+        assert_eq!(
+            keys(&super::inverse_inputs(&inputs[..3])),
+            [(0x31, 0x02, KEYEVENTF_KEYUP), (0xA0, 0, 0)]
+        );
+        assert_eq!(
+            keys(&super::inverse_inputs(&inputs)),
+            [(0x31, 0x02, KEYEVENTF_KEYUP)]
+        );
+    }
 }
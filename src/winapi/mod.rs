@@ -1,12 +1,16 @@
 mod auto_start_entry;
 mod console;
+mod foreground;
 pub mod keyboard;
 mod static_icon;
+mod timer;
 mod tray_icon;
 mod util;
 
 pub use auto_start_entry::*;
 pub use console::*;
+pub use foreground::*;
 pub use static_icon::*;
+pub use timer::*;
 pub use tray_icon::*;
 pub use util::*;
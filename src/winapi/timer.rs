@@ -0,0 +1,89 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::pin::pin;
+use std::ptr;
+use std::task::{Context, Poll, Waker};
+
+use windows_sys::Win32::Foundation::*;
+use windows_sys::Win32::System::SystemInformation::GetTickCount;
+use windows_sys::Win32::UI::WindowsAndMessaging::*;
+
+thread_local! {
+    // State of all running timers by their timer id.
+    static TIMERS: RefCell<HashMap<usize, TimerState>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Default)]
+struct TimerState {
+    expired: bool,
+    waker: Option<Waker>,
+}
+
+/// Milliseconds since boot, same clock as the key event time stamps.
+pub fn tick_count() -> u32 {
+    unsafe { GetTickCount() }
+}
+
+/// Runs `future` to completion unless it takes longer than `timeout_ms`.
+///
+/// Returns `None` on timeout. Requires the windows message loop to run on the
+/// current thread.
+pub async fn timeout<F: Future>(timeout_ms: u32, future: F) -> Option<F::Output> {
+    let timer = Timer::new(timeout_ms);
+    let mut future = pin!(future);
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            Poll::Ready(Some(output))
+        } else if timer.poll_expired(cx) {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// One-shot timer of the thread message loop.
+struct Timer(usize);
+
+impl Timer {
+    fn new(timeout_ms: u32) -> Self {
+        let id = unsafe { SetTimer(ptr::null_mut(), 0, timeout_ms, Some(timer_proc)) };
+        assert_ne!(id, 0);
+        TIMERS.with_borrow_mut(|timers| timers.insert(id, TimerState::default()));
+        Self(id)
+    }
+
+    fn poll_expired(&self, cx: &mut Context) -> bool {
+        TIMERS.with_borrow_mut(|timers| {
+            let timer = timers.get_mut(&self.0).unwrap();
+            if !timer.expired {
+                timer.waker = Some(cx.waker().clone());
+            }
+            timer.expired
+        })
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        unsafe { KillTimer(ptr::null_mut(), self.0) };
+        TIMERS.with_borrow_mut(|timers| timers.remove(&self.0));
+    }
+}
+
+unsafe extern "system" fn timer_proc(_hwnd: HWND, _msg: u32, id: usize, _time: u32) {
+    unsafe { KillTimer(ptr::null_mut(), id) };
+
+    let waker = TIMERS.with_borrow_mut(|timers| {
+        let timer = timers.get_mut(&id)?;
+        timer.expired = true;
+        timer.waker.take()
+    });
+
+    // Wake outside of the borrow in case the task is polled immediately.
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
@@ -119,6 +119,17 @@ impl TrayIcon {
         update_tray_icon(self.window.hwnd(), icon);
         self.window.state().icon.set(icon);
     }
+
+    /// Shows an error balloon notification. Text longer than supported by the
+    /// shell is truncated.
+    pub fn show_error(&self, title: &str, text: &str) {
+        let mut notification_data = notification_data(self.window.hwnd());
+        notification_data.uFlags = NIF_INFO;
+        notification_data.dwInfoFlags = NIIF_ERROR;
+        copy_ascii(&mut notification_data.szInfoTitle, title);
+        copy_ascii(&mut notification_data.szInfo, text);
+        unsafe { Shell_NotifyIconA(NIM_MODIFY, &raw const notification_data) };
+    }
 }
 
 fn handle_tray_icon_event(handlers: &RefCell<Handlers>, msg: &WindowMessage) {
@@ -181,3 +192,15 @@ fn notification_data(hwnd: HWND) -> NOTIFYICONDATAA {
     notification_data.hWnd = hwnd;
     notification_data
 }
+
+/// Copies `text` to a zeroed buffer and keeps the null terminator.
+/// Non-ASCII characters are replaced because the buffer uses the ANSI code page.
+fn copy_ascii(buf: &mut [i8], text: &str) {
+    let text = text
+        .chars()
+        .map(|c| if c.is_ascii() { c as u8 } else { b'?' });
+    let len = buf.len() - 1;
+    for (dst, src) in buf[..len].iter_mut().zip(text) {
+        *dst = src as i8;
+    }
+}